@@ -0,0 +1,35 @@
+use crate::vec3::Point3;
+
+#[derive(Clone, Copy)]
+pub struct Aabb {
+    minimum: Point3,
+    maximum: Point3,
+}
+
+impl Aabb {
+    pub(crate) fn new() -> Aabb {
+        Aabb { minimum: Point3::new(), maximum: Point3::new() }
+    }
+
+    pub(crate) fn new_with_values(a: Point3, b: Point3) -> Aabb {
+        Aabb { minimum: a, maximum: b }
+    }
+
+    pub(crate) fn min(&self) -> Point3 {
+        self.minimum
+    }
+
+    pub(crate) fn max(&self) -> Point3 {
+        self.maximum
+    }
+
+    pub(crate) fn surrounding_box(box0: &Aabb, box1: &Aabb) -> Aabb {
+        let small = Point3::new_with_values(box0.min().x().min(box1.min().x()),
+                                            box0.min().y().min(box1.min().y()),
+                                            box0.min().z().min(box1.min().z()));
+        let big = Point3::new_with_values(box0.max().x().max(box1.max().x()),
+                                          box0.max().y().max(box1.max().y()),
+                                          box0.max().z().max(box1.max().z()));
+        Aabb::new_with_values(small, big)
+    }
+}
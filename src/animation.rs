@@ -0,0 +1,378 @@
+use std::ops::{Add, Mul, Sub};
+use std::sync::Arc;
+use crate::aabb::Aabb;
+use crate::camera;
+use crate::checkpoint::invalid;
+use crate::hittable::{hit_record, Hittable};
+use crate::hittable_list::HittableList;
+use crate::light::AreaLight;
+use crate::ray::Ray;
+use crate::vec3::{Point3, Vec3};
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Interpolation {
+    Linear,
+    // Catmull-Rom, passing through every key with a smooth curve
+    Spline,
+}
+
+// Focus distance keys can follow an object, focusing on it wherever it is
+#[derive(Clone, Copy)]
+enum Focus {
+    Distance(f64),
+    Object(isize),
+}
+
+// Values keyed at frames, in frame order
+struct Track<T> {
+    keys: Vec<(f64, T)>,
+}
+
+impl<T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f64, Output = T>> Track<T> {
+    fn new() -> Track<T> {
+        Track { keys: Vec::new() }
+    }
+
+    // A later key for the same frame replaces the earlier one
+    fn insert(&mut self, frame: f64, value: T) {
+        let index = self.keys.partition_point(|&(f, _)| f < frame);
+        if self.keys.get(index).is_some_and(|&(f, _)| f == frame) {
+            self.keys[index].1 = value;
+        } else {
+            self.keys.insert(index, (frame, value));
+        }
+    }
+
+    // Held at the first and last keys outside them
+    fn at(&self, frame: f64, interpolation: Interpolation) -> T {
+        let n = self.keys.len();
+        let k = self.keys.partition_point(|&(f, _)| f <= frame);
+        if k == 0 {
+            return self.keys[0].1;
+        }
+        if k == n {
+            return self.keys[n - 1].1;
+        }
+        let (f0, p0) = self.keys[k - 1];
+        let (f1, p1) = self.keys[k];
+        let t = (frame - f0) / (f1 - f0);
+        if interpolation == Interpolation::Linear {
+            return p0 + (p1 - p0) * t;
+        }
+
+        // Hermite segment with tangents through the neighbouring keys, scaled
+        // for uneven spacing, and one-sided at the ends
+        let tangent = |i: usize| {
+            let (a, b) = (i.saturating_sub(1), (i + 1).min(n - 1));
+            let (fa, pa) = self.keys[a];
+            let (fb, pb) = self.keys[b];
+            (pb - pa) * ((f1 - f0) / (fb - fa))
+        };
+        let (m0, m1) = (tangent(k - 1), tangent(k));
+        let (t2, t3) = (t * t, t * t * t);
+        p0 * (2.0 * t3 - 3.0 * t2 + 1.0) + m0 * (t3 - 2.0 * t2 + t) + p1 * (-2.0 * t3 + 3.0 * t2) + m1 * (t3 - t2)
+    }
+}
+
+// Keyframed camera and object motion read from a file. Each line holds a
+// frame number, a property and its values:
+//
+//   0  lookfrom 13 2 3
+//   48 lookat 0 1 0
+//   0  fov 20
+//   0  focus 10
+//   24 object -1 0 2 0
+//   48 focus object -1
+//
+// `object <index>` moves an object by an offset from where the scene puts it,
+// counting objects in the order the scene adds them, or from the end when
+// negative. `focus object <index>` focuses on an object at that key. An `interpolation linear` or `interpolation spline` line picks how
+// keys are joined (default linear). Lines starting with # are comments.
+pub(crate) struct Animation {
+    interpolation: Interpolation,
+    lookfrom: Track<Vec3>,
+    lookat: Track<Vec3>,
+    fov: Track<f64>,
+    focus: Vec<(f64, Focus)>,
+    objects: Vec<(isize, Track<Vec3>)>,
+}
+
+impl Animation {
+    pub(crate) fn read(path: &str) -> std::io::Result<Animation> {
+        let mut animation = Animation {
+            interpolation: Interpolation::Linear,
+            lookfrom: Track::new(),
+            lookat: Track::new(),
+            fov: Track::new(),
+            focus: Vec::new(),
+            objects: Vec::new(),
+        };
+        for line in std::fs::read_to_string(path)?.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+            if words[0] == "interpolation" {
+                animation.interpolation = match words.get(1) {
+                    Some(&"linear") => Interpolation::Linear,
+                    Some(&"spline") => Interpolation::Spline,
+                    _ => return Err(invalid(&format!("Unknown interpolation: {}", line))),
+                };
+                continue;
+            }
+            let number = |word: &str| word.parse::<f64>().map_err(|_| invalid(&format!("Invalid number in animation: {}", line)));
+            let values = |words: &[&str]| -> std::io::Result<Vec<f64>> { words.iter().map(|w| number(w)).collect() };
+            let vector = |words: &[&str]| -> std::io::Result<Vec3> {
+                match values(words)?[..] {
+                    [x, y, z] => Ok(Vec3::new_with_values(x, y, z)),
+                    _ => Err(invalid(&format!("Expected three values: {}", line))),
+                }
+            };
+            let scalar = |words: &[&str]| -> std::io::Result<f64> {
+                match values(words)?[..] {
+                    [x] => Ok(x),
+                    _ => Err(invalid(&format!("Expected one value: {}", line))),
+                }
+            };
+            if words.len() < 3 {
+                return Err(invalid(&format!("Expected a frame, a property and values: {}", line)));
+            }
+            let frame = number(words[0])?;
+            match words[1] {
+                "lookfrom" => animation.lookfrom.insert(frame, vector(&words[2..])?),
+                "lookat" => animation.lookat.insert(frame, vector(&words[2..])?),
+                "fov" => animation.fov.insert(frame, scalar(&words[2..])?),
+                "focus" => {
+                    let focus = match words[2..] {
+                        ["object", index] => Focus::Object(index.parse().map_err(|_| invalid(&format!("Invalid object index: {}", line)))?),
+                        _ => Focus::Distance(scalar(&words[2..])?),
+                    };
+                    animation.focus.retain(|&(f, _)| f != frame);
+                    animation.focus.push((frame, focus));
+                }
+                "object" => {
+                    let index: isize = words[2].parse().map_err(|_| invalid(&format!("Invalid object index: {}", line)))?;
+                    let offset = vector(&words[3..])?;
+                    match animation.objects.iter_mut().find(|(i, _)| *i == index) {
+                        Some((_, track)) => track.insert(frame, offset),
+                        None => {
+                            let mut track = Track::new();
+                            track.insert(frame, offset);
+                            animation.objects.push((index, track));
+                        }
+                    }
+                }
+                _ => return Err(invalid(&format!("Unknown animated property: {}", line))),
+            }
+        }
+        Ok(animation)
+    }
+
+    fn value<T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f64, Output = T>>(&self, track: &Track<T>, frame: f64) -> Option<T> {
+        if track.keys.is_empty() { None } else { Some(track.at(frame, self.interpolation)) }
+    }
+
+    pub(crate) fn lookfrom(&self, frame: f64) -> Option<Point3> {
+        self.value(&self.lookfrom, frame)
+    }
+
+    pub(crate) fn lookat(&self, frame: f64) -> Option<Point3> {
+        self.value(&self.lookat, frame)
+    }
+
+    pub(crate) fn fov(&self, frame: f64) -> Option<f64> {
+        self.value(&self.fov, frame)
+    }
+
+    // `distance_to` gives how far away an object is, for keys that follow one
+    pub(crate) fn focus(&self, frame: f64, distance_to: impl Fn(isize) -> f64) -> Option<f64> {
+        let mut track = Track::new();
+        for &(f, focus) in &self.focus {
+            track.insert(f, match focus {
+                Focus::Distance(d) => d,
+                Focus::Object(index) => distance_to(index),
+            });
+        }
+        self.value(&track, frame)
+    }
+
+    // Puts the animated objects in the world where they are during `frame`,
+    // lights included. While the shutter is open they keep moving, `fps`
+    // frames a second.
+    pub(crate) fn apply(&self, world: &mut HittableList, lights: &mut [AreaLight], frame: f64, fps: f64, shutter: f64) {
+        for (index, track) in &self.objects {
+            let i = world.index(*index).unwrap_or_else(|| panic!("The animation moves object {} but the scene has {} objects", index, world.len()));
+            let moved = Arc::new(Moved {
+                object: world.objects()[i].clone(),
+                offset: Track { keys: track.keys.clone() },
+                interpolation: self.interpolation,
+                frame,
+                frames_per_second: fps,
+                frames_open: shutter * fps,
+            });
+            world.set(i, moved.clone());
+            for light in lights.iter_mut().filter(|light| light.object_id() == i) {
+                let moved = moved.clone();
+                *light = light.moved(Arc::new(move || moved.offset_now()));
+            }
+        }
+    }
+}
+
+// An object moved along an offset track
+struct Moved {
+    object: Arc<dyn Hittable>,
+    offset: Track<Vec3>,
+    interpolation: Interpolation,
+    frame: f64,
+    frames_per_second: f64,
+    // How many frames pass while the shutter is open
+    frames_open: f64,
+}
+
+impl Moved {
+    fn offset_at(&self, frame: f64) -> Vec3 {
+        self.offset.at(frame, self.interpolation)
+    }
+
+    // Where it is at the current camera time
+    fn offset_now(&self) -> Vec3 {
+        self.offset_at(self.frame + camera::time() * self.frames_per_second)
+    }
+}
+
+impl Hittable for Moved {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut hit_record) -> bool {
+        let offset = self.offset_now();
+        let moved = Ray::new_with_values(r.origin() - offset, r.direction());
+        if !self.object.hit(&moved, t_min, t_max, rec) {
+            return false;
+        }
+        rec.p = rec.p + offset;
+        true
+    }
+
+    fn bounding_box(&self, output_box: &mut Aabb) -> bool {
+        let mut object_box = Aabb::new();
+        if !self.object.bounding_box(&mut object_box) {
+            return false;
+        }
+        // Everywhere it passes through while the shutter is open
+        for step in 0..=8 {
+            let offset = self.offset_at(self.frame + self.frames_open * step as f64 / 8.0);
+            let moved = Aabb::new_with_values(object_box.min() + offset, object_box.max() + offset);
+            *output_box = if step == 0 { moved } else { Aabb::surrounding_box(output_box, &moved) };
+        }
+        true
+    }
+
+    fn is_specular(&self) -> bool {
+        self.object.is_specular()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::PerspectiveCamera;
+    use crate::environment::GradientSky;
+    use crate::bdpt::BdptIntegrator;
+use crate::integrator::Integrator;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::sampler;
+    use crate::scene::Scene;
+    use crate::sphere::Sphere;
+    use crate::vec3::Color;
+
+    fn read_text(name: &str, text: &str) -> std::io::Result<Animation> {
+        let path = std::env::temp_dir().join(format!("ray_tracer_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let animation = Animation::read(&path.to_string_lossy());
+        std::fs::remove_file(&path).ok();
+        animation
+    }
+
+    #[test]
+    fn reads_keys() {
+        let text = "# Comment\n\ninterpolation linear\n0 lookfrom 0 0 0\n10 lookfrom 10 20 0\n0 fov 20\n10 fov 40\n0 focus 5\n10 focus object -1\n0 object 1 0 0 0\n10 object 1 0 4 0\n";
+        let animation = read_text("keys.anim", text).unwrap();
+        let lookfrom = animation.lookfrom(5.0).unwrap();
+        assert_eq!([lookfrom.x(), lookfrom.y(), lookfrom.z()], [5.0, 10.0, 0.0]);
+        assert!(animation.lookat(5.0).is_none());
+        assert_eq!(animation.fov(-3.0), Some(20.0));
+        assert_eq!(animation.fov(2.5), Some(25.0));
+        assert_eq!(animation.fov(12.0), Some(40.0));
+        assert_eq!(animation.focus(5.0, |index| if index == -1 { 15.0 } else { 0.0 }), Some(10.0));
+        assert_eq!(animation.objects.len(), 1);
+        assert_eq!(animation.objects[0].0, 1);
+        assert_eq!(animation.objects[0].1.at(5.0, Interpolation::Linear).y(), 2.0);
+    }
+
+    #[test]
+    fn later_keys_replace_earlier_ones() {
+        let animation = read_text("replaced.anim", "0 fov 20\n0 fov 30\n4 focus 1\n4 focus 2\n").unwrap();
+        assert_eq!(animation.fov(0.0), Some(30.0));
+        assert_eq!(animation.focus(4.0, |_| 0.0), Some(2.0));
+    }
+
+    #[test]
+    fn spline_keys_pass_through_every_key() {
+        let animation = read_text("spline.anim", "interpolation spline\n0 fov 10\n10 fov 20\n20 fov 10\n25 fov 30\n").unwrap();
+        for (frame, fov) in [(0.0, 10.0), (10.0, 20.0), (20.0, 10.0), (25.0, 30.0)] {
+            assert!((animation.fov(frame).unwrap() - fov).abs() < 1e-12, "frame {}", frame);
+        }
+        // Rounded off on the way up to the peak, where linear keys give 15
+        assert!(animation.fov(5.0).unwrap() > 15.0);
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        let lines = ["0 lookfrom 1 2", "0 fov 20 30", "x fov 20", "0 fov wide", "0 zoom 2", "0 fov", "0 object one 0 0 0", "0 focus object near", "interpolation cubic"];
+        for (index, line) in lines.iter().enumerate() {
+            assert!(read_text(&format!("bad_{}.anim", index), line).is_err(), "accepted {:?}", line);
+        }
+    }
+
+    // A lamp over a grey floor, in the dark
+    fn lamp_scene(lamp_center: Point3) -> (HittableList, Vec<AreaLight>) {
+        let mut world = HittableList::new();
+        let grey = Arc::new(Lambertian::new_with_values(Color::new_with_values(0.5, 0.5, 0.5)));
+        world.add(Arc::new(Sphere::new_with_values(Point3::new_with_values(0.0, -1000.0, 0.0), 1000.0, grey)));
+        let lamp_material = Arc::new(DiffuseLight::new_with_values(Color::new_with_values(4.0, 4.0, 4.0)));
+        let lamp = Arc::new(Sphere::new_with_values(lamp_center, 1.0, lamp_material));
+        let lights = vec![AreaLight::new_with_values(world.len(), lamp.clone())];
+        world.add(lamp);
+        (world, lights)
+    }
+
+    // Mean light bdpt finds reaching the floor right under where the lamp
+    // ends up, partly by sampling points on the lamp
+    fn floor_brightness(world: HittableList, lights: Vec<AreaLight>) -> f64 {
+        let up = Vec3::new_with_values(0.0, 1.0, 0.0);
+        let camera = PerspectiveCamera::new(Point3::new_with_values(3.0, 1.0, 0.0), Point3::new_with_values(3.0, 0.0, 0.0), Vec3::new_with_values(1.0, 0.0, 0.0), 1.0, 1.0, 0.0, 1.0);
+        let scene = Scene::new_with_values(world, lights, Box::new(GradientSky::new_with_values(0.0)), Box::new(camera));
+        let integrator = BdptIntegrator::new_with_values(2);
+        sampler::seed(1);
+        let samples = 4000;
+        let r = Ray::new_with_values(Point3::new_with_values(3.0, 1.0, 0.0), -up);
+        (0..samples).map(|_| integrator.li(&r, &scene, &mut Vec::new()).y()).sum::<f64>() / samples as f64
+    }
+
+    #[test]
+    fn moved_lamps_light_from_where_they_are() {
+        let animation = read_text("lamp.anim", "0 object -1 3 0 0\n").unwrap();
+        let (mut world, mut lights) = lamp_scene(Point3::new_with_values(0.0, 3.0, 0.0));
+        animation.apply(&mut world, &mut lights, 0.0, 24.0, 0.0);
+
+        let moved_center = Point3::new_with_values(3.0, 3.0, 0.0);
+        for _ in 0..100 {
+            let (p, _) = lights[0].sample_point();
+            assert!(((p - moved_center).length() - 1.0).abs() < 1e-9, "sampled {:?} off the moved lamp", [p.x(), p.y(), p.z()]);
+        }
+
+        let animated = floor_brightness(world, lights);
+        let (world, lights) = lamp_scene(moved_center);
+        let placed = floor_brightness(world, lights);
+        assert!((animated - placed).abs() < 0.02 * placed, "moved lamp gives {} against {} for one put there", animated, placed);
+    }
+}
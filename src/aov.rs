@@ -0,0 +1,275 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use crate::checkpoint::{self, read_f64, read_u64, write_f64, write_u64};
+use crate::hittable::{hit_record, Hittable};
+use crate::image_io::{self, Channel};
+use crate::ray::Ray;
+use crate::rtweekend;
+use crate::scene::Scene;
+use crate::vec3::Color;
+
+pub(crate) const AOVS: [&str; 12] = [
+    "depth", "normal", "albedo", "position", "object-id", "material-id",
+    "direct-diffuse", "indirect-diffuse", "direct-specular", "indirect-specular", "emission", "alpha",
+];
+
+// AOVs that need the integrator to split up the light it finds
+pub(crate) const LIGHTING_AOVS: [&str; 5] = ["direct-diffuse", "indirect-diffuse", "direct-specular", "indirect-specular", "emission"];
+
+// Radiance along a camera ray split by how it got there. Emission is what the
+// camera sees of lights and the background directly. Direct light reached the
+// first surface straight from a light, indirect light after more bounces, and
+// whether it counts as diffuse or specular depends on that first surface.
+#[derive(Clone, Copy)]
+pub(crate) struct LightComponents {
+    pub emission: Color,
+    pub direct_diffuse: Color,
+    pub indirect_diffuse: Color,
+    pub direct_specular: Color,
+    pub indirect_specular: Color,
+}
+
+impl LightComponents {
+    pub(crate) fn new() -> LightComponents {
+        LightComponents {
+            emission: Color::new(),
+            direct_diffuse: Color::new(),
+            indirect_diffuse: Color::new(),
+            direct_specular: Color::new(),
+            indirect_specular: Color::new(),
+        }
+    }
+
+    // Adds light that arrived after `bounces` scattering events, the first one
+    // at a specular surface or not.
+    pub(crate) fn add(&mut self, bounces: usize, specular: bool, color: Color) {
+        let component = match (bounces, specular) {
+            (0, _) => &mut self.emission,
+            (1, false) => &mut self.direct_diffuse,
+            (1, true) => &mut self.direct_specular,
+            (_, false) => &mut self.indirect_diffuse,
+            (_, true) => &mut self.indirect_specular,
+        };
+        *component = *component + color;
+    }
+
+    pub(crate) fn map(&self, f: impl Fn(Color) -> Color) -> LightComponents {
+        LightComponents {
+            emission: f(self.emission),
+            direct_diffuse: f(self.direct_diffuse),
+            indirect_diffuse: f(self.indirect_diffuse),
+            direct_specular: f(self.direct_specular),
+            indirect_specular: f(self.indirect_specular),
+        }
+    }
+
+    pub(crate) fn total(&self) -> Color {
+        self.emission + self.direct_diffuse + self.indirect_diffuse + self.direct_specular + self.indirect_specular
+    }
+
+    fn get(&self, name: &str) -> Color {
+        match name {
+            "direct-diffuse" => self.direct_diffuse,
+            "indirect-diffuse" => self.indirect_diffuse,
+            "direct-specular" => self.direct_specular,
+            "indirect-specular" => self.indirect_specular,
+            _ => self.emission,
+        }
+    }
+}
+
+// A camera sample's value for every layer, worked out where it was traced and
+// added to the buffers later
+pub(crate) struct AovSample {
+    index: usize,
+    hit: bool,
+    values: Vec<[f64; 3]>,
+}
+
+impl AovSample {
+    pub(crate) fn write<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        write_u64(out, self.index as u64)?;
+        write_u64(out, self.hit as u64)?;
+        write_u64(out, self.values.len() as u64)?;
+        for value in self.values.iter().flatten() {
+            write_f64(out, *value)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read<R: Read>(input: &mut R) -> std::io::Result<AovSample> {
+        let index = read_u64(input)? as usize;
+        let hit = read_u64(input)? != 0;
+        let count = read_u64(input)? as usize;
+        if count > AOVS.len() {
+            return Err(checkpoint::invalid("Invalid AOV sample"));
+        }
+        let mut values = Vec::with_capacity(count);
+        for _ in 0..count {
+            values.push([read_f64(input)?, read_f64(input)?, read_f64(input)?]);
+        }
+        Ok(AovSample { index, hit, values })
+    }
+}
+
+struct Layer {
+    name: &'static str,
+    channels: &'static [&'static str],
+    sums: Vec<f64>,
+}
+
+// The AOVs being rendered, all a thread tracing camera samples needs to work
+// out their values
+#[derive(Clone)]
+pub(crate) struct AovLayout {
+    width: usize,
+    height: usize,
+    names: Vec<&'static str>,
+}
+
+impl AovLayout {
+    pub(crate) fn new_with_values(names: &[String], width: usize, height: usize) -> AovLayout {
+        AovLayout { width, height, names: AOVS.iter().copied().filter(|name| names.iter().any(|n| n == name)).collect() }
+    }
+
+    pub(crate) fn wants_lighting(&self) -> bool {
+        self.names.iter().any(|name| LIGHTING_AOVS.contains(name))
+    }
+
+    // What the camera sample taken at (u, v) along `r`, which found
+    // `components`, adds to the AOVs. There's no ray where the camera can't see.
+    pub(crate) fn sample(&self, u: f64, v: f64, r: Option<&Ray>, scene: &Scene, components: Option<&LightComponents>) -> AovSample {
+        let i = ((u * (self.width - 1) as f64) as usize).min(self.width - 1);
+        let j = ((v * (self.height - 1) as f64) as usize).min(self.height - 1);
+        let index = (self.height - 1 - j) * self.width + i;
+
+        let mut rec = hit_record::new();
+        let hit = r.is_some_and(|r| scene.world.hit(r, 0.001, rtweekend::INFINITY, &mut rec));
+        let values = self.names.iter().map(|&name| {
+            match (name, hit) {
+                ("depth", true) => [r.map_or(0.0, |r| rec.t * r.direction().length()), 0.0, 0.0],
+                ("normal", true) => {
+                    let outward = if rec.front_face { rec.normal } else { -rec.normal };
+                    [outward.x(), outward.y(), outward.z()]
+                }
+                ("position", true) => [rec.p.x(), rec.p.y(), rec.p.z()],
+                ("albedo", true) => {
+                    let a = rec.mat_ptr.as_ref().unwrap().albedo(&rec);
+                    [a.x(), a.y(), a.z()]
+                }
+                ("object-id", true) => [rec.object_id as f64, 0.0, 0.0],
+                ("material-id", true) => [rec.mat_ptr.as_ref().unwrap().id() as f64, 0.0, 0.0],
+                ("object-id", false) | ("material-id", false) => [-1.0, 0.0, 0.0],
+                ("alpha", _) => [if hit { 1.0 } else { 0.0 }, 0.0, 0.0],
+                (name, _) if LIGHTING_AOVS.contains(&name) => {
+                    let c = components.map_or(Color::new(), |c| c.get(name));
+                    [c.x(), c.y(), c.z()]
+                }
+                _ => [0.0; 3],
+            }
+        }).collect();
+        AovSample { index, hit, values }
+    }
+}
+
+// Accumulates the requested AOVs over the camera samples, each sample going
+// to the pixel it was taken in. Geometric AOVs come from where the camera ray
+// first hits the scene and are averaged over the samples that hit something,
+// apart from the ids, which are taken from a pixel's first sample.
+pub(crate) struct AovBuffers {
+    width: usize,
+    height: usize,
+    layers: Vec<Layer>,
+    samples: Vec<f64>,
+    hits: Vec<f64>,
+}
+
+impl AovBuffers {
+    pub(crate) fn new_with_values(names: &[String], width: usize, height: usize) -> AovBuffers {
+        let layers = AOVS.iter().filter(|name| names.iter().any(|n| n == *name)).map(|&name| {
+            let channels: &'static [&'static str] = match name {
+                "depth" => &["Z"],
+                "normal" | "position" => &["X", "Y", "Z"],
+                "object-id" | "material-id" => &["id"],
+                "alpha" => &["A"],
+                _ => &["R", "G", "B"],
+            };
+            Layer { name, channels, sums: vec![0.0; width * height * channels.len()] }
+        }).collect();
+        AovBuffers { width, height, layers, samples: vec![0.0; width * height], hits: vec![0.0; width * height] }
+    }
+
+    pub(crate) fn add_sample(&mut self, sample: AovSample) {
+        let index = sample.index;
+        let first = self.samples[index] == 0.0;
+        self.samples[index] += 1.0;
+        if sample.hit {
+            self.hits[index] += 1.0;
+        }
+
+        for (layer, value) in self.layers.iter_mut().zip(sample.values) {
+            if layer.name == "object-id" || layer.name == "material-id" {
+                if first {
+                    layer.sums[index] = value[0];
+                }
+                continue;
+            }
+            let n = layer.channels.len();
+            for (c, v) in value.iter().take(n).enumerate() {
+                layer.sums[index * n + c] += v;
+            }
+        }
+    }
+
+    // Averaged channels for every layer, named "layer.channel". Pixels where
+    // nothing was hit have zero depth, normal and position and an id of -1.
+    fn channels(&self) -> Vec<(&'static str, Channel)> {
+        let mut channels = Vec::new();
+        for layer in &self.layers {
+            let n = layer.channels.len();
+            for (c, &channel) in layer.channels.iter().enumerate() {
+                let data = (0..self.width * self.height).map(|index| {
+                    let count = match layer.name {
+                        "object-id" | "material-id" => 1.0,
+                        "depth" | "normal" | "position" | "albedo" => self.hits[index],
+                        _ => self.samples[index],
+                    };
+                    if count > 0.0 { (layer.sums[index * n + c] / count) as f32 } else { 0.0 }
+                }).collect();
+                channels.push((layer.name, Channel { name: format!("{}.{}", layer.name.replace('-', "_"), channel), data }));
+            }
+        }
+        channels
+    }
+
+    // A multi-layer EXR holding the image and every AOV. The image's alpha is
+    // its A channel when it has one, or else the alpha AOV is.
+    pub(crate) fn write_exr(&self, path: &str, pixels: &[Color], alpha: Option<&[f64]>) -> std::io::Result<()> {
+        let mut channels: Vec<Channel> = self.channels().into_iter().map(|(name, mut channel)| {
+            if name == "alpha" && alpha.is_none() {
+                channel.name = String::from("A");
+            }
+            channel
+        }).collect();
+        for (c, name) in ["R", "G", "B"].iter().enumerate() {
+            channels.push(Channel { name: String::from(*name), data: pixels.iter().map(|p| p[c] as f32).collect() });
+        }
+        if let Some(alpha) = alpha {
+            channels.push(Channel { name: String::from("A"), data: alpha.iter().map(|&a| a as f32).collect() });
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        image_io::write_exr(&mut out, self.width, self.height, &channels)?;
+        out.flush()
+    }
+
+    // One PFM per AOV, named `<prefix>.<aov>.pfm`. Colours and vectors are
+    // written in colour, the rest in greyscale.
+    pub(crate) fn write_pfms(&self, prefix: &str) -> std::io::Result<()> {
+        let mut channels = self.channels();
+        for layer in &self.layers {
+            let layer_channels: Vec<Channel> = channels.drain(..layer.channels.len()).map(|(_, channel)| channel).collect();
+            image_io::write_pfm(&format!("{}.{}.pfm", prefix, layer.name), self.width, self.height, &layer_channels)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,556 @@
+use crate::hittable::{hit_record, Hittable};
+use crate::integrator::{Integrator, Splat};
+use crate::ray::Ray;
+use crate::rtweekend::{self, PI};
+use crate::scene::Scene;
+use crate::vec3::{Color, Point3, Vec3};
+
+// Bidirectional path tracer following Veach's thesis and the structure of
+// pbrt-v3. A camera subpath and a light subpath are traced for every camera
+// sample and every pair of their vertices is connected. All the ways of
+// building a path are combined with the balance heuristic, and paths that
+// only contain the camera's own vertex (light tracing) are splatted.
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LightSource {
+    Environment,
+    Area(usize),
+}
+
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexKind,
+    beta: Color,
+    p: Point3,
+    // Surface or area light normal. For the environment this is the direction
+    // pointing towards it instead.
+    n: Vec3,
+    rec: Option<hit_record>,
+    // Direction of the ray that arrived at a surface vertex
+    r_in: Vec3,
+    light: Option<LightSource>,
+    delta: bool,
+    // Area densities of sampling this vertex from either side of the path
+    pdf_fwd: f64,
+    pdf_rev: f64,
+}
+
+impl Vertex {
+    fn camera(p: Point3, beta: Color) -> Vertex {
+        Vertex {
+            kind: VertexKind::Camera,
+            beta,
+            p,
+            n: Vec3::new(),
+            rec: None,
+            r_in: Vec3::new(),
+            light: None,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn area_light(index: usize, p: Point3, n: Vec3, beta: Color, pdf_fwd: f64) -> Vertex {
+        Vertex {
+            kind: VertexKind::Light,
+            n,
+            light: Some(LightSource::Area(index)),
+            pdf_fwd,
+            ..Vertex::camera(p, beta)
+        }
+    }
+
+    // `direction` points from the scene towards the environment
+    fn environment(p: Point3, direction: Vec3, beta: Color, pdf_fwd: f64) -> Vertex {
+        Vertex {
+            kind: VertexKind::Light,
+            n: direction,
+            light: Some(LightSource::Environment),
+            pdf_fwd,
+            ..Vertex::camera(p, beta)
+        }
+    }
+
+    fn surface(rec: hit_record, r_in: Vec3, beta: Color) -> Vertex {
+        Vertex {
+            kind: VertexKind::Surface,
+            n: rec.normal,
+            r_in,
+            ..Vertex::camera(rec.p, beta)
+        }
+        .with_record(rec)
+    }
+
+    fn with_record(mut self, rec: hit_record) -> Vertex {
+        self.rec = Some(rec);
+        self
+    }
+
+    fn is_infinite_light(&self) -> bool {
+        self.light == Some(LightSource::Environment)
+    }
+
+    fn is_on_surface(&self) -> bool {
+        self.kind == VertexKind::Surface || matches!(self.light, Some(LightSource::Area(_)))
+    }
+
+    fn emitted(&self) -> Color {
+        match &self.rec {
+            Some(rec) => rec.mat_ptr.as_ref().unwrap().emitted(rec),
+            None => Color::new(),
+        }
+    }
+
+    // Index of the area light this vertex lies on, if any
+    fn area_light_index(&self, scene: &Scene) -> Option<usize> {
+        match (self.kind, self.light) {
+            (VertexKind::Light, Some(LightSource::Area(index))) => Some(index),
+            (VertexKind::Surface, _) if !self.emitted().near_zero() => scene.area_light_index(self.rec.as_ref().unwrap().object_id),
+            _ => None,
+        }
+    }
+
+    fn is_light(&self, scene: &Scene) -> bool {
+        self.kind == VertexKind::Light || self.area_light_index(scene).is_some()
+    }
+
+    fn direction_to(&self, v: &Vertex) -> Vec3 {
+        if v.is_infinite_light() {
+            v.n
+        } else if self.is_infinite_light() {
+            -self.n
+        } else {
+            (v.p - self.p).unit_vector()
+        }
+    }
+
+    // Turns a solid angle density at this vertex into an area density at `next`
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        if next.is_infinite_light() {
+            return pdf;
+        }
+        let w = next.p - self.p;
+        let inv_dist2 = 1.0 / w.length_squared();
+        let mut pdf = pdf * inv_dist2;
+        if next.is_on_surface() {
+            pdf *= Vec3::dot(&next.n, &(w * inv_dist2.sqrt())).abs();
+        }
+        pdf
+    }
+
+    // BRDF times cosine for scattering from the previous vertex towards `next`
+    fn f(&self, next: &Vertex) -> Color {
+        match &self.rec {
+            Some(rec) => {
+                let r_in = Ray::new_with_values(self.p, self.r_in);
+                rec.mat_ptr.as_ref().unwrap().eval(&r_in, rec, &self.direction_to(next))
+            }
+            None => Color::new(),
+        }
+    }
+
+    // Area density of this vertex sampling `next`, having arrived from `prev`
+    fn pdf(&self, scene: &Scene, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        if self.kind == VertexKind::Light {
+            return self.pdf_light(scene, next);
+        }
+
+        let wn = self.direction_to(next);
+        let pdf = match self.kind {
+            VertexKind::Camera => scene.camera.pdf_we(&Ray::new_with_values(self.p, wn)).1,
+            _ => {
+                let rec = self.rec.as_ref().unwrap();
+                let r_in = Ray::new_with_values(self.p, prev.map_or(self.r_in, |v| v.direction_to(self)));
+                rec.mat_ptr.as_ref().unwrap().scattering_pdf(&r_in, rec, &wn)
+            }
+        };
+        self.convert_density(pdf, next)
+    }
+
+    // Area density of a light subpath leaving this light vertex towards `v`
+    fn pdf_light(&self, scene: &Scene, v: &Vertex) -> f64 {
+        let w = self.direction_to(v);
+        let mut pdf = if self.is_infinite_light() {
+            1.0 / (PI * scene.radius * scene.radius)
+        } else {
+            let cosine = Vec3::dot(&self.n, &w).max(0.0);
+            cosine / PI / (v.p - self.p).length_squared()
+        };
+        if v.is_on_surface() {
+            pdf *= Vec3::dot(&v.n, &w).abs();
+        }
+        pdf
+    }
+
+    // Density of a light subpath starting at this vertex
+    fn pdf_light_origin(&self, scene: &Scene, v: &Vertex) -> f64 {
+        if self.is_infinite_light() {
+            return scene.light_pick_pdf() * scene.environment.pdf(&-self.direction_to(v));
+        }
+        match self.area_light_index(scene) {
+            Some(index) => scene.light_pick_pdf() * scene.lights[index].pdf_position(),
+            None => 0.0,
+        }
+    }
+
+    // Radiance emitted from this vertex towards `v`
+    fn le(&self, scene: &Scene, v: &Vertex) -> Color {
+        if self.is_infinite_light() {
+            return scene.environment.value(&self.n);
+        }
+        match (self.kind, self.light) {
+            (VertexKind::Light, Some(LightSource::Area(index))) => scene.lights[index].radiance(&self.n, &self.direction_to(v)),
+            _ => self.emitted(),
+        }
+    }
+}
+
+pub(crate) struct BdptIntegrator {
+    max_depth: usize,
+}
+
+impl BdptIntegrator {
+    pub(crate) fn new_with_values(max_depth: usize) -> BdptIntegrator {
+        BdptIntegrator { max_depth }
+    }
+
+    fn generate_camera_subpath(&self, r: &Ray, scene: &Scene, path: &mut Vec<Vertex>) {
+        let beta = Color::new_with_values(1.0, 1.0, 1.0);
+        let (_, pdf_dir) = scene.camera.pdf_we(r);
+        path.push(Vertex::camera(r.origin(), beta));
+        random_walk(scene, Ray::new_with_values(r.origin(), r.direction()), beta, pdf_dir, self.max_depth + 1, true, path);
+    }
+
+    fn generate_light_subpath(&self, scene: &Scene, path: &mut Vec<Vertex>) {
+        let light_ray = match scene.sample_light_ray() {
+            Some(light_ray) => light_ray,
+            None => return,
+        };
+        let beta = light_ray.power();
+        let ray = Ray::new_with_values(light_ray.ray.origin(), light_ray.ray.direction());
+
+        let index = match light_ray.light {
+            Some(index) => index,
+            None => {
+                path.push(Vertex::environment(ray.origin(), light_ray.n, light_ray.le, light_ray.pdf_pos * light_ray.pick_pdf));
+                random_walk(scene, ray, beta, light_ray.pdf_dir, self.max_depth, false, path);
+
+                // The disk sampling gives the first hit a positional rather than a
+                // solid angle density, and the light itself a directional one.
+                if path.len() > 1 {
+                    path[1].pdf_fwd = light_ray.pdf_pos;
+                    if path[1].is_on_surface() {
+                        path[1].pdf_fwd *= Vec3::dot(&light_ray.n, &path[1].n).abs();
+                    }
+                }
+                path[0].pdf_fwd = light_ray.pick_pdf * light_ray.pdf_dir;
+                return;
+            }
+        };
+
+        path.push(Vertex::area_light(index, ray.origin(), light_ray.n, light_ray.le, light_ray.pdf_pos * light_ray.pick_pdf));
+        random_walk(scene, ray, beta, light_ray.pdf_dir, self.max_depth, false, path);
+    }
+
+    // Contribution of the path made of the first s light and first t camera
+    // vertices, plus its image position for t = 1 strategies.
+    fn connect(&self, scene: &Scene, light: &[Vertex], camera: &[Vertex], s: usize, t: usize) -> (Color, Option<(f64, f64)>) {
+        if t > 1 && s != 0 && camera[t - 1].kind == VertexKind::Light {
+            return (Color::new(), None);
+        }
+
+        let mut l = Color::new();
+        let mut sampled = None;
+        let mut raster = None;
+
+        if s == 0 {
+            let pt = &camera[t - 1];
+            if pt.is_light(scene) {
+                l = pt.le(scene, &camera[t - 2]) * pt.beta;
+            } else if pt.kind == VertexKind::Surface && !pt.emitted().near_zero() {
+                // Emitters that aren't registered as lights can only be hit
+                return (pt.emitted() * pt.beta, None);
+            }
+        } else if t == 1 {
+            let qs = &light[s - 1];
+            if let Some((lens_point, we, pdf, uv)) = scene.camera.sample_wi(&qs.p) {
+                if pdf > 0.0 && we > 0.0 {
+                    let v = Vertex::camera(lens_point, Color::new_with_values(we, we, we) / pdf);
+                    l = qs.beta * qs.f(&v) * v.beta;
+                    if !l.near_zero() && !scene.visible(&qs.p, &lens_point) {
+                        l = Color::new();
+                    }
+                    sampled = Some(v);
+                    raster = Some(uv);
+                }
+            }
+        } else if s == 1 {
+            let pt = &camera[t - 1];
+            if let Some(sample) = scene.sample_light(&pt.p) {
+                let beta = sample.le / sample.pdf;
+                let mut v = match sample.light {
+                    Some(index) => Vertex::area_light(index, sample.p, sample.n, beta, 0.0),
+                    None => Vertex::environment(sample.p, sample.wi, beta, 0.0),
+                };
+                v.pdf_fwd = v.pdf_light_origin(scene, pt);
+                l = pt.beta * pt.f(&v) * v.beta;
+                if !l.near_zero() && !scene.unoccluded(&pt.p, &sample) {
+                    l = Color::new();
+                }
+                sampled = Some(v);
+            }
+        } else {
+            let qs = &light[s - 1];
+            let pt = &camera[t - 1];
+            l = qs.beta * qs.f(pt) * pt.f(qs) * pt.beta;
+            if !l.near_zero() {
+                // Both ends are surfaces whose cosines are already part of f
+                let g = if scene.visible(&qs.p, &pt.p) { 1.0 / (qs.p - pt.p).length_squared() } else { 0.0 };
+                l = l * g;
+            }
+        }
+
+        if l.near_zero() {
+            return (Color::new(), None);
+        }
+        let weight = self.mis_weight(scene, light, camera, sampled.as_ref(), s, t);
+        (l * weight, raster)
+    }
+
+    // Balance heuristic weight of strategy (s, t) against every other way of
+    // sampling the same path.
+    fn mis_weight(&self, scene: &Scene, light: &[Vertex], camera: &[Vertex], sampled: Option<&Vertex>, s: usize, t: usize) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        // (pdf_fwd, pdf_rev, delta) of each vertex, updated for this connection
+        let mut cam: Vec<(f64, f64, bool)> = camera[..t].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+        let mut lig: Vec<(f64, f64, bool)> = light[..s].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+
+        let qs = match s {
+            0 => None,
+            1 => sampled,
+            _ => Some(&light[s - 1]),
+        };
+        let pt = match t {
+            1 => sampled.unwrap(),
+            _ => &camera[t - 1],
+        };
+        let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+        let pt_minus = if t > 1 { Some(&camera[t - 2]) } else { None };
+
+        if let Some(q) = qs {
+            lig[s - 1] = (q.pdf_fwd, q.pdf_rev, false);
+        }
+        cam[t - 1] = (pt.pdf_fwd, pt.pdf_rev, false);
+
+        cam[t - 1].1 = match qs {
+            Some(q) => q.pdf(scene, qs_minus, pt),
+            None => pt.pdf_light_origin(scene, pt_minus.unwrap()),
+        };
+        if let Some(ptm) = pt_minus {
+            cam[t - 2].1 = match qs {
+                Some(q) => pt.pdf(scene, Some(q), ptm),
+                None => pt.pdf_light(scene, ptm),
+            };
+        }
+        if let Some(q) = qs {
+            lig[s - 1].1 = pt.pdf(scene, pt_minus, q);
+        }
+        if let (Some(q), Some(qsm)) = (qs, qs_minus) {
+            lig[s - 2].1 = q.pdf(scene, Some(pt), qsm);
+        }
+
+        let remap0 = |f: f64| if f != 0.0 { f } else { 1.0 };
+        let mut sum_ri = 0.0;
+
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap0(cam[i].1) / remap0(cam[i].0);
+            if !cam[i].2 && !cam[i - 1].2 {
+                sum_ri += ri;
+            }
+        }
+
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap0(lig[i].1) / remap0(lig[i].0);
+            let delta_light_vertex = i > 0 && lig[i - 1].2;
+            if !lig[i].2 && !delta_light_vertex {
+                sum_ri += ri;
+            }
+        }
+
+        1.0 / (1.0 + sum_ri)
+    }
+}
+
+impl Integrator for BdptIntegrator {
+    fn li(&self, r: &Ray, scene: &Scene, splats: &mut Vec<Splat>) -> Color {
+        let mut camera = Vec::with_capacity(self.max_depth + 2);
+        let mut light = Vec::with_capacity(self.max_depth + 1);
+        self.generate_camera_subpath(r, scene, &mut camera);
+        self.generate_light_subpath(scene, &mut light);
+
+        let mut color = Color::new();
+        for t in 1..=camera.len() {
+            for s in 0..=light.len() {
+                if (s == 1 && t == 1) || s + t < 2 || s + t - 2 > self.max_depth {
+                    continue;
+                }
+                let (l, raster) = self.connect(scene, &light, &camera, s, t);
+                match raster {
+                    Some((u, v)) if t == 1 => splats.push(Splat { u, v, color: l }),
+                    _ => color = color + l,
+                }
+            }
+        }
+        color
+    }
+}
+
+// Extends a subpath by sampling materials until it leaves the scene, is
+// absorbed or reaches max_vertices new vertices. Camera subpaths (radiance)
+// end with an environment vertex when they escape.
+fn random_walk(scene: &Scene, mut ray: Ray, mut beta: Color, pdf: f64, max_vertices: usize, radiance: bool, path: &mut Vec<Vertex>) {
+    if max_vertices == 0 {
+        return;
+    }
+    let mut pdf_fwd = pdf;
+    let mut bounces = 0;
+
+    loop {
+        if beta.near_zero() {
+            break;
+        }
+        let prev = path.len() - 1;
+        let mut rec = hit_record::new();
+        if !scene.world.hit(&ray, 0.001, rtweekend::INFINITY, &mut rec) {
+            if radiance {
+                let to_env = ray.direction().unit_vector();
+                path.push(Vertex::environment(ray.origin() + to_env, to_env, beta, pdf_fwd));
+            }
+            break;
+        }
+
+        let mut vertex = Vertex::surface(rec, ray.direction(), beta);
+        vertex.pdf_fwd = path[prev].convert_density(pdf_fwd, &vertex);
+        path.push(vertex);
+        let current = prev + 1;
+        bounces += 1;
+        if bounces >= max_vertices {
+            break;
+        }
+
+        let rec = path[current].rec.as_ref().unwrap();
+        let mat = rec.mat_ptr.as_ref().unwrap();
+        let mut scattered = Ray::new();
+        let mut attenuation = Color::new();
+        if !mat.scatter(&ray, rec, &mut attenuation, &mut scattered) {
+            break;
+        }
+
+        let wi = scattered.direction().unit_vector();
+        pdf_fwd = mat.scattering_pdf(&ray, rec, &wi);
+        let mut pdf_rev = mat.scattering_pdf(&Ray::new_with_values(rec.p, -wi), rec, &-ray.direction().unit_vector());
+        if pdf_fwd == 0.0 {
+            // Specular bounce, the vertex can't be connected to
+            path[current].delta = true;
+            pdf_rev = 0.0;
+        }
+        beta = beta * attenuation;
+
+        let prev_pdf_rev = path[current].convert_density(pdf_rev, &path[prev]);
+        path[prev].pdf_rev = prev_pdf_rev;
+        ray = Ray::new_with_values(scattered.origin(), wi);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::BdptIntegrator;
+    use crate::camera::PerspectiveCamera;
+    use crate::environment::GradientSky;
+    use crate::hittable_list::HittableList;
+    use crate::integrator::{Integrator, PathIntegrator};
+    use crate::light::AreaLight;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::rtweekend;
+    use crate::scene::Scene;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Color, Point3, Vec3};
+
+    const WIDTH: usize = 16;
+    const HEIGHT: usize = 16;
+
+    // A diffuse ball on a diffuse floor under a lamp and a dim sky
+    fn lamp_scene() -> Scene {
+        let mut world = HittableList::new();
+        let grey = Arc::new(Lambertian::new_with_values(Color::new_with_values(0.5, 0.5, 0.5)));
+        world.add(Arc::new(Sphere::new_with_values(Point3::new_with_values(0.0, -1000.0, 0.0), 1000.0, grey)));
+        let brown = Arc::new(Lambertian::new_with_values(Color::new_with_values(0.4, 0.2, 0.1)));
+        world.add(Arc::new(Sphere::new_with_values(Point3::new_with_values(0.0, 1.0, 0.0), 1.0, brown)));
+        let lamp_material = Arc::new(DiffuseLight::new_with_values(Color::new_with_values(4.0, 3.6, 3.0)));
+        let lamp = Arc::new(Sphere::new_with_values(Point3::new_with_values(-1.0, 4.5, 1.0), 1.5, lamp_material));
+        let lights = vec![AreaLight::new_with_values(world.len(), lamp.clone())];
+        world.add(lamp);
+
+        let camera = PerspectiveCamera::new(Point3::new_with_values(6.0, 3.0, 4.0), Point3::new_with_values(0.0, 0.8, 0.0),
+            Vec3::new_with_values(0.0, 1.0, 0.0), 40.0, WIDTH as f64 / HEIGHT as f64, 0.0, 7.0);
+        Scene::new_with_values(world, lights, Box::new(GradientSky::new_with_values(0.05)), Box::new(camera))
+    }
+
+    // Mean radiance of each quarter of the image, light traced splats included
+    fn quarter_means(integrator: &dyn Integrator, samples_per_pixel: usize) -> Vec<Color> {
+        let scene = lamp_scene();
+        let mut image = vec![Color::new(); WIDTH * HEIGHT];
+        let mut splats = Vec::new();
+        for j in 0..HEIGHT {
+            for i in 0..WIDTH {
+                for _ in 0..samples_per_pixel {
+                    let u = (i as f64 + rtweekend::random_double()) / (WIDTH - 1) as f64;
+                    let v = (j as f64 + rtweekend::random_double()) / (HEIGHT - 1) as f64;
+                    let r = scene.camera.get_ray(u, v).unwrap();
+                    let color = integrator.li(&r, &scene, &mut splats);
+                    image[j * WIDTH + i] = image[j * WIDTH + i] + color / samples_per_pixel as f64;
+                }
+            }
+        }
+        // Each camera sample traced one light path spread over the whole image
+        let splat_scale = ((WIDTH - 1) * (HEIGHT - 1)) as f64 / (WIDTH * HEIGHT * samples_per_pixel) as f64;
+        for splat in splats {
+            let x = ((splat.u * (WIDTH - 1) as f64) as usize).min(WIDTH - 1);
+            let y = ((splat.v * (HEIGHT - 1) as f64) as usize).min(HEIGHT - 1);
+            image[y * WIDTH + x] = image[y * WIDTH + x] + splat.color * splat_scale;
+        }
+
+        let mut means = vec![Color::new(); 4];
+        for (index, color) in image.iter().enumerate() {
+            let quarter = 2 * (index / WIDTH >= HEIGHT / 2) as usize + (index % WIDTH >= WIDTH / 2) as usize;
+            means[quarter] = means[quarter] + *color * (4.0 / (WIDTH * HEIGHT) as f64);
+        }
+        means
+    }
+
+    // Both estimate the same image, so each part of it should agree, not just
+    // the average
+    #[test]
+    fn agrees_with_path_tracing() {
+        let path = quarter_means(&PathIntegrator::new_with_values(8, 3), 1024);
+        let bdpt = quarter_means(&BdptIntegrator::new_with_values(8), 512);
+        for (quarter, (p, b)) in path.iter().zip(bdpt.iter()).enumerate() {
+            let (p, b) = (p.x() + p.y() + p.z(), b.x() + b.y() + b.z());
+            assert!((b - p).abs() < 0.06 * p, "quarter {}: bdpt {} against path {}", quarter, b, p);
+        }
+    }
+}
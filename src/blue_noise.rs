@@ -0,0 +1,117 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+pub(crate) const MASK_SIZE: usize = 64;
+
+// Spread of the energy each point contributes, in pixels
+const SIGMA: f64 = 1.5;
+
+// A tileable blue noise threshold mask made with Ulichney's void-and-cluster
+// method, returned as values in [0, 1) with every value used once. The seed is
+// fixed so renders are repeatable.
+pub(crate) fn mask(size: usize) -> Vec<f64> {
+    let n = size * size;
+    let mut field = EnergyField::new_with_values(size);
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+
+    // Initial binary pattern, relaxed by moving the point in the tightest
+    // cluster to the largest void until that point is the void itself.
+    let initial = n / 10;
+    while field.count < initial {
+        let i = rng.gen_range(0..n);
+        if !field.points[i] {
+            field.toggle(i);
+        }
+    }
+    loop {
+        let cluster = field.tightest_cluster();
+        field.toggle(cluster);
+        let void = field.largest_void();
+        field.toggle(void);
+        if void == cluster {
+            break;
+        }
+    }
+    let prototype = field.clone();
+
+    let mut rank = vec![0; n];
+
+    // Ranks of the initial points, taking away the most clustered first
+    while field.count > 0 {
+        let cluster = field.tightest_cluster();
+        field.toggle(cluster);
+        rank[cluster] = field.count;
+    }
+
+    // Up to half full, filling the largest voids
+    field = prototype;
+    while field.count < n / 2 {
+        let void = field.largest_void();
+        rank[void] = field.count;
+        field.toggle(void);
+    }
+
+    // The rest, treating the remaining gaps as the points and removing their
+    // tightest clusters
+    let filled = field.count;
+    let mut gaps = EnergyField::new_with_values(size);
+    for i in 0..n {
+        if !field.points[i] {
+            gaps.toggle(i);
+        }
+    }
+    for r in filled..n {
+        let cluster = gaps.tightest_cluster();
+        gaps.toggle(cluster);
+        rank[cluster] = r;
+    }
+
+    rank.iter().map(|&r| r as f64 / n as f64).collect()
+}
+
+#[derive(Clone)]
+struct EnergyField {
+    size: usize,
+    points: Vec<bool>,
+    energy: Vec<f64>,
+    // Gaussian falloff indexed by the wrapped offset between two pixels
+    kernel: Vec<f64>,
+    count: usize,
+}
+
+impl EnergyField {
+    fn new_with_values(size: usize) -> EnergyField {
+        let mut kernel = vec![0.0; size * size];
+        for dy in 0..size {
+            for dx in 0..size {
+                let x = dx.min(size - dx) as f64;
+                let y = dy.min(size - dy) as f64;
+                kernel[dy * size + dx] = (-(x * x + y * y) / (2.0 * SIGMA * SIGMA)).exp();
+            }
+        }
+        EnergyField { size, points: vec![false; size * size], energy: vec![0.0; size * size], kernel, count: 0 }
+    }
+
+    fn toggle(&mut self, i: usize) {
+        let sign = if self.points[i] { -1.0 } else { 1.0 };
+        self.points[i] = !self.points[i];
+        if self.points[i] { self.count += 1 } else { self.count -= 1 }
+
+        let (ix, iy) = (i % self.size, i / self.size);
+        for y in 0..self.size {
+            let dy = (y + self.size - iy) % self.size;
+            for x in 0..self.size {
+                let dx = (x + self.size - ix) % self.size;
+                self.energy[y * self.size + x] += sign * self.kernel[dy * self.size + dx];
+            }
+        }
+    }
+
+    fn tightest_cluster(&self) -> usize {
+        (0..self.points.len()).filter(|&i| self.points[i]).max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b])).unwrap()
+    }
+
+    fn largest_void(&self) -> usize {
+        (0..self.points.len()).filter(|&i| !self.points[i]).min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b])).unwrap()
+    }
+}
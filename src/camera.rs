@@ -1,10 +1,133 @@
+use std::cell::Cell;
 use crate::vec3::{Point3, Vec3};
 use crate::ray::Ray;
-use crate::rtweekend::degrees_to_radians;
+use crate::rtweekend::{self, degrees_to_radians, PI};
 use crate::vec3;
 
+// Turns image coordinates into camera rays. (u, v) run over [0, 1]^2 from the
+// bottom left of the image. Cameras that can't see through some of the image,
+// like a fisheye outside its circle, return None there.
+pub(crate) trait Camera: Send + Sync {
+    fn get_ray(&self, u: f64, v: f64) -> Option<Ray>;
 
-pub struct Camera {
+    // The ray with the weight its radiance counts for, for cameras that don't
+    // see every direction equally well, like a real lens towards the corners
+    fn get_weighted_ray(&self, u: f64, v: f64) -> Option<(Ray, f64)> {
+        self.get_ray(u, v).map(|r| (r, 1.0))
+    }
+
+    // The importance functions used to trace light towards the camera, as
+    // bdpt does. Only the perspective camera has them.
+
+    fn we(&self, _r: &Ray) -> f64 {
+        0.0
+    }
+
+    fn pdf_we(&self, _r: &Ray) -> (f64, f64) {
+        (0.0, 0.0)
+    }
+
+    fn sample_wi(&self, _p: &Point3) -> Option<(Point3, f64, f64, (f64, f64))> {
+        None
+    }
+}
+
+thread_local! {
+    static TIME: Cell<f64> = const { Cell::new(0.0) };
+}
+
+// Sets the moment the current path is traced at, in seconds after the shutter
+// opens. Moving objects are hit where they are at that moment.
+pub(crate) fn set_time(t: f64) {
+    TIME.with(|time| time.set(t));
+}
+
+pub(crate) fn time() -> f64 {
+    TIME.with(|time| time.get())
+}
+
+// Stops of exposure given by the ISO, shutter time in seconds and f-number.
+// ISO 100 at 1/100 s and f/16, the sunny 16 rule for daylight, is 0, as the
+// default sky stands in for daylight.
+pub(crate) fn exposure_stops(iso: f64, shutter: f64, f_number: f64) -> f64 {
+    (iso * shutter * 256.0 / (f_number * f_number)).log2()
+}
+
+// Right, up and backwards for a camera at `lookfrom` facing `lookat`
+pub(crate) fn basis(lookfrom: Point3, lookat: Point3, vup: Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = (lookfrom - lookat).unit_vector();
+    let u = Vec3::cross(vup, w).unit_vector();
+    let v = Vec3::cross(w, u);
+    (u, v, w)
+}
+
+// Like `basis` but levelled, with up along `vup`, so panoramas keep the
+// horizon straight
+fn level_basis(lookfrom: Point3, lookat: Point3, vup: Vec3) -> (Vec3, Vec3, Vec3) {
+    let (u, _, _) = basis(lookfrom, lookat, vup);
+    let v = vup.unit_vector();
+    (u, v, Vec3::cross(u, v))
+}
+
+// Shape of the lens opening, which is what out of focus highlights take:
+// a circle, or a regular polygon made by `blades` straight blades
+#[derive(Clone, Copy)]
+pub(crate) struct Aperture {
+    blades: usize,
+    rotation: f64,
+}
+
+impl Aperture {
+    // Rotation in degrees, fewer than 3 blades for a circle
+    pub(crate) fn new_with_values(blades: usize, rotation: f64) -> Aperture {
+        Aperture { blades, rotation: degrees_to_radians(rotation) }
+    }
+
+    fn is_circle(&self) -> bool {
+        self.blades < 3
+    }
+
+    // Uniformly distributed point in the shape, which fits the unit circle
+    pub(crate) fn sample(&self) -> (f64, f64) {
+        if self.is_circle() {
+            let p = vec3::random_in_unit_disk();
+            return (p.x(), p.y());
+        }
+        // Pick one of the triangles between the centre and an edge, then a
+        // point in it
+        let (r1, r2) = rtweekend::random_double_2d();
+        let n = self.blades as f64;
+        let k = (r1 * n).floor().min(n - 1.0);
+        let s = (r1 * n - k).sqrt();
+        let a0 = self.rotation + 2.0 * PI * k / n;
+        let a1 = a0 + 2.0 * PI / n;
+        let (b0, b1) = (s * (1.0 - r2), s * r2);
+        (b0 * a0.cos() + b1 * a1.cos(), b0 * a0.sin() + b1 * a1.sin())
+    }
+
+    pub(crate) fn area(&self) -> f64 {
+        if self.is_circle() {
+            PI
+        } else {
+            let n = self.blades as f64;
+            0.5 * n * (2.0 * PI / n).sin()
+        }
+    }
+
+    pub(crate) fn contains(&self, x: f64, y: f64) -> bool {
+        let r = (x * x + y * y).sqrt();
+        if self.is_circle() || r == 0.0 {
+            return r <= 1.0;
+        }
+        // Angle from the middle of the nearest edge, which is cos(π / n) away
+        let sector = 2.0 * PI / self.blades as f64;
+        let angle = (y.atan2(x) - self.rotation).rem_euclid(sector) - sector / 2.0;
+        r * angle.cos() <= (sector / 2.0).cos()
+    }
+}
+
+// The book's thin lens camera
+pub(crate) struct PerspectiveCamera {
     origin: Point3,
     lower_left_corner: Point3,
     horizontal: Vec3,
@@ -13,9 +136,13 @@ pub struct Camera {
     v: Vec3,
     w: Vec3,
     lens_radius: f64,
+    aperture: Aperture,
+    focus_dist: f64,
+    // Area of the image at unit distance, used for the importance function
+    film_area: f64,
 }
 
-impl Camera {
+impl PerspectiveCamera {
     pub fn new(lookfrom: Point3,lookat: Point3,vup: Point3,vfov: f64, aspect_ratio: f64, aperture: f64, focus_dist: f64) -> Self {
 
         let theta = degrees_to_radians(vfov);
@@ -23,9 +150,7 @@ impl Camera {
         let viewport_height = 2.0 * h;
         let viewport_width = aspect_ratio * viewport_height;
 
-        let w = (lookfrom - lookat).unit_vector();
-        let u = Vec3::cross(vup, w).unit_vector();
-        let v = Vec3::cross(w, u);
+        let (u, v, w) = basis(lookfrom, lookat, vup);
 
         let origin = lookfrom;
         let horizontal = u * viewport_width * focus_dist;
@@ -34,7 +159,7 @@ impl Camera {
 
         let lens_radius = aperture / 2.0;
 
-        Camera {
+        PerspectiveCamera {
             origin,
             lower_left_corner,
             horizontal,
@@ -42,14 +167,233 @@ impl Camera {
             u,
             v,
             w,
-            lens_radius,}
+            lens_radius,
+            aperture: Aperture::new_with_values(0, 0.0),
+            focus_dist,
+            film_area: viewport_width * viewport_height,}
+    }
+
+    pub(crate) fn set_aperture(&mut self, aperture: Aperture) {
+        self.aperture = aperture;
+    }
+
+    // Direction from the middle of the lens through (u, v)
+    pub(crate) fn pinhole_direction(&self, u: f64, v: f64) -> Vec3 {
+        self.lower_left_corner + self.horizontal * u + self.vertical * v - self.origin
+    }
+
+    // Point on the lens in camera space
+    fn sample_lens(&self) -> Vec3 {
+        let (x, y) = self.aperture.sample();
+        self.u * (x * self.lens_radius) + self.v * (y * self.lens_radius)
+    }
+
+    fn lens_area(&self) -> f64 {
+        if self.lens_radius > 0.0 { self.aperture.area() * self.lens_radius * self.lens_radius } else { 1.0 }
+    }
+
+    // Image coordinates of a ray leaving the lens, None if it misses the image
+    fn raster_position(&self, origin: &Point3, direction: &Vec3) -> Option<(f64, f64)> {
+        let cos_theta = Vec3::dot(&direction.unit_vector(), &-self.w);
+        if cos_theta <= 0.0 {
+            return None;
+        }
+        let t = self.focus_dist / Vec3::dot(direction, &-self.w);
+        let offset = *origin + *direction * t - self.lower_left_corner;
+        let s = Vec3::dot(&offset, &self.horizontal) / self.horizontal.length_squared();
+        let t = Vec3::dot(&offset, &self.vertical) / self.vertical.length_squared();
+        if (0.0..=1.0).contains(&s) && (0.0..=1.0).contains(&t) { Some((s, t)) } else { None }
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn get_ray(&self, u: f64, v: f64) -> Option<Ray> {
+        let offset = self.sample_lens();
+
+
+        Some(Ray::new_with_values(self.origin +offset, self.lower_left_corner + self.horizontal * u + self.vertical * v - self.origin - offset))
+    }
+
+    // The importance functions below are normalised over the [0, 1]^2 image
+    // coordinates that `get_ray` takes, so light traced paths can be splatted
+    // onto the same image as camera paths.
+
+    // Importance emitted along a ray leaving the lens
+    fn we(&self, r: &Ray) -> f64 {
+        if self.raster_position(&r.origin(), &r.direction()).is_none() {
+            return 0.0;
+        }
+        let cos_theta = Vec3::dot(&r.direction().unit_vector(), &-self.w);
+        1.0 / (self.film_area * self.lens_area() * cos_theta.powi(4))
+    }
+
+    // Positional and directional pdfs of `get_ray` producing this ray
+    fn pdf_we(&self, r: &Ray) -> (f64, f64) {
+        if self.raster_position(&r.origin(), &r.direction()).is_none() {
+            return (0.0, 0.0);
+        }
+        let cos_theta = Vec3::dot(&r.direction().unit_vector(), &-self.w);
+        (1.0 / self.lens_area(), 1.0 / (self.film_area * cos_theta.powi(3)))
     }
 
-    pub(crate) fn get_ray(&self, u: f64, v: f64) -> Ray {
-        let rd = vec3::random_in_unit_disk() * self.lens_radius;
-        let offset = self.u * rd.x() + self.v * rd.y();
+    // Samples a point on the lens that can see `p`. Returns the lens point, the
+    // importance arriving at p, the solid angle pdf at p and the image position.
+    fn sample_wi(&self, p: &Point3) -> Option<(Point3, f64, f64, (f64, f64))> {
+        let lens_point = self.origin + self.sample_lens();
 
+        let to_lens = lens_point - *p;
+        let dist_squared = to_lens.length_squared();
+        let wi = to_lens.unit_vector();
+        let cos_lens = Vec3::dot(&wi, &self.w).abs();
+        if cos_lens == 0.0 {
+            return None;
+        }
 
-        Ray::new_with_values(self.origin +offset, self.lower_left_corner + self.horizontal * u + self.vertical * v - self.origin - offset)
+        let r = Ray::new_with_values(lens_point, -wi);
+        let raster = self.raster_position(&lens_point, &-wi)?;
+        let pdf = dist_squared / (cos_lens * self.lens_area());
+        Some((lens_point, self.we(&r), pdf, raster))
     }
-}
\ No newline at end of file
+}
+
+// Parallel rays with no perspective, framing what a perspective camera with
+// the same field of view sees at `focus_dist`
+pub(crate) struct OrthographicCamera {
+    lower_left_corner: Point3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    direction: Vec3,
+}
+
+impl OrthographicCamera {
+    pub(crate) fn new_with_values(lookfrom: Point3, lookat: Point3, vup: Vec3, vfov: f64, aspect_ratio: f64, focus_dist: f64) -> OrthographicCamera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        let height = 2.0 * (degrees_to_radians(vfov) / 2.0).tan() * focus_dist;
+        let horizontal = u * height * aspect_ratio;
+        let vertical = v * height;
+        OrthographicCamera { lower_left_corner: lookfrom - horizontal / 2.0 - vertical / 2.0, horizontal, vertical, direction: -w }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn get_ray(&self, u: f64, v: f64) -> Option<Ray> {
+        Some(Ray::new_with_values(self.lower_left_corner + self.horizontal * u + self.vertical * v, self.direction))
+    }
+}
+
+// Direction at longitude `phi` around the up axis, 0 looking ahead and
+// increasing to the right, and latitude `theta` above the horizon
+fn spherical_direction(u: &Vec3, v: &Vec3, w: &Vec3, phi: f64, theta: f64) -> Vec3 {
+    *u * (phi.sin() * theta.cos()) + *v * theta.sin() - *w * (phi.cos() * theta.cos())
+}
+
+// A full 360 by 180 degree latitude-longitude panorama with the view
+// direction in the middle and the horizon level, best rendered at 2:1
+pub(crate) struct EquirectangularCamera {
+    origin: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl EquirectangularCamera {
+    pub(crate) fn new_with_values(lookfrom: Point3, lookat: Point3, vup: Vec3) -> EquirectangularCamera {
+        let (u, v, w) = level_basis(lookfrom, lookat, vup);
+        EquirectangularCamera { origin: lookfrom, u, v, w }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn get_ray(&self, u: f64, v: f64) -> Option<Ray> {
+        let direction = spherical_direction(&self.u, &self.v, &self.w, (u - 0.5) * 2.0 * PI, (v - 0.5) * PI);
+        Some(Ray::new_with_values(self.origin, direction))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum FisheyeMapping {
+    // Distance from the centre proportional to the angle off axis
+    Equidistant,
+    // Equal solid angles cover equal areas of the image
+    Equisolid,
+}
+
+// A circular fisheye covering `fov` degrees across the circle that fits the
+// image height. Outside the circle is black.
+pub(crate) struct FisheyeCamera {
+    origin: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    max_theta: f64,
+    aspect_ratio: f64,
+    mapping: FisheyeMapping,
+}
+
+impl FisheyeCamera {
+    pub(crate) fn new_with_values(lookfrom: Point3, lookat: Point3, vup: Vec3, fov: f64, aspect_ratio: f64, mapping: FisheyeMapping) -> FisheyeCamera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        FisheyeCamera { origin: lookfrom, u, v, w, max_theta: degrees_to_radians(fov) / 2.0, aspect_ratio, mapping }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn get_ray(&self, u: f64, v: f64) -> Option<Ray> {
+        let x = (2.0 * u - 1.0) * self.aspect_ratio;
+        let y = 2.0 * v - 1.0;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => r * self.max_theta,
+            FisheyeMapping::Equisolid => 2.0 * (r * (self.max_theta / 2.0).sin()).asin(),
+        };
+        let phi = y.atan2(x);
+        let direction = (self.u * phi.cos() + self.v * phi.sin()) * theta.sin() - self.w * theta.cos();
+        Some(Ray::new_with_values(self.origin, direction))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum StereoLayout {
+    // Left eye on the left half
+    SideBySide,
+    // Left eye on the top half
+    OverUnder,
+}
+
+// Omni-directional stereo: an equirectangular panorama for each eye, whose
+// rays start from a circle `ipd` across so every direction is seen with the
+// eyes side by side, as VR headsets expect
+pub(crate) struct OdsCamera {
+    origin: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    ipd: f64,
+    layout: StereoLayout,
+}
+
+impl OdsCamera {
+    pub(crate) fn new_with_values(lookfrom: Point3, lookat: Point3, vup: Vec3, ipd: f64, layout: StereoLayout) -> OdsCamera {
+        let (u, v, w) = level_basis(lookfrom, lookat, vup);
+        OdsCamera { origin: lookfrom, u, v, w, ipd, layout }
+    }
+}
+
+impl Camera for OdsCamera {
+    fn get_ray(&self, u: f64, v: f64) -> Option<Ray> {
+        // Each eye's own image coordinates
+        let (left, u, v) = match self.layout {
+            StereoLayout::SideBySide => (u < 0.5, (2.0 * u).fract(), v),
+            StereoLayout::OverUnder => (v >= 0.5, u, (2.0 * v).fract()),
+        };
+        let phi = (u - 0.5) * 2.0 * PI;
+        let direction = spherical_direction(&self.u, &self.v, &self.w, phi, (v - 0.5) * PI);
+        // The eyes sit either side of the centre, across the horizontal direction
+        let right = self.u * phi.cos() + self.w * phi.sin();
+        let offset = right * (if left { -self.ipd } else { self.ipd } / 2.0);
+        Some(Ray::new_with_values(self.origin + offset, direction))
+    }
+}
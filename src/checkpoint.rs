@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use crate::film::Film;
+
+const MAGIC: &[u8; 8] = b"RTCKPT03";
+
+// Everything needed to carry on with a progressive render: the image settings
+// and the passes done so far. No random number state is kept, as every pixel
+// sample seeds its own from the pixel and the pass. The film is kept
+// serialised until the settings have set up the image.
+pub(crate) struct Checkpoint {
+    pub settings: Vec<String>,
+    pub passes: usize,
+    pub film: Vec<u8>,
+}
+
+impl Checkpoint {
+    pub(crate) fn read(path: &str) -> std::io::Result<Checkpoint> {
+        let data = std::fs::read(path)?;
+        let mut input = data.as_slice();
+
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a checkpoint file"));
+        }
+
+        let count = read_u64(&mut input)? as usize;
+        let mut settings = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut bytes = vec![0u8; read_u64(&mut input)? as usize];
+            input.read_exact(&mut bytes)?;
+            settings.push(String::from_utf8(bytes).map_err(|_| invalid("Invalid setting in checkpoint"))?);
+        }
+        let passes = read_u64(&mut input)? as usize;
+
+        Ok(Checkpoint { settings, passes, film: input.to_vec() })
+    }
+}
+
+// Saves the render after `passes` passes, through a temporary file so a crash
+// while writing leaves the previous checkpoint intact.
+pub(crate) fn write(path: &str, settings: &[String], passes: usize, film: &Film) -> std::io::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    {
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        out.write_all(MAGIC)?;
+        write_u64(&mut out, settings.len() as u64)?;
+        for setting in settings {
+            write_u64(&mut out, setting.len() as u64)?;
+            out.write_all(setting.as_bytes())?;
+        }
+        write_u64(&mut out, passes as u64)?;
+        film.write_state(&mut out)?;
+        out.flush()?;
+    }
+    std::fs::rename(&temporary, path)
+}
+
+pub(crate) fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+pub(crate) fn write_u64<W: Write>(out: &mut W, value: u64) -> std::io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_f64<W: Write>(out: &mut W, value: f64) -> std::io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn read_u64<R: Read>(input: &mut R) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_f64<R: Read>(input: &mut R) -> std::io::Result<f64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::film::{CropWindow, FilmTile};
+    use crate::filter::TentFilter;
+    use crate::integrator::Splat;
+    use crate::vec3::Color;
+
+    #[test]
+    fn round_trips() {
+        let (width, height) = (5, 4);
+        let filter = TentFilter::new_with_values(1.5);
+        let mut film = Film::new_with_values(width, height);
+        let mut tile = FilmTile::new_with_values(width, height, CropWindow { x0: 0, y0: 0, x1: width, y1: height }, &filter);
+        for i in 0..40 {
+            let (u, v) = ((i % 7) as f64 / 7.0, (i % 5) as f64 / 5.0);
+            tile.add_sample(&filter, u, v, Color::new_with_values(u, v, i as f64 * 0.1), 0.5);
+        }
+        tile.add_splat(Splat { u: 0.3, v: 0.6, color: Color::new_with_values(1.0, 2.0, 3.0) });
+        film.add_tile(tile, None);
+
+        let path = std::env::temp_dir().join(format!("ray_tracer_test_{}_checkpoint", std::process::id())).to_string_lossy().into_owned();
+        let settings = vec![String::from("--integrator"), String::from("bdpt")];
+        write(&path, &settings, 7, &film).unwrap();
+        let checkpoint = Checkpoint::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        let checkpoint = checkpoint.unwrap();
+        assert_eq!(checkpoint.settings, settings);
+        assert_eq!(checkpoint.passes, 7);
+
+        let mut restored = Film::new_with_values(width, height);
+        restored.read_state(&mut checkpoint.film.as_slice()).unwrap();
+        let colors = |film: &Film| film.pixels().iter().map(|c| [c.x(), c.y(), c.z()]).collect::<Vec<_>>();
+        assert_eq!(colors(&restored), colors(&film));
+        assert_eq!(restored.alphas(), film.alphas());
+        assert_eq!(restored.mean_variances(), film.mean_variances());
+
+        let mut wrong_size = Film::new_with_values(width + 1, height);
+        assert!(wrong_size.read_state(&mut checkpoint.film.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = std::env::temp_dir().join(format!("ray_tracer_test_{}_not_a_checkpoint", std::process::id())).to_string_lossy().into_owned();
+        std::fs::write(&path, b"P3\n1 1\n255\n0 0 0\n").unwrap();
+        let result = Checkpoint::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -1,20 +1,31 @@
-
-
-use crate::vec3::Color;
 use std::io::Write;
-use crate::rtweekend::clamp;
+use crate::vec3::{Color, Vec3};
+
 
+pub(crate) fn write_color<W: Write>(out: &mut W, rgb: [u8; 3]) {
+    writeln!(out, "{} {} {}", rgb[0], rgb[1], rgb[2]).expect("Error writing color");
+}
 
-pub(crate) fn write_color<W: Write>(out: &mut W, pixel_color: Color, samples_per_pixel: i32) {
-    let r = pixel_color.x();
-    let g = pixel_color.y();
-    let b = pixel_color.z();
+// sRGB encoded value to linear light
+pub(crate) fn srgb_to_linear(v: f64) -> f64 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
 
-    let scale = 1.0 / samples_per_pixel as f64;
-    let r = (scale * r).sqrt();
-    let g = (scale * g).sqrt();
-    let b = (scale * b).sqrt();
+// Linear light to the sRGB encoding displays expect
+pub(crate) fn linear_to_srgb(v: f64) -> f64 {
+    if v <= 0.0031308 { 12.92 * v } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
 
+// The Rec. 709 camera OETF
+pub(crate) fn rec709_oetf(v: f64) -> f64 {
+    if v < 0.018 { 4.5 * v } else { 1.099 * v.powf(0.45) - 0.099 }
+}
 
-    writeln!(out,"{} {} {}", (256.00 * clamp(r, 0.0, 0.999)) as i32 , (256.00 * clamp(g, 0.0, 0.999)) as i32, (256.00 * clamp(b, 0.0, 0.999)) as i32).expect("Error writing color");
-}
\ No newline at end of file
+// CIE XYZ to linear Rec. 709 with a D65 white
+pub(crate) fn xyz_to_rgb(xyz: Vec3) -> Color {
+    Color::new_with_values(
+        3.2404542 * xyz.x() - 1.5371385 * xyz.y() - 0.4985314 * xyz.z(),
+        -0.9692660 * xyz.x() + 1.8760108 * xyz.y() + 0.0415560 * xyz.z(),
+        0.0556434 * xyz.x() - 0.2040259 * xyz.y() + 1.0572252 * xyz.z(),
+    )
+}
@@ -0,0 +1,165 @@
+use crate::environment::luminance;
+use crate::hittable::{hit_record, Hittable};
+use crate::ray::Ray;
+use crate::rtweekend;
+use crate::sampler;
+use crate::scene::Scene;
+use crate::vec3::{Color, Vec3};
+
+// Camera samples per pixel used for the feature buffers. No lighting is traced
+// for them, so a few are enough to antialias edges and average the lens.
+const FEATURE_SAMPLES: usize = 8;
+
+// Mirror and glass bounces followed to find the surface seen in them
+const SPECULAR_DEPTH: usize = 8;
+
+// Five passes reach 2^5 = 32 pixels away
+const ITERATIONS: usize = 5;
+
+// Edge stopping strengths. Colour is compared after mapping it into [0, 1)
+// and its tolerance halves every pass, as in Dammertz et al.
+const SIGMA_COLOR: f64 = 0.2;
+const SIGMA_LUMINANCE: f64 = 2.0;
+const SIGMA_NORMAL: f64 = 0.3;
+const SIGMA_ALBEDO: f64 = 0.1;
+
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Per-pixel surface colour and normal where the camera rays first reach a
+// diffuse surface, rows top to bottom. Mirrors and glass are looked through,
+// tinting the albedo, so what they show is kept apart from the noise. Rays
+// that miss get the background, clamped, and a zero normal.
+pub(crate) struct FeatureBuffers {
+    pub albedo: Vec<Color>,
+    pub normal: Vec<Vec3>,
+}
+
+impl FeatureBuffers {
+    pub(crate) fn new_with_values(scene: &Scene, width: usize, height: usize) -> FeatureBuffers {
+        let mut albedo = vec![Color::new(); width * height];
+        let mut normal = vec![Vec3::new(); width * height];
+
+        for j in 0..height {
+            for i in 0..width {
+                let index = (height - 1 - j) * width + i;
+                for s in 0..FEATURE_SAMPLES {
+                    sampler::start_pixel_sample(i, j, s);
+                    let (du, dv) = rtweekend::random_double_2d();
+                    let u = (i as f64 + du) / (width - 1) as f64;
+                    let v = (j as f64 + dv) / (height - 1) as f64;
+                    let (a, n) = match scene.camera.get_ray(u, v) {
+                        Some(r) => first_diffuse_hit(r, scene),
+                        None => (Color::new(), Vec3::new()),
+                    };
+                    albedo[index] = albedo[index] + a;
+                    normal[index] = normal[index] + n;
+                }
+                albedo[index] = albedo[index] / FEATURE_SAMPLES as f64;
+                normal[index] = normal[index] / FEATURE_SAMPLES as f64;
+            }
+        }
+
+        FeatureBuffers { albedo, normal }
+    }
+}
+
+fn first_diffuse_hit(mut r: Ray, scene: &Scene) -> (Color, Vec3) {
+    let mut throughput = Color::new_with_values(1.0, 1.0, 1.0);
+    for _ in 0..SPECULAR_DEPTH {
+        let mut rec = hit_record::new();
+        if !scene.world.hit(&r, 0.001, rtweekend::INFINITY, &mut rec) {
+            let background = scene.environment.value(&r.direction());
+            let clamped = Color::new_with_values(background.x().min(1.0), background.y().min(1.0), background.z().min(1.0));
+            return (throughput * clamped, Vec3::new());
+        }
+
+        let mat = rec.mat_ptr.clone().unwrap();
+        let mut scattered = Ray::new();
+        let mut attenuation = Color::new();
+        if !mat.is_specular() || !mat.scatter(&r, &rec, &mut attenuation, &mut scattered) {
+            return (throughput * mat.albedo(&rec), rec.normal);
+        }
+        throughput = throughput * attenuation;
+        r = scattered;
+    }
+    (Color::new(), Vec3::new())
+}
+
+// Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010) with the colour
+// weights scaled by each pixel's noise as in SVGF (Schied et al. 2017). The
+// image is divided by the albedo first so only the lighting gets blurred, then
+// each pass applies a 5x5 B3 spline kernel with its taps twice as far apart as
+// the last, weighting every tap by how alike its colour, normal and albedo
+// are. `variance` is the variance of each pixel's mean luminance; without it
+// a fixed colour tolerance is used.
+pub(crate) fn denoise(pixels: &[Color], variance: Option<&[f64]>, features: &FeatureBuffers, width: usize, height: usize) -> Vec<Color> {
+    let albedo: Vec<Color> = features.albedo.iter().map(|a| {
+        Color::new_with_values(a.x().max(0.01), a.y().max(0.01), a.z().max(0.01))
+    }).collect();
+    let mut lighting: Vec<Color> = pixels.iter().zip(&albedo).map(|(&c, &a)| c / a).collect();
+    let mut variance: Vec<f64> = match variance {
+        Some(v) => v.iter().zip(&albedo).map(|(&v, a)| v / (luminance(a) * luminance(a))).collect(),
+        None => Vec::new(),
+    };
+    let mut filtered = vec![Color::new(); lighting.len()];
+    let mut filtered_variance = variance.clone();
+
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        let sigma_color = SIGMA_COLOR / (1 << iteration) as f64;
+
+        for y in 0..height {
+            for x in 0..width {
+                let p = y * width + x;
+                let color_p = compress(&lighting[p]);
+                let luminance_p = luminance(&lighting[p]);
+                let sigma_luminance = variance.get(p).map(|v| SIGMA_LUMINANCE * v.sqrt() + 1e-4);
+                let mut sum = Color::new();
+                let mut weight_sum = 0.0;
+                let mut variance_sum = 0.0;
+
+                for (ky, hy) in KERNEL.iter().enumerate() {
+                    let qy = y as isize + (ky as isize - 2) * step;
+                    if qy < 0 || qy >= height as isize {
+                        continue;
+                    }
+                    for (kx, hx) in KERNEL.iter().enumerate() {
+                        let qx = x as isize + (kx as isize - 2) * step;
+                        if qx < 0 || qx >= width as isize {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+
+                        let color_weight = match sigma_luminance {
+                            Some(sigma) => -(luminance(&lighting[q]) - luminance_p).abs() / sigma,
+                            None => -(compress(&lighting[q]) - color_p).length_squared() / (sigma_color * sigma_color),
+                        };
+                        let dn = (features.normal[q] - features.normal[p]).length_squared();
+                        let da = (albedo[q] - albedo[p]).length_squared();
+                        let weight = hx * hy
+                            * (color_weight - dn / (SIGMA_NORMAL * SIGMA_NORMAL) - da / (SIGMA_ALBEDO * SIGMA_ALBEDO)).exp();
+                        sum = sum + lighting[q] * weight;
+                        weight_sum += weight;
+                        if let Some(v) = variance.get(q) {
+                            variance_sum += weight * weight * v;
+                        }
+                    }
+                }
+                filtered[p] = sum / weight_sum;
+                if let Some(v) = filtered_variance.get_mut(p) {
+                    *v = variance_sum / (weight_sum * weight_sum);
+                }
+            }
+        }
+        std::mem::swap(&mut lighting, &mut filtered);
+        std::mem::swap(&mut variance, &mut filtered_variance);
+    }
+
+    lighting.iter().zip(&albedo).map(|(&c, &a)| c * a).collect()
+}
+
+// Maps radiance into [0, 1) so bright pixels can still be compared
+fn compress(c: &Color) -> Color {
+    let f = |x: f64| x.max(0.0) / (1.0 + x.max(0.0));
+    Color::new_with_values(f(c.x()), f(c.y()), f(c.z()))
+}
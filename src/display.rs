@@ -0,0 +1,180 @@
+use crate::color;
+use crate::environment::luminance;
+use crate::post::PostEffects;
+use crate::vec3::Color;
+
+#[derive(Clone, Copy)]
+pub(crate) enum ToneMap {
+    // Values past 1 are simply cut off, as in the book
+    Clamp,
+    // Reinhard's L / (1 + L) on luminance, keeping the hue
+    Reinhard,
+    // Stephen Hill's fit of the ACES reference and sRGB output transforms
+    Aces,
+    // Troy Sobotka's AgX, as approximated by Benjamin Wrensch
+    Agx,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Transfer {
+    // Square root, the book's gamma 2
+    Gamma2,
+    Srgb,
+    Rec709,
+    Linear,
+}
+
+// Turns the renderer's scene-referred linear Rec. 709 colours into 8-bit
+// display values: the post effects first, then exposure and white balance,
+// then the tone curve, then the transfer function encoding for the display
+// and quantisation, optionally dithered to break up banding.
+pub(crate) struct DisplayTransform {
+    effects: PostEffects,
+    exposure: f64,
+    white_balance: Color,
+    tone_map: ToneMap,
+    transfer: Transfer,
+    dither: bool,
+}
+
+impl DisplayTransform {
+    pub(crate) fn new_with_values(effects: PostEffects, exposure: f64, white_balance: Option<f64>, tone_map: ToneMap, transfer: Transfer, dither: bool) -> DisplayTransform {
+        let white_balance = match white_balance {
+            // Gains that turn light of the given colour temperature into D65 white
+            Some(kelvin) => {
+                let light = blackbody_rgb(kelvin);
+                let white = blackbody_rgb(6504.0);
+                let light = light / luminance(&light);
+                let white = white / luminance(&white);
+                white / light
+            }
+            None => Color::new_with_values(1.0, 1.0, 1.0),
+        };
+        DisplayTransform { effects, exposure: 2f64.powf(exposure), white_balance, tone_map, transfer, dither }
+    }
+
+    // Writes values through unchanged, for data such as heat maps
+    pub(crate) fn raw() -> DisplayTransform {
+        DisplayTransform::new_with_values(PostEffects::new(), 0.0, None, ToneMap::Clamp, Transfer::Linear, false)
+    }
+
+    // The image with the post effects applied, ready for `to_bytes`
+    pub(crate) fn prepare(&self, pixels: &[Color], width: usize, height: usize) -> Vec<Color> {
+        if self.effects.is_empty() { pixels.to_vec() } else { self.effects.apply(pixels, width, height) }
+    }
+
+    // Display encoded colour in [0, 1]
+    pub(crate) fn encode(&self, c: Color) -> Color {
+        let c = c * self.white_balance * self.exposure;
+        let c = Color::new_with_values(c.x().max(0.0), c.y().max(0.0), c.z().max(0.0));
+        let mapped = match self.tone_map {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => {
+                let l = luminance(&c);
+                if l > 0.0 { c * (1.0 / (1.0 + l)) } else { c }
+            }
+            ToneMap::Aces => aces_fitted(c),
+            ToneMap::Agx => agx(c),
+        };
+        let encode = |v: f64| {
+            let v = v.clamp(0.0, 1.0);
+            match self.transfer {
+                Transfer::Gamma2 => v.sqrt(),
+                Transfer::Srgb => color::linear_to_srgb(v),
+                Transfer::Rec709 => color::rec709_oetf(v),
+                Transfer::Linear => v,
+            }
+        };
+        Color::new_with_values(encode(mapped.x()), encode(mapped.y()), encode(mapped.z()))
+    }
+
+    // The colour of pixel (x, y) as 8-bit values
+    pub(crate) fn to_bytes(&self, c: Color, x: usize, y: usize) -> [u8; 3] {
+        let encoded = self.encode(c);
+        let mut bytes = [0u8; 3];
+        for (channel, byte) in bytes.iter_mut().enumerate() {
+            let mut v = encoded[channel];
+            if self.dither {
+                // Triangular noise one step wide, fixed per pixel so images repeat
+                let h = hash((y * 65536 + x) as u64 * 3 + channel as u64);
+                let u1 = (h >> 40) as f64 / (1u64 << 24) as f64;
+                let u2 = (h & 0xFF_FFFF) as f64 / (1u64 << 24) as f64;
+                v += (u1 + u2 - 1.0) / 255.0;
+            }
+            *byte = (256.0 * v.clamp(0.0, 0.999)) as u8;
+        }
+        bytes
+    }
+}
+
+pub(crate) fn hash(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+fn mul(m: &[[f64; 3]; 3], c: Color) -> Color {
+    Color::new_with_values(
+        m[0][0] * c.x() + m[0][1] * c.y() + m[0][2] * c.z(),
+        m[1][0] * c.x() + m[1][1] * c.y() + m[1][2] * c.z(),
+        m[2][0] * c.x() + m[2][1] * c.y() + m[2][2] * c.z(),
+    )
+}
+
+fn aces_fitted(c: Color) -> Color {
+    // sRGB to the ACES reference transform's input space, and back afterwards
+    const INPUT: [[f64; 3]; 3] = [[0.59719, 0.35458, 0.04823], [0.07600, 0.90834, 0.01566], [0.02840, 0.13383, 0.83777]];
+    const OUTPUT: [[f64; 3]; 3] = [[1.60475, -0.53108, -0.07367], [-0.10208, 1.10813, -0.00605], [-0.00327, -0.07276, 1.07602]];
+
+    let rrt_odt = |v: f64| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.4329510) + 0.238081);
+    let c = mul(&INPUT, c);
+    mul(&OUTPUT, Color::new_with_values(rrt_odt(c.x()), rrt_odt(c.y()), rrt_odt(c.z())))
+}
+
+fn agx(c: Color) -> Color {
+    const INSET: [[f64; 3]; 3] = [
+        [0.842479062253094, 0.0784335999999992, 0.0792237451477643],
+        [0.0423282422610123, 0.878468636469772, 0.0791661274605434],
+        [0.0423756549057051, 0.0784336, 0.879142973793104],
+    ];
+    const OUTSET: [[f64; 3]; 3] = [
+        [1.19687900512017, -0.0980208811401368, -0.0990297440797205],
+        [-0.0528968517574562, 1.15190312990417, -0.0989611768448433],
+        [-0.0529716355144438, -0.0980434501171241, 1.15107367264116],
+    ];
+    const MIN_EV: f64 = -12.47393;
+    const MAX_EV: f64 = 4.026069;
+
+    // Log encoding over about 16.5 stops, then a sigmoid fitted with a polynomial
+    let curve = |v: f64| {
+        let x = (v.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    };
+    let c = mul(&INSET, c);
+    let c = mul(&OUTSET, Color::new_with_values(curve(c.x()), curve(c.y()), curve(c.z())));
+    // The curve's output is meant for a gamma 2.2 display, so it is linearised again
+    Color::new_with_values(c.x().max(0.0).powf(2.2), c.y().max(0.0).powf(2.2), c.z().max(0.0).powf(2.2))
+}
+
+// Linear Rec. 709 colour of a blackbody at `kelvin`, from Kang et al.'s fit of
+// the Planckian locus (1667 K to 25000 K)
+fn blackbody_rgb(kelvin: f64) -> Color {
+    let t = kelvin.clamp(1667.0, 25000.0);
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / (t * t * t) - 0.2343589e6 / (t * t) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / (t * t * t) + 2.1070379e6 / (t * t) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t <= 2222.0 {
+        -1.1063814 * x * x * x - 1.34811020 * x * x + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x * x * x - 1.37418593 * x * x + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x * x * x - 5.87338670 * x * x + 3.75112997 * x - 0.37001483
+    };
+
+    let rgb = color::xyz_to_rgb(Color::new_with_values(x / y, 1.0, (1.0 - x - y) / y));
+    Color::new_with_values(rgb.x().max(1e-4), rgb.y().max(1e-4), rgb.z().max(1e-4))
+}
@@ -0,0 +1,372 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::checkpoint::{invalid, read_u64, write_u64};
+use crate::film::{CropWindow, FilmTile};
+use crate::options::Options;
+
+// Options naming files the workers need a copy of
+const FILE_OPTIONS: [&str; 3] = ["--env-map", "--lens", "--animation"];
+
+// Options that only matter to the coordinator or to each worker itself
+const LOCAL_OPTIONS: [&str; 3] = ["--listen", "--threads", "--worker-timeout"];
+
+// What every worker is sent when it connects: the command line the render was
+// started with and the contents of the files it names.
+pub(crate) struct Job {
+    args: Vec<String>,
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl Job {
+    pub(crate) fn new_with_values(options: &Options) -> std::io::Result<Job> {
+        let mut args = Vec::new();
+        let mut files = Vec::new();
+        let mut iter = options.args.iter();
+        while let Some(arg) = iter.next() {
+            if LOCAL_OPTIONS.contains(&arg.as_str()) {
+                iter.next();
+                continue;
+            }
+            args.push(arg.clone());
+            if FILE_OPTIONS.contains(&arg.as_str()) {
+                if let Some(path) = iter.next() {
+                    files.push((path.clone(), std::fs::read(path)?));
+                    args.push(path.clone());
+                }
+            }
+        }
+        Ok(Job { args, files })
+    }
+
+    fn write<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        write_u64(out, self.args.len() as u64)?;
+        for arg in &self.args {
+            write_bytes(out, arg.as_bytes())?;
+        }
+        write_u64(out, self.files.len() as u64)?;
+        for (path, data) in &self.files {
+            write_bytes(out, path.as_bytes())?;
+            write_bytes(out, data)?;
+        }
+        Ok(())
+    }
+
+    fn read<R: Read>(input: &mut R) -> std::io::Result<Job> {
+        let string = |input: &mut R| String::from_utf8(read_bytes(input)?).map_err(|_| invalid("Invalid text in job"));
+        let mut args = Vec::new();
+        for _ in 0..read_u64(input)? {
+            args.push(string(input)?);
+        }
+        let mut files = Vec::new();
+        for _ in 0..read_u64(input)? {
+            let path = string(input)?;
+            files.push((path, read_bytes(input)?));
+        }
+        Ok(Job { args, files })
+    }
+
+    // Writes the files to `directory` and points the command line at them
+    fn unpack(&self, directory: &Path) -> std::io::Result<Vec<String>> {
+        std::fs::create_dir_all(directory)?;
+        let mut args = self.args.clone();
+        for (n, (path, data)) in self.files.iter().enumerate() {
+            let name = Path::new(path).file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+            let copy = directory.join(format!("{}_{}", n, name));
+            std::fs::write(&copy, data)?;
+            for k in 1..args.len() {
+                if FILE_OPTIONS.contains(&args[k - 1].as_str()) && &args[k] == path {
+                    args[k] = copy.to_string_lossy().into_owned();
+                }
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> std::io::Result<()> {
+    write_u64(out, bytes.len() as u64)?;
+    out.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(input: &mut R) -> std::io::Result<Vec<u8>> {
+    let length = read_u64(input)? as usize;
+    let mut bytes = Vec::new();
+    input.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(invalid("Message cut short"));
+    }
+    Ok(bytes)
+}
+
+// The tiles of the frame being rendered that no worker has taken yet
+struct Work {
+    frame: usize,
+    width: usize,
+    height: usize,
+    tiles: Vec<CropWindow>,
+    queue: VecDeque<usize>,
+}
+
+// Hands out tiles to worker processes that connect over TCP and gathers what
+// they render. Each worker takes a few tiles per thread at a time. When one
+// disconnects, sends back a tile that isn't the one it was asked for, or
+// hasn't sent a tile for `timeout`, it is dropped and the tiles it hadn't
+// sent back go back in the queue for the others.
+pub(crate) struct Coordinator {
+    work: Arc<(Mutex<Work>, Condvar)>,
+    results: Receiver<(usize, FilmTile)>,
+}
+
+impl Coordinator {
+    pub(crate) fn listen(address: &str, job: Job, timeout: Duration) -> std::io::Result<Coordinator> {
+        let listener = TcpListener::bind(address)?;
+        eprintln!("Waiting for workers on {}", listener.local_addr()?);
+        let work = Arc::new((Mutex::new(Work { frame: 0, width: 0, height: 0, tiles: Vec::new(), queue: VecDeque::new() }), Condvar::new()));
+        let (sender, results) = mpsc::channel();
+        let job = Arc::new(job);
+        let shared = work.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (work, job, sender) = (shared.clone(), job.clone(), sender.clone());
+                thread::spawn(move || serve(stream, &job, &work, &sender, timeout));
+            }
+        });
+        Ok(Coordinator { work, results })
+    }
+
+    // Has the workers render `tiles` of `frame`, a width x height image, and
+    // hands them to `finish_tile` in order
+    pub(crate) fn render(&self, frame: usize, width: usize, height: usize, tiles: &[CropWindow], mut finish_tile: impl FnMut(FilmTile)) {
+        {
+            let (work, ready) = &*self.work;
+            let mut work = work.lock().unwrap();
+            *work = Work { frame, width, height, tiles: tiles.to_vec(), queue: (0..tiles.len()).collect() };
+            ready.notify_all();
+        }
+
+        // Tiles that came back before some earlier one, waiting for it
+        let mut waiting = BTreeMap::new();
+        let mut next = 0;
+        while next < tiles.len() {
+            let (index, tile) = self.results.recv().expect("Worker connections closed");
+            waiting.insert(index, tile);
+            while let Some(tile) = waiting.remove(&next) {
+                finish_tile(tile);
+                next += 1;
+            }
+        }
+    }
+}
+
+// Runs one worker's connection, until it goes away
+fn serve(stream: TcpStream, job: &Job, work: &(Mutex<Work>, Condvar), results: &Sender<(usize, FilmTile)>, timeout: Duration) {
+    let peer = stream.peer_addr().map_or(String::from("worker"), |address| address.to_string());
+    if stream.set_read_timeout(Some(timeout)).is_err() {
+        return;
+    }
+    let mut input = match stream.try_clone() {
+        Ok(input) => BufReader::new(input),
+        Err(_) => return,
+    };
+    let mut out = BufWriter::new(stream);
+    let threads = match read_u64(&mut input).and_then(|threads| job.write(&mut out).and(out.flush()).map(|_| threads)) {
+        Ok(threads) => threads as usize,
+        Err(e) => return eprintln!("Worker {} failed to start: {}", peer, e),
+    };
+    eprintln!("Worker {} connected with {} threads", peer, threads);
+
+    let (work, ready) = work;
+    loop {
+        let (frame, width, height, batch, windows) = {
+            let mut work = ready.wait_while(work.lock().unwrap(), |work| work.queue.is_empty()).unwrap();
+            let count = work.queue.len().min(2 * threads.max(1));
+            let batch: Vec<usize> = work.queue.drain(..count).collect();
+            let windows: Vec<CropWindow> = batch.iter().map(|&index| work.tiles[index]).collect();
+            (work.frame, work.width, work.height, batch, windows)
+        };
+
+        let mut returned = 0;
+        let result = send_task(&mut out, frame, &windows).and_then(|_| {
+            for (&index, window) in batch.iter().zip(&windows) {
+                let tile = FilmTile::read(&mut input)?;
+                if !tile.fits(width, height, window) {
+                    return Err(invalid("Sent back a different tile from the one asked for"));
+                }
+                if results.send((index, tile)).is_err() {
+                    return Err(invalid("Render finished"));
+                }
+                returned += 1;
+            }
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            let mut work = work.lock().unwrap();
+            if work.frame == frame {
+                for &index in batch[returned..].iter().rev() {
+                    work.queue.push_front(index);
+                }
+                ready.notify_all();
+            }
+            let reason = match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => format!("no tile for {}s", timeout.as_secs_f64()),
+                _ => e.to_string(),
+            };
+            eprintln!("Worker {} dropped ({}), {} tiles handed back", peer, reason, batch.len() - returned);
+            return;
+        }
+    }
+}
+
+fn send_task<W: Write>(out: &mut W, frame: usize, windows: &[CropWindow]) -> std::io::Result<()> {
+    write_u64(out, frame as u64)?;
+    write_u64(out, windows.len() as u64)?;
+    for window in windows {
+        for value in [window.x0, window.y0, window.x1, window.y1] {
+            write_u64(out, value as u64)?;
+        }
+    }
+    out.flush()
+}
+
+// Connects to the coordinator at `address` and renders the tiles it sends
+// until it's done. `render` gets the render's options, the frame and its
+// tiles, and sends each finished tile back in order.
+pub(crate) fn work(address: &str, threads: usize, mut render: impl FnMut(&Options, usize, &[CropWindow], &mut dyn FnMut(FilmTile))) -> std::io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    write_u64(&mut out, threads as u64)?;
+    out.flush()?;
+
+    let job = Job::read(&mut input)?;
+    let directory = std::env::temp_dir().join(format!("ray_tracer_worker_{}", std::process::id()));
+    let args = job.unpack(&directory)?;
+    let options = Options::parse(&args).map_err(|e| invalid(&e))?;
+    eprintln!("Connected to {}", address);
+
+    let result = (|| -> std::io::Result<()> {
+        loop {
+            // The coordinator hangs up once everything is rendered
+            let frame = match read_u64(&mut input) {
+                Ok(frame) => frame as usize,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let mut windows = Vec::new();
+            for _ in 0..read_u64(&mut input)? {
+                let mut values = [0; 4];
+                for value in values.iter_mut() {
+                    *value = read_u64(&mut input)? as usize;
+                }
+                let [x0, y0, x1, y1] = values;
+                windows.push(CropWindow { x0, y0, x1, y1 });
+            }
+
+            let mut sent = Ok(());
+            render(&options, frame, &windows, &mut |tile| {
+                if sent.is_ok() {
+                    sent = tile.write(&mut out).and_then(|_| out.flush());
+                }
+            });
+            sent?;
+        }
+    })();
+    std::fs::remove_dir_all(&directory).ok();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::BoxFilter;
+    use crate::tiles::{self, TileOrder};
+
+    const WIDTH: usize = 40;
+    const HEIGHT: usize = 30;
+
+    fn empty_tile(window: CropWindow) -> FilmTile {
+        FilmTile::new_with_values(WIDTH, HEIGHT, window, &BoxFilter::new_with_values(0.5))
+    }
+
+    // Connects like a worker and reads the job and the first task, returning
+    // the connection and the windows asked for
+    fn connect(address: &str) -> (BufReader<TcpStream>, BufWriter<TcpStream>, Vec<CropWindow>) {
+        let stream = TcpStream::connect(address).unwrap();
+        let mut input = BufReader::new(stream.try_clone().unwrap());
+        let mut out = BufWriter::new(stream);
+        write_u64(&mut out, 1).unwrap();
+        out.flush().unwrap();
+        Job::read(&mut input).unwrap();
+        read_u64(&mut input).unwrap();
+        let windows = (0..read_u64(&mut input).unwrap()).map(|_| {
+            let mut values = [0; 4];
+            for value in values.iter_mut() {
+                *value = read_u64(&mut input).unwrap() as usize;
+            }
+            let [x0, y0, x1, y1] = values;
+            CropWindow { x0, y0, x1, y1 }
+        }).collect();
+        (input, out, windows)
+    }
+
+    #[test]
+    fn every_tile_arrives_when_workers_fail() {
+        // A free port to listen on
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let options = Options::parse(&[String::from("--listen"), address.clone()]).unwrap();
+        let coordinator = Coordinator::listen(&address, Job::new_with_values(&options).unwrap(), Duration::from_millis(300)).unwrap();
+        let tiles = tiles::tiles(WIDTH, HEIGHT, 8, TileOrder::Scanline);
+
+        let (started, failed) = mpsc::channel();
+        let failing = |fail: fn(&mut BufWriter<TcpStream>, &[CropWindow])| {
+            let (address, started) = (address.clone(), started.clone());
+            thread::spawn(move || {
+                let (_input, mut out, windows) = connect(&address);
+                started.send(()).unwrap();
+                fail(&mut out, &windows);
+            })
+        };
+        // Killed after sending back one tile
+        failing(|out, windows| {
+            empty_tile(windows[0]).write(out).and_then(|_| out.flush()).unwrap();
+        });
+        // Hangs without sending anything back
+        failing(|_, _| thread::sleep(Duration::from_secs(5)));
+        // Sends back a tile it wasn't asked for
+        failing(|out, windows| {
+            let wrong = CropWindow { x0: windows[0].x0, y0: windows[0].y0, x1: windows[0].x0 + 1, y1: windows[0].y0 + 1 };
+            empty_tile(wrong).write(out).and_then(|_| out.flush()).unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        // Two good workers join once the others have taken their tiles
+        let workers = address.clone();
+        thread::spawn(move || {
+            for _ in 0..3 {
+                failed.recv().unwrap();
+            }
+            for _ in 0..2 {
+                let address = workers.clone();
+                thread::spawn(move || work(&address, 2, |_, _, windows, send| {
+                    for &window in windows {
+                        send(empty_tile(window));
+                    }
+                }));
+            }
+        });
+
+        let mut arrived = Vec::new();
+        coordinator.render(0, WIDTH, HEIGHT, &tiles, |tile| arrived.push(tile));
+        assert_eq!(arrived.len(), tiles.len());
+        for (index, (tile, window)) in arrived.iter().zip(&tiles).enumerate() {
+            assert!(tile.fits(WIDTH, HEIGHT, window), "tile {}", index);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// Piecewise-constant distributions used for importance sampling tabulated data
+// such as environment map luminance.
+
+pub(crate) struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    func_int: f64,
+}
+
+impl Distribution1D {
+    pub(crate) fn new_with_values(f: &[f64]) -> Distribution1D {
+        let n = f.len();
+        let func: Vec<f64> = f.iter().map(|v| v.abs()).collect();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as f64;
+        }
+
+        let func_int = cdf[n];
+        if func_int == 0.0 {
+            // Degenerate input, fall back to a uniform distribution
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= func_int;
+            }
+        }
+
+        Distribution1D { func, cdf, func_int }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub(crate) fn integral(&self) -> f64 {
+        self.func_int
+    }
+
+    // Returns a continuous sample in [0, 1), its pdf and the index of the bucket it landed in.
+    pub(crate) fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        let offset = self.find_interval(u);
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let pdf = if self.func_int > 0.0 { self.func[offset] / self.func_int } else { 1.0 };
+        ((offset as f64 + du) / self.count() as f64, pdf, offset)
+    }
+
+    // Pdf of the continuous sample x in [0, 1)
+    pub(crate) fn pdf(&self, x: f64) -> f64 {
+        if self.func_int == 0.0 {
+            return 1.0;
+        }
+        let offset = ((x * self.count() as f64) as usize).min(self.count() - 1);
+        self.func[offset] / self.func_int
+    }
+
+    fn find_interval(&self, u: f64) -> usize {
+        // Largest index with cdf[index] <= u
+        let idx = self.cdf.partition_point(|&c| c <= u);
+        idx.saturating_sub(1).min(self.count() - 1)
+    }
+}
+
+pub(crate) struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    // `f` is laid out row by row, `nu` values per row and `nv` rows.
+    pub(crate) fn new_with_values(f: &[f64], nu: usize, nv: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> = (0..nv)
+            .map(|v| Distribution1D::new_with_values(&f[v * nu..(v + 1) * nu]))
+            .collect();
+        let marginal_func: Vec<f64> = conditional.iter().map(|d| d.integral()).collect();
+        let marginal = Distribution1D::new_with_values(&marginal_func);
+
+        Distribution2D { conditional, marginal }
+    }
+
+    // Returns (u, v) in [0, 1)^2 and the pdf with respect to that unit square.
+    pub(crate) fn sample_continuous(&self, u0: f64, u1: f64) -> ((f64, f64), f64) {
+        let (d1, pdf1, v) = self.marginal.sample_continuous(u1);
+        let (d0, pdf0, _) = self.conditional[v].sample_continuous(u0);
+        ((d0, d1), pdf0 * pdf1)
+    }
+
+    pub(crate) fn pdf(&self, u: f64, v: f64) -> f64 {
+        let iv = ((v * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        self.conditional[iv].pdf(u) * self.marginal.pdf(v)
+    }
+}
@@ -0,0 +1,263 @@
+use crate::distribution::Distribution2D;
+use crate::image_io::HdrImage;
+use crate::onb::Onb;
+use crate::rtweekend::{self, clamp, degrees_to_radians, PI};
+use crate::vec3::{self, Color, Vec3};
+
+// Radiance arriving from infinitely far away, seen by rays that miss the scene.
+pub(crate) trait Environment: Send + Sync {
+    fn value(&self, direction: &Vec3) -> Color;
+
+    // Importance samples a direction towards the environment, returning it with
+    // its solid angle pdf. Environments that can't be sampled return None.
+    fn sample(&self) -> Option<(Vec3, f64)> {
+        None
+    }
+
+    fn pdf(&self, _direction: &Vec3) -> f64 {
+        0.0
+    }
+}
+
+pub(crate) fn luminance(c: &Color) -> f64 {
+    0.2126 * c.x() + 0.7152 * c.y() + 0.0722 * c.z()
+}
+
+// The original white to blue gradient
+pub(crate) struct GradientSky {
+    intensity: f64,
+}
+
+impl GradientSky {
+    pub(crate) fn new_with_values(intensity: f64) -> GradientSky {
+        GradientSky { intensity }
+    }
+}
+
+impl Environment for GradientSky {
+    fn value(&self, direction: &Vec3) -> Color {
+        let unit_direction = direction.unit_vector();
+        let t = 0.5 * (unit_direction.y() + 1.0);
+        (Color::new_with_values(1.0, 1.0, 1.0) * (1.0 - t) + Color::new_with_values(0.5, 0.7, 1.0) * t) * self.intensity
+    }
+
+    // The gradient is smooth enough that uniform sampling works well
+    fn sample(&self) -> Option<(Vec3, f64)> {
+        Some((vec3::random_unit_vector(), 1.0 / (4.0 * PI)))
+    }
+
+    fn pdf(&self, _direction: &Vec3) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+}
+
+// Equirectangular (latitude-longitude) environment map with +y up.
+pub(crate) struct EnvironmentMap {
+    image: HdrImage,
+    rotation: f64,
+    intensity: f64,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    // rotation is in degrees around the up axis
+    pub(crate) fn new_with_values(image: HdrImage, rotation: f64, intensity: f64) -> EnvironmentMap {
+        // Weight each texel's luminance by sin(theta) to account for the
+        // stretching of the map towards the poles.
+        let mut func = Vec::with_capacity(image.width * image.height);
+        for y in 0..image.height {
+            let sin_theta = (PI * (y as f64 + 0.5) / image.height as f64).sin();
+            for x in 0..image.width {
+                func.push(luminance(&image.get(x, y)).max(0.0) * sin_theta);
+            }
+        }
+        let distribution = Distribution2D::new_with_values(&func, image.width, image.height);
+
+        EnvironmentMap {
+            image,
+            rotation: degrees_to_radians(rotation),
+            intensity,
+            distribution,
+        }
+    }
+
+    fn direction_to_uv(&self, direction: &Vec3) -> (f64, f64) {
+        let d = direction.unit_vector();
+        let theta = clamp(d.y(), -1.0, 1.0).acos();
+        let phi = d.z().atan2(d.x());
+        let u = ((phi - self.rotation) / (2.0 * PI)).rem_euclid(1.0);
+        (u, theta / PI)
+    }
+
+    fn uv_to_direction(&self, u: f64, v: f64) -> Vec3 {
+        let theta = v * PI;
+        let phi = u * 2.0 * PI + self.rotation;
+        Vec3::new_with_values(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn value(&self, direction: &Vec3) -> Color {
+        let (u, v) = self.direction_to_uv(direction);
+        let x = ((u * self.image.width as f64) as usize).min(self.image.width - 1);
+        let y = ((v * self.image.height as f64) as usize).min(self.image.height - 1);
+        self.image.get(x, y) * self.intensity
+    }
+
+    fn sample(&self) -> Option<(Vec3, f64)> {
+        let (u0, u1) = rtweekend::random_double_2d();
+        let ((u, v), map_pdf) = self.distribution.sample_continuous(u0, u1);
+        let sin_theta = (v * PI).sin();
+        if map_pdf == 0.0 || sin_theta == 0.0 {
+            return None;
+        }
+        Some((self.uv_to_direction(u, v), map_pdf / (2.0 * PI * PI * sin_theta)))
+    }
+
+    fn pdf(&self, direction: &Vec3) -> f64 {
+        let (u, v) = self.direction_to_uv(direction);
+        let sin_theta = (v * PI).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+// Analytic daylight model from Preetham, Shirley and Smits, "A Practical
+// Analytic Model for Daylight" (1999), plus a solar disk.
+pub(crate) struct PreethamSky {
+    sun_direction: Vec3,
+    intensity: f64,
+    sun_radiance: Color,
+    cos_sun_radius: f64,
+    perez_luminance: [f64; 5],
+    perez_x: [f64; 5],
+    perez_y: [f64; 5],
+    zenith: [f64; 3],
+}
+
+const SUN_ANGULAR_RADIUS: f64 = 0.27;
+
+fn perez(c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    (1.0 + c[0] * (c[1] / cos_theta).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+}
+
+impl PreethamSky {
+    // sun_intensity is the irradiance of the sun disk relative to a sky of unit zenith luminance
+    pub(crate) fn new_with_values(sun_direction: Vec3, turbidity: f64, intensity: f64, sun_intensity: f64) -> PreethamSky {
+        let t = turbidity;
+        let sun_direction = sun_direction.unit_vector();
+        let theta_s = clamp(sun_direction.y(), 0.0, 1.0).acos();
+
+        let perez_luminance = [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ];
+        let perez_x = [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ];
+        let perez_y = [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ];
+
+        let (t2, s, s2, s3) = (t * t, theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x = t2 * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s)
+            + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s + 0.00394)
+            + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s + 0.25886);
+        let zenith_y = t2 * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s)
+            + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s + 0.00516)
+            + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s + 0.26688);
+
+        // Luminance is normalised so the zenith has unit luminance, the
+        // absolute scale comes from the intensity setting instead.
+        let zenith = [1.0 / perez(&perez_luminance, 1.0, theta_s), zenith_x / perez(&perez_x, 1.0, theta_s), zenith_y / perez(&perez_y, 1.0, theta_s)];
+
+        let cos_sun_radius = degrees_to_radians(SUN_ANGULAR_RADIUS).cos();
+        let solid_angle = 2.0 * PI * (1.0 - cos_sun_radius);
+        let sun_radiance = Color::new_with_values(1.0, 0.94, 0.86) * (sun_intensity * PI / solid_angle);
+
+        PreethamSky {
+            sun_direction,
+            intensity,
+            sun_radiance,
+            cos_sun_radius,
+            perez_luminance,
+            perez_x,
+            perez_y,
+            zenith,
+        }
+    }
+
+    fn sky(&self, d: &Vec3) -> Color {
+        // Below the horizon the sky is mirrored and darkened to give a simple ground
+        let (cos_theta, ground) = if d.y() > 0.0 { (d.y().max(0.01), 1.0) } else { ((-d.y()).max(0.01), 0.3) };
+        let up = Vec3::new_with_values(d.x(), cos_theta, d.z()).unit_vector();
+        let gamma = clamp(Vec3::dot(&up, &self.sun_direction), -1.0, 1.0).acos();
+
+        let lum = self.zenith[0] * perez(&self.perez_luminance, cos_theta, gamma);
+        let x = self.zenith[1] * perez(&self.perez_x, cos_theta, gamma);
+        let y = self.zenith[2] * perez(&self.perez_y, cos_theta, gamma);
+
+        let cx = x / y * lum;
+        let cz = (1.0 - x - y) / y * lum;
+        let rgb = Color::new_with_values(
+            3.2406 * cx - 1.5372 * lum - 0.4986 * cz,
+            -0.9689 * cx + 1.8758 * lum + 0.0415 * cz,
+            0.0557 * cx - 0.2040 * lum + 1.0570 * cz,
+        );
+        Color::new_with_values(rgb.x().max(0.0), rgb.y().max(0.0), rgb.z().max(0.0)) * (self.intensity * ground)
+    }
+
+    fn sun_visible(&self) -> bool {
+        self.sun_direction.y() > 0.0 && self.sun_radiance.x() > 0.0
+    }
+}
+
+impl Environment for PreethamSky {
+    fn value(&self, direction: &Vec3) -> Color {
+        let d = direction.unit_vector();
+        let mut c = self.sky(&d);
+        if self.sun_visible() && Vec3::dot(&d, &self.sun_direction) >= self.cos_sun_radius {
+            c = c + self.sun_radiance;
+        }
+        c
+    }
+
+    // Half the samples go towards the sun disk, the other half uniformly over the sphere.
+    fn sample(&self) -> Option<(Vec3, f64)> {
+        let direction = if self.sun_visible() && rtweekend::random_double() < 0.5 {
+            let (r1, r2) = rtweekend::random_double_2d();
+            let cos_theta = 1.0 - r1 * (1.0 - self.cos_sun_radius);
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * r2;
+            Onb::build_from_w(&self.sun_direction).local(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+        } else {
+            vec3::random_unit_vector()
+        };
+        Some((direction, self.pdf(&direction)))
+    }
+
+    fn pdf(&self, direction: &Vec3) -> f64 {
+        let uniform = 1.0 / (4.0 * PI);
+        if !self.sun_visible() {
+            return uniform;
+        }
+        let mut pdf = 0.5 * uniform;
+        if Vec3::dot(&direction.unit_vector(), &self.sun_direction) >= self.cos_sun_radius {
+            pdf += 0.5 / (2.0 * PI * (1.0 - self.cos_sun_radius));
+        }
+        pdf
+    }
+}
@@ -0,0 +1,415 @@
+use std::io::{Read, Write};
+use crate::checkpoint::{self, read_f64, read_u64, write_f64, write_u64};
+use crate::aov::{AovBuffers, AovSample};
+use crate::environment::luminance;
+use crate::filter::Filter;
+use crate::integrator::Splat;
+use crate::vec3::Color;
+
+// Running mean and variance of the luminance of a pixel's samples (Welford's method)
+#[derive(Clone, Copy)]
+pub(crate) struct PixelStatistics {
+    count: usize,
+    mean: f64,
+    m2: f64,
+}
+
+impl PixelStatistics {
+    pub(crate) fn new() -> PixelStatistics {
+        PixelStatistics { count: 0, mean: 0.0, m2: 0.0 }
+    }
+
+    pub(crate) fn add(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.count
+    }
+
+    pub(crate) fn variance(&self) -> f64 {
+        if self.count < 2 { 0.0 } else { self.m2 / (self.count - 1) as f64 }
+    }
+
+    // Standard error of the mean relative to the mean. Dark pixels are compared
+    // against a small floor instead, or they would never be considered done.
+    pub(crate) fn relative_error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        (self.variance() / self.count as f64).sqrt() / self.mean.max(0.01)
+    }
+
+    // Combines the statistics of samples taken separately, as if they were
+    // added one by one after these
+    fn merge(&mut self, other: &PixelStatistics) {
+        if self.count == 0 {
+            *self = *other;
+            return;
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.m2 += other.m2 + delta * (other.mean - self.mean) * other.count as f64;
+        self.count = count;
+    }
+}
+
+// A rectangle of pixels, rows counted from the top, ends excluded
+#[derive(Clone, Copy)]
+pub(crate) struct CropWindow {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl CropWindow {
+    pub(crate) fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+
+    // Grown by `margin` pixels on every side, within a width x height image
+    pub(crate) fn grown(&self, margin: usize, width: usize, height: usize) -> CropWindow {
+        CropWindow {
+            x0: self.x0.saturating_sub(margin),
+            y0: self.y0.saturating_sub(margin),
+            x1: (self.x1 + margin).min(width),
+            y1: (self.y1 + margin).min(height),
+        }
+    }
+
+    pub(crate) fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+
+    // The part of an image `width` wide inside the window
+    pub(crate) fn cut<T: Copy>(&self, image: &[T], width: usize) -> Vec<T> {
+        (self.y0..self.y1).flat_map(|y| image[y * width + self.x0..y * width + self.x1].iter().copied()).collect()
+    }
+}
+
+// Part of a width x height image rendered on its own, holding the samples
+// taken for the pixels of one tile. They are filtered into the pixels around
+// the tile as well, as far as the filter reaches, and the tile is then added
+// to the film. Tiles are always added in the same order, wherever they were
+// rendered, so the image comes out the same.
+pub(crate) struct FilmTile {
+    width: usize,
+    height: usize,
+    // The pixels the tile's samples can reach, rows counted from the top
+    window: CropWindow,
+    sums: Vec<Color>,
+    weights: Vec<f64>,
+    alphas: Vec<f64>,
+    statistics: Vec<PixelStatistics>,
+    samples: usize,
+    splats: Vec<Splat>,
+    aovs: Vec<AovSample>,
+}
+
+impl FilmTile {
+    pub(crate) fn new_with_values(width: usize, height: usize, tile: CropWindow, filter: &dyn Filter) -> FilmTile {
+        // A pixel further for samples that round onto the tile's edge
+        let window = tile.grown(filter.radius().ceil() as usize + 1, width, height);
+        let n = window.width() * window.height();
+        FilmTile {
+            width,
+            height,
+            window,
+            sums: vec![Color::new(); n],
+            weights: vec![0.0; n],
+            alphas: vec![0.0; n],
+            statistics: vec![PixelStatistics::new(); n],
+            samples: 0,
+            splats: Vec::new(),
+            aovs: Vec::new(),
+        }
+    }
+
+    pub(crate) fn width(&self) -> usize {
+        self.width
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.height
+    }
+
+    // Whether this is a tile of a width x height image holding the samples for `tile`
+    pub(crate) fn fits(&self, width: usize, height: usize, tile: &CropWindow) -> bool {
+        let w = &self.window;
+        self.width == width && self.height == height && w.x0 <= tile.x0 && w.y0 <= tile.y0 && w.x1 >= tile.x1 && w.y1 >= tile.y1
+    }
+
+    // Index in the tile of pixel (px, py), py counting up from the bottom
+    fn index(&self, px: usize, py: usize) -> usize {
+        (self.height - 1 - py - self.window.y0) * self.window.width() + px - self.window.x0
+    }
+
+    // Adds a sample taken at (u, v) in the image coordinates of `Camera::get_ray`,
+    // where pixel (i, j) covers [i, i + 1) x [j, j + 1) once scaled by (width - 1, height - 1).
+    pub(crate) fn add_sample(&mut self, filter: &dyn Filter, u: f64, v: f64, color: Color, alpha: f64) {
+        let x = u * (self.width - 1) as f64;
+        let y = v * (self.height - 1) as f64;
+        let radius = filter.radius();
+
+        let i = (x as usize).min(self.width - 1);
+        let j = (y as usize).min(self.height - 1);
+        let index = self.index(i, j);
+        self.statistics[index].add(luminance(&color));
+        self.samples += 1;
+
+        // Rows of the window counted up from the bottom, like y
+        let bottom = self.height - self.window.y1;
+        let top = self.height - 1 - self.window.y0;
+        let x0 = ((x - 0.5 - radius).ceil().max(0.0) as usize).max(self.window.x0);
+        let x1 = (x - 0.5 + radius).floor().min((self.window.x1 - 1) as f64);
+        let y0 = ((y - 0.5 - radius).ceil().max(0.0) as usize).max(bottom);
+        let y1 = (y - 0.5 + radius).floor().min(top as f64);
+        if x1 < 0.0 || y1 < 0.0 {
+            return;
+        }
+
+        for py in y0..=y1 as usize {
+            for px in x0..=x1 as usize {
+                let weight = filter.evaluate(px as f64 + 0.5 - x, py as f64 + 0.5 - y);
+                if weight != 0.0 {
+                    let index = self.index(px, py);
+                    self.sums[index] = self.sums[index] + color * weight;
+                    self.weights[index] += weight;
+                    self.alphas[index] += alpha * weight;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn add_splat(&mut self, splat: Splat) {
+        self.splats.push(splat);
+    }
+
+    pub(crate) fn add_aov_sample(&mut self, sample: AovSample) {
+        self.aovs.push(sample);
+    }
+
+    pub(crate) fn write<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        for value in [self.width, self.height, self.window.x0, self.window.y0, self.window.x1, self.window.y1, self.samples] {
+            write_u64(out, value as u64)?;
+        }
+        for index in 0..self.sums.len() {
+            let (c, s) = (self.sums[index], &self.statistics[index]);
+            for value in [c.x(), c.y(), c.z(), self.weights[index], self.alphas[index], s.mean, s.m2] {
+                write_f64(out, value)?;
+            }
+            write_u64(out, s.count as u64)?;
+        }
+        write_u64(out, self.splats.len() as u64)?;
+        for splat in &self.splats {
+            for value in [splat.u, splat.v, splat.color.x(), splat.color.y(), splat.color.z()] {
+                write_f64(out, value)?;
+            }
+        }
+        write_u64(out, self.aovs.len() as u64)?;
+        for sample in &self.aovs {
+            sample.write(out)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read<R: Read>(input: &mut R) -> std::io::Result<FilmTile> {
+        let mut values = [0; 7];
+        for value in values.iter_mut() {
+            *value = read_u64(input)? as usize;
+        }
+        let [width, height, x0, y0, x1, y1, samples] = values;
+        if x0 >= x1 || y0 >= y1 || x1 > width || y1 > height {
+            return Err(checkpoint::invalid("Invalid tile"));
+        }
+        let window = CropWindow { x0, y0, x1, y1 };
+        let n = window.width() * window.height();
+        let mut tile = FilmTile {
+            width,
+            height,
+            window,
+            sums: Vec::with_capacity(n),
+            weights: Vec::with_capacity(n),
+            alphas: Vec::with_capacity(n),
+            statistics: Vec::with_capacity(n),
+            samples,
+            splats: Vec::new(),
+            aovs: Vec::new(),
+        };
+        for _ in 0..n {
+            tile.sums.push(Color::new_with_values(read_f64(input)?, read_f64(input)?, read_f64(input)?));
+            tile.weights.push(read_f64(input)?);
+            tile.alphas.push(read_f64(input)?);
+            let (mean, m2) = (read_f64(input)?, read_f64(input)?);
+            tile.statistics.push(PixelStatistics { count: read_u64(input)? as usize, mean, m2 });
+        }
+        for _ in 0..read_u64(input)? {
+            let (u, v) = (read_f64(input)?, read_f64(input)?);
+            tile.splats.push(Splat { u, v, color: Color::new_with_values(read_f64(input)?, read_f64(input)?, read_f64(input)?) });
+        }
+        for _ in 0..read_u64(input)? {
+            tile.aovs.push(AovSample::read(input)?);
+        }
+        Ok(tile)
+    }
+}
+
+// The image being rendered. Camera samples are spread over the pixels around
+// them with the reconstruction filter, by the tiles they are taken in, and
+// normalised by the total weight, while light traced splats are kept apart and
+// added at the end. Rows are stored top to bottom.
+pub(crate) struct Film {
+    width: usize,
+    height: usize,
+    sums: Vec<Color>,
+    weights: Vec<f64>,
+    // Coverage, filtered like the colour
+    alphas: Vec<f64>,
+    splats: Vec<Color>,
+    statistics: Vec<PixelStatistics>,
+    // One per camera sample taken, in the crop window or not
+    light_paths: usize,
+}
+
+impl Film {
+    pub(crate) fn new_with_values(width: usize, height: usize) -> Film {
+        Film {
+            width,
+            height,
+            sums: vec![Color::new(); width * height],
+            weights: vec![0.0; width * height],
+            alphas: vec![0.0; width * height],
+            splats: vec![Color::new(); width * height],
+            statistics: vec![PixelStatistics::new(); width * height],
+            light_paths: 0,
+        }
+    }
+
+    fn add_splat(&mut self, splat: &Splat) {
+        let x = ((splat.u * (self.width - 1) as f64) as usize).min(self.width - 1);
+        let y = ((splat.v * (self.height - 1) as f64) as usize).min(self.height - 1);
+        let index = (self.height - 1 - y) * self.width + x;
+        self.splats[index] = self.splats[index] + splat.color;
+    }
+
+    // Adds a finished tile, and its AOV samples to `aovs`
+    pub(crate) fn add_tile(&mut self, tile: FilmTile, aovs: Option<&mut AovBuffers>) {
+        if tile.width != self.width || tile.height != self.height {
+            panic!("A {}x{} tile can't go in a {}x{} image", tile.width, tile.height, self.width, self.height);
+        }
+        let window = tile.window;
+        for y in window.y0..window.y1 {
+            for x in window.x0..window.x1 {
+                let (index, k) = (y * self.width + x, (y - window.y0) * window.width() + x - window.x0);
+                self.sums[index] = self.sums[index] + tile.sums[k];
+                self.weights[index] += tile.weights[k];
+                self.alphas[index] += tile.alphas[k];
+                self.statistics[index].merge(&tile.statistics[k]);
+            }
+        }
+        self.light_paths += tile.samples;
+        for splat in &tile.splats {
+            self.add_splat(splat);
+        }
+        if let Some(aovs) = aovs {
+            for sample in tile.aovs {
+                aovs.add_sample(sample);
+            }
+        }
+    }
+
+    // Statistics of the samples taken for pixel (i, j), j counting up from the bottom as in the render loop
+    pub(crate) fn statistics(&self, i: usize, j: usize) -> &PixelStatistics {
+        &self.statistics[(self.height - 1 - j) * self.width + i]
+    }
+
+    // Variance of each pixel's mean sample luminance, rows top to bottom
+    pub(crate) fn mean_variances(&self) -> Vec<f64> {
+        self.statistics.iter().map(|s| if s.count < 2 { 0.0 } else { s.variance() / s.count as f64 }).collect()
+    }
+
+    // Samples taken per pixel mapped from black through red and yellow to
+    // white at `max_samples`, rows top to bottom.
+    pub(crate) fn sample_map(&self, max_samples: usize) -> Vec<Color> {
+        self.statistics.iter().map(|s| {
+            let t = s.count() as f64 / max_samples as f64;
+            Color::new_with_values((3.0 * t).clamp(0.0, 1.0), (3.0 * t - 1.0).clamp(0.0, 1.0), (3.0 * t - 2.0).clamp(0.0, 1.0))
+        }).collect()
+    }
+
+    // Sets a pixel outright, for renderers that estimate whole pixels themselves
+    pub(crate) fn set_pixel(&mut self, index: usize, color: Color) {
+        self.sums[index] = color;
+        self.weights[index] = 1.0;
+        self.alphas[index] = 1.0;
+    }
+
+    // Filtered coverage, rows top to bottom
+    pub(crate) fn alphas(&self) -> Vec<f64> {
+        (0..self.alphas.len()).map(|index| {
+            if self.weights[index] != 0.0 { self.alphas[index] / self.weights[index] } else { 0.0 }
+        }).collect()
+    }
+
+    // Final pixel values, rows top to bottom
+    pub(crate) fn pixels(&self) -> Vec<Color> {
+        // Every camera sample traces one light path, spread over the whole image
+        // rather than a single pixel, see Camera::we. Each path's splats are an
+        // estimate for the whole image, so they're averaged over the paths that
+        // were traced. A crop render traces fewer of them, but that only makes
+        // the window noisier, not brighter.
+        let splat_scale = ((self.width - 1) * (self.height - 1)) as f64 / self.light_paths.max(1) as f64;
+
+        (0..self.sums.len()).map(|index| {
+            let filtered = if self.weights[index] != 0.0 { self.sums[index] / self.weights[index] } else { Color::new() };
+            filtered + self.splats[index] * splat_scale
+        }).collect()
+    }
+
+    // Raw accumulation buffers and per-pixel statistics, for checkpoints
+    pub(crate) fn write_state<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        write_u64(out, self.width as u64)?;
+        write_u64(out, self.height as u64)?;
+        write_u64(out, self.light_paths as u64)?;
+        for index in 0..self.sums.len() {
+            for c in [self.sums[index], self.splats[index]] {
+                write_f64(out, c.x())?;
+                write_f64(out, c.y())?;
+                write_f64(out, c.z())?;
+            }
+            write_f64(out, self.weights[index])?;
+            write_f64(out, self.alphas[index])?;
+            let s = &self.statistics[index];
+            write_u64(out, s.count as u64)?;
+            write_f64(out, s.mean)?;
+            write_f64(out, s.m2)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_state<R: Read>(&mut self, input: &mut R) -> std::io::Result<()> {
+        let width = read_u64(input)? as usize;
+        let height = read_u64(input)? as usize;
+        if width != self.width || height != self.height {
+            return Err(checkpoint::invalid(&format!("Checkpoint is {}x{}, not {}x{}", width, height, self.width, self.height)));
+        }
+        self.light_paths = read_u64(input)? as usize;
+        for index in 0..self.sums.len() {
+            self.sums[index] = Color::new_with_values(read_f64(input)?, read_f64(input)?, read_f64(input)?);
+            self.splats[index] = Color::new_with_values(read_f64(input)?, read_f64(input)?, read_f64(input)?);
+            self.weights[index] = read_f64(input)?;
+            self.alphas[index] = read_f64(input)?;
+            self.statistics[index] = PixelStatistics { count: read_u64(input)? as usize, mean: read_f64(input)?, m2: read_f64(input)? };
+        }
+        Ok(())
+    }
+}
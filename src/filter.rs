@@ -0,0 +1,148 @@
+use crate::rtweekend::PI;
+
+// Pixel reconstruction filter, weighting a sample by its offset (in pixels)
+// from the centre of a pixel. Weights may be negative for sharpening filters.
+pub(crate) trait Filter: Send + Sync {
+    // Offsets at or past this in either axis get no weight
+    fn radius(&self) -> f64;
+    fn evaluate(&self, x: f64, y: f64) -> f64;
+}
+
+// Each sample only counts towards the pixel it lies in when the radius is one
+// half, the average the book uses.
+pub(crate) struct BoxFilter {
+    radius: f64,
+}
+
+impl BoxFilter {
+    pub(crate) fn new_with_values(radius: f64) -> BoxFilter {
+        BoxFilter { radius }
+    }
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        if x.abs() <= self.radius && y.abs() <= self.radius { 1.0 } else { 0.0 }
+    }
+}
+
+pub(crate) struct TentFilter {
+    radius: f64,
+}
+
+impl TentFilter {
+    pub(crate) fn new_with_values(radius: f64) -> TentFilter {
+        TentFilter { radius }
+    }
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        (self.radius - x.abs()).max(0.0) * (self.radius - y.abs()).max(0.0)
+    }
+}
+
+// Gaussian with a standard deviation of half a pixel, shifted down so it
+// reaches zero at the radius.
+pub(crate) struct GaussianFilter {
+    radius: f64,
+    alpha: f64,
+}
+
+impl GaussianFilter {
+    pub(crate) fn new_with_values(radius: f64) -> GaussianFilter {
+        GaussianFilter { radius, alpha: 2.0 }
+    }
+
+    fn gaussian(&self, d: f64) -> f64 {
+        ((-self.alpha * d * d).exp() - (-self.alpha * self.radius * self.radius).exp()).max(0.0)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.gaussian(x) * self.gaussian(y)
+    }
+}
+
+// Mitchell-Netravali cubic with B = C = 1/3, stretched over the radius
+pub(crate) struct MitchellFilter {
+    radius: f64,
+    b: f64,
+    c: f64,
+}
+
+impl MitchellFilter {
+    pub(crate) fn new_with_values(radius: f64) -> MitchellFilter {
+        MitchellFilter { radius, b: 1.0 / 3.0, c: 1.0 / 3.0 }
+    }
+
+    fn mitchell(&self, x: f64) -> f64 {
+        let x = (2.0 * x / self.radius).abs();
+        let (b, c) = (self.b, self.c);
+        if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+        } else {
+            ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+        }
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.mitchell(x) * self.mitchell(y)
+    }
+}
+
+// Sinc windowed by a wider sinc, with as many lobes as the radius is wide
+pub(crate) struct LanczosFilter {
+    radius: f64,
+}
+
+impl LanczosFilter {
+    pub(crate) fn new_with_values(radius: f64) -> LanczosFilter {
+        LanczosFilter { radius }
+    }
+
+    fn lanczos(&self, x: f64) -> f64 {
+        if x.abs() >= self.radius {
+            return 0.0;
+        }
+        sinc(x) * sinc(x / self.radius)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.lanczos(x) * self.lanczos(y)
+    }
+}
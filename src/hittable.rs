@@ -1,17 +1,20 @@
-use std::rc::Rc;
+use std::sync::Arc;
+use crate::aabb::Aabb;
 use crate::material::Material;
-use crate::vec3;
-use crate::ray;
 use crate::ray::Ray;
 use crate::vec3::{Point3, Vec3};
 
 #[derive(Clone)]
+#[allow(non_camel_case_types)]
 pub struct hit_record {
     pub p: Point3,
     pub normal: Vec3,
     pub t: f64,
+    pub u: f64,
+    pub v: f64,
+    pub object_id: usize,
     pub front_face: bool,
-    pub(crate) mat_ptr: Option<Rc<dyn Material>>
+    pub(crate) mat_ptr: Option<Arc<dyn Material>>
 }
 
 
@@ -25,13 +28,21 @@ impl hit_record {
             p: Point3::new(),
             normal: Vec3::new(),
             t: 0.0,
+            u: 0.0,
+            v: 0.0,
+            object_id: 0,
             front_face: false,
             mat_ptr: None,
         }
     }
 }
 
-pub(crate) trait Hittable {
+pub(crate) trait Hittable: Send + Sync {
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut hit_record) -> bool;
+    fn bounding_box(&self, output_box: &mut Aabb) -> bool;
+
+    fn is_specular(&self) -> bool {
+        false
+    }
 }
 
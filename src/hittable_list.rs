@@ -1,10 +1,10 @@
-use std::rc::Rc;
+use std::sync::Arc;
+use crate::aabb::Aabb;
 use crate::hittable;
-use crate::vec3;
 use crate::ray;
 
 pub struct HittableList {
-    objects: Vec<Rc<dyn hittable::Hittable>>,
+    objects: Vec<Arc<dyn hittable::Hittable>>,
 }
 
 impl HittableList {
@@ -12,24 +12,39 @@ impl HittableList {
         HittableList { objects: Vec::new() }
     }
 
-    pub fn clear(&mut self) {
-        self.objects.clear();
+    pub(crate) fn add(&mut self, object: Arc<dyn hittable::Hittable>) {
+        self.objects.push(object);
     }
 
-    pub(crate) fn add(&mut self, object: Rc<dyn hittable::Hittable>) {
-        self.objects.push(object);
+    pub(crate) fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    // Position of the nth object, counting from the end when negative
+    pub(crate) fn index(&self, n: isize) -> Option<usize> {
+        let i = if n < 0 { self.objects.len() as isize + n } else { n };
+        if i >= 0 && i < self.objects.len() as isize { Some(i as usize) } else { None }
+    }
+
+    pub(crate) fn set(&mut self, index: usize, object: Arc<dyn hittable::Hittable>) {
+        self.objects[index] = object;
+    }
+
+    pub(crate) fn objects(&self) -> &[Arc<dyn hittable::Hittable>] {
+        &self.objects
     }
 }
 
 impl hittable::Hittable for HittableList {
-    fn hit(&self, r: &ray::Ray, t_min: f64, t_max: f64, mut rec: &mut hittable::hit_record) -> bool {
+    fn hit(&self, r: &ray::Ray, t_min: f64, t_max: f64, rec: &mut hittable::hit_record) -> bool {
         let mut temp_rec = hittable::hit_record::new();
         let mut hit_anything = false;
         let mut closest_so_far = t_max;
 
-        for object in self.objects.iter() {
+        for (index, object) in self.objects.iter().enumerate() {
             if object.hit(r, t_min, closest_so_far, &mut temp_rec) {
                 hit_anything = true;
+                temp_rec.object_id = index;
                 let temp_rec_clone = temp_rec.clone();
                 closest_so_far = temp_rec_clone.t;
                 *rec = temp_rec_clone;
@@ -38,4 +53,23 @@ impl hittable::Hittable for HittableList {
 
         hit_anything
     }
+
+    fn bounding_box(&self, output_box: &mut Aabb) -> bool {
+        if self.objects.is_empty() {
+            return false;
+        }
+
+        let mut temp_box = Aabb::new();
+        let mut first_box = true;
+
+        for object in self.objects.iter() {
+            if !object.bounding_box(&mut temp_box) {
+                return false;
+            }
+            *output_box = if first_box { temp_box } else { Aabb::surrounding_box(output_box, &temp_box) };
+            first_box = false;
+        }
+
+        true
+    }
 }
@@ -0,0 +1,730 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+use crate::color;
+use crate::display::DisplayTransform;
+use crate::vec3::Color;
+
+// Linear floating point image. Rows are stored top to bottom.
+pub(crate) struct HdrImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
+}
+
+impl HdrImage {
+    pub(crate) fn get(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+// How the values in an image file are encoded. Auto takes 8-bit files to be
+// sRGB, like most of them are, and floating point ones to be linear.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum InputSpace {
+    Auto,
+    Srgb,
+    Linear,
+}
+
+// Loads an environment image, picking the decoder from the file extension,
+// and linearises it if its values are sRGB encoded.
+pub(crate) fn read_image(path: &str, space: InputSpace) -> std::io::Result<HdrImage> {
+    let ext = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    let mut image = match ext.as_str() {
+        "hdr" | "pic" => read_hdr(path),
+        "pfm" => read_pfm(path),
+        "ppm" => read_ppm(path),
+        "exr" => read_exr(path),
+        _ => Err(Error::new(ErrorKind::Unsupported, format!("Unknown image format: {}", path))),
+    }?;
+    if space == InputSpace::Srgb || (space == InputSpace::Auto && ext == "ppm") {
+        let linear = |c: Color| Color::new_with_values(color::srgb_to_linear(c.x()), color::srgb_to_linear(c.y()), color::srgb_to_linear(c.z()));
+        image.pixels = image.pixels.into_iter().map(linear).collect();
+    }
+    Ok(image)
+}
+
+// Radiance RGBE (.hdr) reader supporting both flat and run length encoded scanlines.
+pub(crate) fn read_hdr(path: &str) -> std::io::Result<HdrImage> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(invalid("Missing Radiance header"));
+    }
+
+    // Header lines end with an empty line, followed by the resolution string
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("Unexpected end of header"));
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            break;
+        }
+        if let Some(format) = trimmed.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(invalid("Only 32-bit_rle_rgbe Radiance files are supported"));
+            }
+        }
+    }
+
+    line.clear();
+    reader.read_line(&mut line)?;
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 4 || parts[0] != "-Y" || parts[2] != "+X" {
+        return Err(invalid("Only -Y H +X W oriented Radiance files are supported"));
+    }
+    let height: usize = parts[1].parse().map_err(|_| invalid("Bad image height"))?;
+    let width: usize = parts[3].parse().map_err(|_| invalid("Bad image width"))?;
+
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut pos = 0;
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        pos = read_hdr_scanline(&data, pos, &mut scanline)?;
+        pixels.extend(scanline.iter().map(rgbe_to_color));
+    }
+
+    Ok(HdrImage { width, height, pixels })
+}
+
+fn read_hdr_scanline(data: &[u8], mut pos: usize, scanline: &mut [[u8; 4]]) -> std::io::Result<usize> {
+    let width = scanline.len();
+    let byte = |p: usize| data.get(p).copied().ok_or_else(|| invalid("Unexpected end of pixel data"));
+
+    let is_rle = (8..0x8000).contains(&width)
+        && byte(pos)? == 2
+        && byte(pos + 1)? == 2
+        && byte(pos + 2)? & 0x80 == 0;
+    if !is_rle {
+        for px in scanline.iter_mut() {
+            for (c, v) in px.iter_mut().enumerate() {
+                *v = byte(pos + c)?;
+            }
+            pos += 4;
+        }
+        return Ok(pos);
+    }
+
+    let encoded_width = ((byte(pos + 2)? as usize) << 8) | byte(pos + 3)? as usize;
+    if encoded_width != width {
+        return Err(invalid("Scanline width mismatch"));
+    }
+    pos += 4;
+
+    // Each of the four channels is stored separately as runs
+    for c in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = byte(pos)? as usize;
+            pos += 1;
+            if count > 128 {
+                let run = count - 128;
+                let value = byte(pos)?;
+                pos += 1;
+                if x + run > width {
+                    return Err(invalid("Run length overflows scanline"));
+                }
+                for px in scanline[x..x + run].iter_mut() {
+                    px[c] = value;
+                }
+                x += run;
+            } else {
+                if count == 0 || x + count > width {
+                    return Err(invalid("Bad scanline literal count"));
+                }
+                for px in scanline[x..x + count].iter_mut() {
+                    px[c] = byte(pos)?;
+                    pos += 1;
+                }
+                x += count;
+            }
+        }
+    }
+
+    Ok(pos)
+}
+
+fn rgbe_to_color(rgbe: &[u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::new();
+    }
+    let f = 2f64.powi(rgbe[3] as i32 - (128 + 8));
+    Color::new_with_values(rgbe[0] as f64 * f, rgbe[1] as f64 * f, rgbe[2] as f64 * f)
+}
+
+// 8-bit PPM reader for plain ("P3") and binary ("P6") files, returning the
+// stored values scaled to [0, 1].
+pub(crate) fn read_ppm(path: &str) -> std::io::Result<HdrImage> {
+    let data = std::fs::read(path)?;
+
+    // Whitespace separated tokens, skipping comments
+    let mut pos = 0;
+    let mut next_token = |data: &[u8]| -> Option<String> {
+        loop {
+            while pos < data.len() && data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if pos < data.len() && data[pos] == b'#' {
+                while pos < data.len() && data[pos] != b'\n' {
+                    pos += 1;
+                }
+                continue;
+            }
+            break;
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos { None } else { Some(String::from_utf8_lossy(&data[start..pos]).to_string()) }
+    };
+
+    let magic = next_token(&data).ok_or_else(|| invalid("Missing PPM magic"))?;
+    let mut number = |what: &str| -> std::io::Result<usize> {
+        next_token(&data).and_then(|t| t.parse().ok()).ok_or_else(|| invalid(what))
+    };
+    let width = number("Bad image width")?;
+    let height = number("Bad image height")?;
+    let max = number("Bad maximum value")?;
+    if max == 0 || max > 255 {
+        return Err(invalid("Only 8-bit PPM files are supported"));
+    }
+
+    let values: Vec<usize> = match magic.as_str() {
+        "P3" => (0..width * height * 3).map(|_| number("Truncated PPM data")).collect::<std::io::Result<_>>()?,
+        "P6" => {
+            // A single whitespace byte separates the header from the samples
+            let start = pos + 1;
+            let end = start + width * height * 3;
+            if end > data.len() {
+                return Err(invalid("Truncated PPM data"));
+            }
+            data[start..end].iter().map(|&b| b as usize).collect()
+        }
+        _ => return Err(invalid("Missing PPM magic")),
+    };
+
+    let scale = |v: usize| v as f64 / max as f64;
+    let pixels = values.chunks(3).map(|rgb| Color::new_with_values(scale(rgb[0]), scale(rgb[1]), scale(rgb[2]))).collect();
+    Ok(HdrImage { width, height, pixels })
+}
+
+// Portable float map (.pfm) reader, colour ("PF") and greyscale ("Pf").
+pub(crate) fn read_pfm(path: &str) -> std::io::Result<HdrImage> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    // The header is three whitespace separated tokens followed by the scale
+    let mut tokens = Vec::new();
+    let mut token = Vec::new();
+    let mut byte = [0u8; 1];
+    while tokens.len() < 4 {
+        reader.read_exact(&mut byte)?;
+        if byte[0].is_ascii_whitespace() {
+            if !token.is_empty() {
+                tokens.push(String::from_utf8_lossy(&token).to_string());
+                token.clear();
+            }
+        } else {
+            token.push(byte[0]);
+        }
+    }
+
+    let channels = match tokens[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid("Missing PFM magic")),
+    };
+    let width: usize = tokens[1].parse().map_err(|_| invalid("Bad image width"))?;
+    let height: usize = tokens[2].parse().map_err(|_| invalid("Bad image height"))?;
+    let scale: f64 = tokens[3].parse().map_err(|_| invalid("Bad scale"))?;
+    let little_endian = scale < 0.0;
+
+    let mut data = vec![0u8; width * height * channels * 4];
+    reader.read_exact(&mut data)?;
+
+    let value = |i: usize| -> f64 {
+        let b = [data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]];
+        if little_endian { f32::from_le_bytes(b) as f64 } else { f32::from_be_bytes(b) as f64 }
+    };
+
+    // PFM rows are stored bottom to top
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let i = (y * width + x) * channels;
+            pixels.push(if channels == 3 {
+                Color::new_with_values(value(i), value(i + 1), value(i + 2))
+            } else {
+                Color::new_with_values(value(i), value(i), value(i))
+            });
+        }
+    }
+
+    Ok(HdrImage { width, height, pixels })
+}
+
+// OpenEXR reader for uncompressed single part scanline files, the kind
+// `write_exr` writes, with half or float channels. The image is taken from the
+// R, G and B channels, or from Y when it is greyscale.
+pub(crate) fn read_exr(path: &str) -> std::io::Result<HdrImage> {
+    let data = std::fs::read(path)?;
+    let bytes = |pos: usize, n: usize| pos.checked_add(n).and_then(|end| data.get(pos..end)).ok_or_else(|| invalid("Unexpected end of OpenEXR file"));
+    let int = |pos: usize| -> std::io::Result<i32> { Ok(i32::from_le_bytes(bytes(pos, 4)?.try_into().unwrap())) };
+    // A null terminated string and the position after it
+    let string = |pos: usize| -> std::io::Result<(String, usize)> {
+        let end = data[pos.min(data.len())..].iter().position(|&b| b == 0).ok_or_else(|| invalid("Unterminated OpenEXR string"))?;
+        Ok((String::from_utf8_lossy(&data[pos..pos + end]).to_string(), pos + end + 1))
+    };
+
+    if bytes(0, 4)? != [0x76, 0x2f, 0x31, 0x01] {
+        return Err(invalid("Missing OpenEXR magic"));
+    }
+    // Tiled, deep and multi-part files
+    if int(4)? & 0x1a00 != 0 {
+        return Err(invalid("Only scanline OpenEXR files are supported"));
+    }
+
+    // Name, type and sampling of each channel, in the order they are stored
+    let mut channels: Vec<(String, i32)> = Vec::new();
+    let mut window = None;
+    let mut pos = 8;
+    loop {
+        let (name, next) = string(pos)?;
+        if name.is_empty() {
+            pos = next;
+            break;
+        }
+        let (kind, next) = string(next)?;
+        let size = int(next)? as usize;
+        let value = next + 4;
+        bytes(value, size)?;
+        match (name.as_str(), kind.as_str()) {
+            ("channels", "chlist") => {
+                let mut p = value;
+                loop {
+                    let (channel, next) = string(p)?;
+                    if channel.is_empty() {
+                        break;
+                    }
+                    if int(next + 8)? != 1 || int(next + 12)? != 1 {
+                        return Err(invalid("Subsampled OpenEXR channels are not supported"));
+                    }
+                    channels.push((channel, int(next)?));
+                    p = next + 16;
+                }
+            }
+            ("compression", _) if size != 1 => return Err(invalid("Bad OpenEXR compression attribute")),
+            ("compression", _) if data[value] != 0 => return Err(invalid("Only uncompressed OpenEXR files are supported")),
+            ("dataWindow", "box2i") => window = Some([int(value)?, int(value + 4)?, int(value + 8)?, int(value + 12)?]),
+            _ => {}
+        }
+        pos = value + size;
+    }
+
+    let [x0, y0, x1, y1] = window.ok_or_else(|| invalid("Missing OpenEXR data window"))?;
+    if x1 < x0 || y1 < y0 {
+        return Err(invalid("Empty OpenEXR data window"));
+    }
+    let width = (x1 as i64 - x0 as i64 + 1) as usize;
+    let height = (y1 as i64 - y0 as i64 + 1) as usize;
+    let find = |name: &str| channels.iter().position(|(n, _)| n == name);
+    let rgb = match (find("R"), find("G"), find("B"), find("Y")) {
+        (Some(r), Some(g), Some(b), _) => [r, g, b],
+        (_, _, _, Some(y)) => [y, y, y],
+        _ => return Err(invalid("OpenEXR file has no R, G and B or Y channels")),
+    };
+    // Bytes per value of each channel: UINT, HALF or FLOAT
+    let sizes: Vec<usize> = channels.iter().map(|&(_, kind)| if kind == 1 { 2 } else { 4 }).collect();
+    let line_size: usize = sizes.iter().sum::<usize>() * width;
+    // Every scanline needs its offset, its header and its pixels
+    if height.saturating_mul(line_size + 16) > data.len() {
+        return Err(invalid("Unexpected end of OpenEXR file"));
+    }
+
+    // The offset table points at each scanline's chunk
+    let mut values = vec![[0.0; 3]; width * height];
+    for line in 0..height {
+        let chunk = u64::from_le_bytes(bytes(pos + line * 8, 8)?.try_into().unwrap()) as usize;
+        if chunk > data.len() {
+            return Err(invalid("Bad OpenEXR scanline offset"));
+        }
+        let y = int(chunk)? as i64 - y0 as i64;
+        if y < 0 || y as usize >= height || int(chunk + 4)? as usize != line_size {
+            return Err(invalid("Bad OpenEXR scanline"));
+        }
+        let row = y as usize * width;
+        let mut start = chunk + 8;
+        for (c, &(_, kind)) in channels.iter().enumerate() {
+            let samples = bytes(start, sizes[c] * width)?;
+            for k in (0..3).filter(|&k| rgb[k] == c) {
+                for x in 0..width {
+                    let v = &samples[x * sizes[c]..(x + 1) * sizes[c]];
+                    values[row + x][k] = match kind {
+                        0 => u32::from_le_bytes(v.try_into().unwrap()) as f64,
+                        1 => half_to_f64(u16::from_le_bytes(v.try_into().unwrap())),
+                        _ => f32::from_le_bytes(v.try_into().unwrap()) as f64,
+                    };
+                }
+            }
+            start += sizes[c] * width;
+        }
+    }
+
+    let pixels = values.iter().map(|v| Color::new_with_values(v[0], v[1], v[2])).collect();
+    Ok(HdrImage { width, height, pixels })
+}
+
+fn half_to_f64(h: u16) -> f64 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((h >> 10) & 0x1f) as i32;
+    let mantissa = (h & 0x3ff) as f64;
+    sign * match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exponent - 15),
+    }
+}
+
+// Plain PPM in the book's format, encoded for display. Rows are top to bottom.
+pub(crate) fn write_ppm<W: Write>(out: &mut W, width: usize, height: usize, pixels: &[Color], display: &DisplayTransform) -> std::io::Result<()> {
+    writeln!(out, "P3\n{} {}\n255\n", width, height)?;
+    for (index, pixel) in display.prepare(pixels, width, height).iter().enumerate() {
+        color::write_color(out, display.to_bytes(*pixel, index % width, index / width));
+    }
+    Ok(())
+}
+
+// Writes the image in the format picked by the file extension: PNG or EXR,
+// which keep `alpha` when there is one, PFM, or else PPM. `pixels` are
+// premultiplied by alpha. EXR and PFM hold the linear values, the others are
+// encoded with `display`. The file is written under a temporary name and
+// renamed into place, so anything watching it never sees a half written image.
+pub(crate) fn write_image_file(path: &str, width: usize, height: usize, pixels: &[Color], alpha: Option<&[f64]>, display: &DisplayTransform) -> std::io::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    {
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => write_png(&mut out, width, height, pixels, alpha, display)?,
+            "exr" => {
+                let mut channels = Vec::new();
+                for (c, name) in ["R", "G", "B"].iter().enumerate() {
+                    channels.push(Channel { name: String::from(*name), data: pixels.iter().map(|p| p[c] as f32).collect() });
+                }
+                if let Some(alpha) = alpha {
+                    channels.push(Channel { name: String::from("A"), data: alpha.iter().map(|&a| a as f32).collect() });
+                }
+                write_exr(&mut out, width, height, &channels)?;
+            }
+            "pfm" => {
+                let channels: Vec<Channel> = (0..3).map(|c| Channel { name: String::new(), data: pixels.iter().map(|p| p[c] as f32).collect() }).collect();
+                write_pfm_data(&mut out, "PF", width, height, &channels)?;
+            }
+            _ => write_ppm(&mut out, width, height, pixels, display)?,
+        }
+        out.flush()?;
+    }
+    std::fs::rename(&temporary, path)
+}
+
+// 8-bit PNG, encoded for display like the PPM output. PNG stores straight
+// rather than premultiplied alpha, so the colour is divided by it first.
+pub(crate) fn write_png<W: Write>(out: &mut W, width: usize, height: usize, pixels: &[Color], alpha: Option<&[f64]>, display: &DisplayTransform) -> std::io::Result<()> {
+    let channels = if alpha.is_some() { 4 } else { 3 };
+    let pixels = display.prepare(pixels, width, height);
+    let mut raw = Vec::with_capacity(height * (width * channels + 1));
+    for y in 0..height {
+        // No filtering on any row
+        raw.push(0);
+        for x in 0..width {
+            let index = y * width + x;
+            let a = alpha.map_or(1.0, |alpha| alpha[index].clamp(0.0, 1.0));
+            let straight = if a > 0.0 { pixels[index] / a } else { Color::new() };
+            raw.extend_from_slice(&display.to_bytes(straight, x, y));
+            if alpha.is_some() {
+                raw.push((a * 255.0).round() as u8);
+            }
+        }
+    }
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, truecolour with or without alpha, no interlacing
+    ihdr.extend_from_slice(&[8, if alpha.is_some() { 6 } else { 2 }, 0, 0, 0]);
+
+    out.write_all(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a])?;
+    write_png_chunk(out, b"IHDR", &ihdr)?;
+    write_png_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+    write_png_chunk(out, b"IEND", &[])
+}
+
+fn write_png_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(&[kind.as_slice(), data].concat());
+    out.write_all(&crc.to_be_bytes())
+}
+
+// A zlib stream of uncompressed deflate blocks, which every PNG reader accepts
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(65535).collect() };
+    for (i, block) in blocks.iter().enumerate() {
+        stream.push(if i + 1 == blocks.len() { 1 } else { 0 });
+        stream.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        stream.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    stream.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// One channel of a floating point image, rows top to bottom
+pub(crate) struct Channel {
+    pub name: String,
+    pub data: Vec<f32>,
+}
+
+// Portable float map writer for one (greyscale) or three (colour) channels
+pub(crate) fn write_pfm(path: &str, width: usize, height: usize, channels: &[Channel]) -> std::io::Result<()> {
+    let magic = match channels.len() {
+        1 => "Pf",
+        3 => "PF",
+        n => return Err(Error::new(ErrorKind::InvalidInput, format!("PFM can't hold {} channels", n))),
+    };
+    let mut out = BufWriter::new(File::create(path)?);
+    write_pfm_data(&mut out, magic, width, height, channels)?;
+    out.flush()
+}
+
+fn write_pfm_data<W: Write>(out: &mut W, magic: &str, width: usize, height: usize, channels: &[Channel]) -> std::io::Result<()> {
+    write!(out, "{}\n{} {}\n-1.0\n", magic, width, height)?;
+
+    // PFM rows are stored bottom to top
+    for y in (0..height).rev() {
+        for x in 0..width {
+            for channel in channels {
+                out.write_all(&channel.data[y * width + x].to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Uncompressed single part scanline OpenEXR with 32-bit float channels. Names
+// such as "albedo.R" put a channel in a layer.
+pub(crate) fn write_exr<W: Write>(out: &mut W, width: usize, height: usize, channels: &[Channel]) -> std::io::Result<()> {
+    let mut sorted: Vec<&Channel> = channels.iter().collect();
+    sorted.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut header = Vec::new();
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+
+    let mut chlist = Vec::new();
+    for channel in &sorted {
+        chlist.extend_from_slice(channel.name.as_bytes());
+        chlist.push(0);
+        // FLOAT pixels, not perceptually linear, sampled every pixel
+        chlist.extend_from_slice(&2i32.to_le_bytes());
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+
+    let mut window = Vec::new();
+    for v in [0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+
+    attribute("channels", "chlist", &chlist);
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0.0f32.to_le_bytes(), 0.0f32.to_le_bytes()].concat());
+    attribute("screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+
+    let long_names = if sorted.iter().any(|c| c.name.len() > 31) { 0x400u32 } else { 0 };
+    out.write_all(&[0x76, 0x2f, 0x31, 0x01])?;
+    out.write_all(&(2 | long_names).to_le_bytes())?;
+    out.write_all(&header)?;
+
+    // Offset table with one chunk per scanline, then the scanlines
+    let line_size = width * sorted.len() * 4;
+    let first_chunk = 8 + header.len() + height * 8;
+    for y in 0..height {
+        out.write_all(&((first_chunk + y * (line_size + 8)) as u64).to_le_bytes())?;
+    }
+    for y in 0..height {
+        out.write_all(&(y as i32).to_le_bytes())?;
+        out.write_all(&(line_size as i32).to_le_bytes())?;
+        for channel in &sorted {
+            for value in &channel.data[y * width..(y + 1) * width] {
+                out.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("ray_tracer_test_{}_{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    // A 3x2 image with a different colour in every pixel, rows top to bottom
+    fn test_channels() -> Vec<Channel> {
+        ["R", "G", "B"].iter().enumerate().map(|(c, name)| {
+            Channel { name: String::from(*name), data: (0..6).map(|i| i as f32 * 0.5 + c as f32 * 0.125).collect() }
+        }).collect()
+    }
+
+    fn assert_matches(image: &HdrImage, channels: &[Channel]) {
+        assert_eq!((image.width, image.height), (3, 2));
+        for (index, pixel) in image.pixels.iter().enumerate() {
+            for (c, channel) in channels.iter().enumerate() {
+                assert_eq!(pixel[c], channel.data[index] as f64, "pixel {} channel {}", index, c);
+            }
+        }
+    }
+
+    fn exr_bytes(channels: &[Channel]) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_exr(&mut data, 3, 2, channels).unwrap();
+        data
+    }
+
+    fn read_exr_bytes(name: &str, data: &[u8]) -> std::io::Result<HdrImage> {
+        let path = temp_path(name);
+        std::fs::write(&path, data).unwrap();
+        let image = read_exr(&path);
+        std::fs::remove_file(&path).ok();
+        image
+    }
+
+    #[test]
+    fn exr_round_trips() {
+        let channels = test_channels();
+        let image = read_exr_bytes("round_trip.exr", &exr_bytes(&channels)).unwrap();
+        assert_matches(&image, &channels);
+    }
+
+    #[test]
+    fn exr_rejects_truncated_files() {
+        let data = exr_bytes(&test_channels());
+        for length in [0, 3, 8, 40, data.len() / 2, data.len() - 1] {
+            assert!(read_exr_bytes(&format!("truncated_{}.exr", length), &data[..length]).is_err(), "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn exr_rejects_an_empty_compression_attribute() {
+        let mut data = exr_bytes(&test_channels());
+        let name = b"compression\0compression\0";
+        let at = data.windows(name.len()).position(|w| w == name).unwrap() + name.len();
+        // Size 0 and no value byte
+        data.splice(at..at + 5, 0i32.to_le_bytes());
+        assert!(read_exr_bytes("empty_compression.exr", &data).is_err());
+    }
+
+    #[test]
+    fn half_floats_decode() {
+        assert_eq!(half_to_f64(0x3c00), 1.0);
+        assert_eq!(half_to_f64(0xc000), -2.0);
+        assert_eq!(half_to_f64(0x3555), 0.333251953125);
+        assert_eq!(half_to_f64(0x0001), 2f64.powi(-24));
+        assert_eq!(half_to_f64(0x7c00), f64::INFINITY);
+        assert!(half_to_f64(0x7e00).is_nan());
+    }
+
+    #[test]
+    fn pfm_round_trips() {
+        let channels = test_channels();
+        let path = temp_path("round_trip.pfm");
+        write_pfm(&path, 3, 2, &channels).unwrap();
+        let image = read_pfm(&path);
+        std::fs::remove_file(&path).ok();
+        assert_matches(&image.unwrap(), &channels);
+    }
+
+    #[test]
+    fn pfm_rejects_bad_input() {
+        let path = temp_path("bad.pfm");
+        for data in [&b"P6\n3 2\n-1.0\n"[..], b"PF\n3 x\n-1.0\n", b"PF\n3 2\n-1.0\n\0\0\0\0"] {
+            std::fs::write(&path, data).unwrap();
+            assert!(read_pfm(&path).is_err(), "{:?}", String::from_utf8_lossy(data));
+        }
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn input_space_picks_the_encoding() {
+        let ppm = temp_path("space.ppm");
+        std::fs::write(&ppm, b"P3\n1 1\n255\n255 128 0\n").unwrap();
+        let pfm = temp_path("space.pfm");
+        let channels: Vec<Channel> = ["R", "G", "B"].iter().map(|name| Channel { name: String::from(*name), data: vec![0.5] }).collect();
+        write_pfm(&pfm, 1, 1, &channels).unwrap();
+
+        let read = |path: &str, space| read_image(path, space).unwrap().pixels[0];
+        let half = color::srgb_to_linear(128.0 / 255.0);
+        let cases = [
+            (&ppm, InputSpace::Auto, half),
+            (&ppm, InputSpace::Srgb, half),
+            (&ppm, InputSpace::Linear, 128.0 / 255.0),
+            (&pfm, InputSpace::Auto, 0.5),
+            (&pfm, InputSpace::Linear, 0.5),
+            (&pfm, InputSpace::Srgb, color::srgb_to_linear(0.5)),
+        ];
+        let results: Vec<f64> = cases.iter().map(|(path, space, _)| read(path, *space).y()).collect();
+        std::fs::remove_file(&ppm).ok();
+        std::fs::remove_file(&pfm).ok();
+        for ((path, _, expected), result) in cases.iter().zip(results) {
+            assert!((result - expected).abs() < 1e-6, "{}: {} instead of {}", path, result, expected);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::aov::LightComponents;
+use crate::hittable::{hit_record, Hittable};
+use crate::material::Material;
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::rtweekend::{self, clamp};
+use crate::scene::Scene;
+use crate::vec3::{self, Color};
+
+// Light that lands on an arbitrary part of the image rather than the pixel
+// being rendered, in the [0, 1]^2 image coordinates of `Camera::get_ray`.
+pub(crate) struct Splat {
+    pub u: f64,
+    pub v: f64,
+    pub color: Color,
+}
+
+// Computes the radiance arriving along a camera ray. Integrators that trace
+// paths from the lights towards the camera push those contributions to `splats`.
+pub(crate) trait Integrator: Send + Sync {
+    // Called once before rendering, for integrators that precompute data such as photon maps
+    fn preprocess(&mut self, _scene: &Scene) {}
+
+    fn li(&self, r: &Ray, scene: &Scene, splats: &mut Vec<Splat>) -> Color;
+
+    // The same radiance split up for the lighting AOVs, by integrators that can
+    fn li_components(&self, _r: &Ray, _scene: &Scene, _splats: &mut Vec<Splat>) -> Option<LightComponents> {
+        None
+    }
+}
+
+// Samples the environment directly from a hit point. The result is weighted
+// against the material's own sampling with the power heuristic, so the BSDF
+// sample that escapes must be weighted with `environment_bsdf_weight`.
+fn sample_environment(r: &Ray, rec: &hit_record, mat: &dyn Material, scene: &Scene) -> Color {
+    let env = scene.environment.as_ref();
+    if let Some((direction, light_pdf)) = env.sample() {
+        let f = mat.eval(r, rec, &direction);
+        let shadow_ray = Ray::new_with_values(rec.p, direction);
+        if !f.near_zero() && !scene.world.hit(&shadow_ray, 0.001, rtweekend::INFINITY, &mut hit_record::new()) {
+            let weight = rtweekend::power_heuristic(light_pdf, mat.scattering_pdf(r, rec, &direction));
+            return f * env.value(&direction) * (weight / light_pdf);
+        }
+    }
+    Color::new()
+}
+
+// MIS weight for an escaping ray that was sampled from a material with `bsdf_pdf`,
+// or None after a specular bounce or for camera rays.
+fn environment_bsdf_weight(r: &Ray, scene: &Scene, bsdf_pdf: Option<f64>) -> f64 {
+    match bsdf_pdf {
+        Some(pdf) => rtweekend::power_heuristic(pdf, scene.environment.pdf(&r.direction())),
+        None => 1.0,
+    }
+}
+
+// The unidirectional path tracer from the book, with explicit environment
+// sampling. Paths are extended iteratively while tracking their throughput,
+// and after `rr_depth` bounces Russian roulette terminates dim paths without
+// biasing the result.
+pub(crate) struct PathIntegrator {
+    max_depth: usize,
+    rr_depth: usize,
+}
+
+impl PathIntegrator {
+    pub(crate) fn new_with_values(max_depth: usize, rr_depth: usize) -> PathIntegrator {
+        PathIntegrator { max_depth, rr_depth }
+    }
+}
+
+impl Integrator for PathIntegrator {
+    fn li(&self, r: &Ray, scene: &Scene, splats: &mut Vec<Splat>) -> Color {
+        self.li_components(r, scene, splats).unwrap().total()
+    }
+
+    fn li_components(&self, r: &Ray, scene: &Scene, _splats: &mut Vec<Splat>) -> Option<LightComponents> {
+        let mut color = LightComponents::new();
+        let mut throughput = Color::new_with_values(1.0, 1.0, 1.0);
+        let mut ray = Ray::new_with_values(r.origin(), r.direction());
+        let mut bsdf_pdf = None;
+        let mut first_specular = false;
+        let mut seen_by_camera = true;
+
+        for depth in 0..self.max_depth {
+            let mut rec = hit_record::new();
+            if !scene.world.hit(&ray, 0.001, rtweekend::INFINITY, &mut rec) {
+                let weight = environment_bsdf_weight(&ray, scene, bsdf_pdf);
+                color.add(depth, first_specular, throughput * scene.background(&ray.direction(), seen_by_camera) * weight);
+                break;
+            }
+
+            let mat = rec.mat_ptr.as_ref().unwrap();
+            if depth == 0 {
+                first_specular = mat.is_specular();
+            }
+            color.add(depth, first_specular, throughput * mat.emitted(&rec));
+            color.add(depth + 1, first_specular, throughput * sample_environment(&ray, &rec, mat.as_ref(), scene));
+
+            let mut scattered = Ray::new();
+            let mut attenuation = Color::new_with_values(0.0, 0.0, 0.0);
+            if !mat.scatter(&ray, &rec, &mut attenuation, &mut scattered) {
+                break;
+            }
+            let pdf = mat.scattering_pdf(&ray, &rec, &scattered.direction());
+            bsdf_pdf = if pdf > 0.0 { Some(pdf) } else { None };
+            seen_by_camera = seen_by_camera && Scene::transmitted(&rec, &scattered);
+            throughput = throughput * attenuation;
+            ray = scattered;
+
+            if depth + 1 >= self.rr_depth {
+                let survive = throughput.max_component().min(0.95);
+                if survive <= 0.0 || rtweekend::random_double() >= survive {
+                    break;
+                }
+                throughput = throughput / survive;
+            }
+        }
+
+        Some(color)
+    }
+}
+
+// Only light arriving directly from the environment and emissive objects is gathered. Specular
+// surfaces are still followed so mirrors and glass show what they reflect.
+pub(crate) struct DirectLightingIntegrator {
+    max_depth: usize,
+}
+
+impl DirectLightingIntegrator {
+    pub(crate) fn new_with_values(max_depth: usize) -> DirectLightingIntegrator {
+        DirectLightingIntegrator { max_depth }
+    }
+
+    fn ray_color(&self, r: &Ray, scene: &Scene, depth: usize, seen_by_camera: bool) -> Color {
+        let mut rec = hit_record::new();
+
+        if depth == 0 {
+            return Color::new_with_values(0.0, 0.0, 0.0);
+        }
+        if !scene.world.hit(r, 0.001, rtweekend::INFINITY, &mut rec) {
+            return scene.background(&r.direction(), seen_by_camera);
+        }
+
+        let mat = rec.mat_ptr.as_ref().unwrap();
+        let mut color = mat.emitted(&rec) + sample_environment(r, &rec, mat.as_ref(), scene);
+
+        let mut scattered = Ray::new();
+        let mut attenuation = Color::new_with_values(0.0, 0.0, 0.0);
+        if mat.scatter(r, &rec, &mut attenuation, &mut scattered) {
+            let pdf = mat.scattering_pdf(r, &rec, &scattered.direction());
+            let mut light_rec = hit_record::new();
+            if pdf == 0.0 {
+                let transmitted = Scene::transmitted(&rec, &scattered);
+                color = color + attenuation * self.ray_color(&scattered, scene, depth - 1, seen_by_camera && transmitted);
+            } else if scene.world.hit(&scattered, 0.001, rtweekend::INFINITY, &mut light_rec) {
+                // Emissive objects are only found by sampling the material
+                color = color + attenuation * light_rec.mat_ptr.as_ref().unwrap().emitted(&light_rec);
+            } else {
+                let weight = environment_bsdf_weight(&scattered, scene, Some(pdf));
+                color = color + attenuation * scene.environment.value(&scattered.direction()) * weight;
+            }
+        }
+        color
+    }
+}
+
+impl Integrator for DirectLightingIntegrator {
+    fn li(&self, r: &Ray, scene: &Scene, _splats: &mut Vec<Splat>) -> Color {
+        self.ray_color(r, scene, self.max_depth, true)
+    }
+}
+
+// Fraction of the cosine weighted hemisphere that is unoccluded within `max_distance`.
+pub(crate) struct AmbientOcclusionIntegrator {
+    samples: usize,
+    max_distance: f64,
+}
+
+impl AmbientOcclusionIntegrator {
+    pub(crate) fn new_with_values(samples: usize, max_distance: f64) -> AmbientOcclusionIntegrator {
+        AmbientOcclusionIntegrator { samples, max_distance }
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn li(&self, r: &Ray, scene: &Scene, _splats: &mut Vec<Splat>) -> Color {
+        let mut rec = hit_record::new();
+        if !scene.world.hit(r, 0.001, rtweekend::INFINITY, &mut rec) {
+            return if scene.transparent_background { Color::new() } else { Color::new_with_values(1.0, 1.0, 1.0) };
+        }
+
+        let uvw = Onb::build_from_w(&rec.normal);
+        let mut unoccluded = 0;
+        for _ in 0..self.samples {
+            let d = vec3::random_cosine_direction();
+            let ao_ray = Ray::new_with_values(rec.p, uvw.local(d.x(), d.y(), d.z()));
+            if !scene.world.hit(&ao_ray, 0.001, self.max_distance, &mut hit_record::new()) {
+                unoccluded += 1;
+            }
+        }
+
+        let visibility = unoccluded as f64 / self.samples as f64;
+        Color::new_with_values(visibility, visibility, visibility)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum DebugMode {
+    Normals,
+    Depth,
+    Uv,
+    MaterialId,
+}
+
+// Visualises first-hit geometry data, handy for checking imported scenes.
+pub(crate) struct DebugIntegrator {
+    mode: DebugMode,
+    depth_range: f64,
+}
+
+impl DebugIntegrator {
+    pub(crate) fn new_with_values(mode: DebugMode, depth_range: f64) -> DebugIntegrator {
+        DebugIntegrator { mode, depth_range }
+    }
+}
+
+// Spreads consecutive ids over distinct, fairly saturated colours
+pub(crate) fn id_to_color(id: usize) -> Color {
+    let mut h = (id as u32).wrapping_add(1).wrapping_mul(0x9E37_79B9);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x85EB_CA6B);
+    h ^= h >> 13;
+    Color::new_with_values(
+        0.2 + 0.8 * ((h & 0xFF) as f64 / 255.0),
+        0.2 + 0.8 * (((h >> 8) & 0xFF) as f64 / 255.0),
+        0.2 + 0.8 * (((h >> 16) & 0xFF) as f64 / 255.0),
+    )
+}
+
+impl Integrator for DebugIntegrator {
+    fn li(&self, r: &Ray, scene: &Scene, _splats: &mut Vec<Splat>) -> Color {
+        let mut rec = hit_record::new();
+        if !scene.world.hit(r, 0.001, rtweekend::INFINITY, &mut rec) {
+            return Color::new();
+        }
+
+        match self.mode {
+            DebugMode::Normals => {
+                let outward = if rec.front_face { rec.normal } else { -rec.normal };
+                (outward + Color::new_with_values(1.0, 1.0, 1.0)) * 0.5
+            }
+            DebugMode::Depth => {
+                let distance = rec.t * r.direction().length();
+                let shade = 1.0 - clamp(distance / self.depth_range, 0.0, 1.0);
+                Color::new_with_values(shade, shade, shade)
+            }
+            DebugMode::Uv => Color::new_with_values(rec.u, rec.v, 0.0),
+            DebugMode::MaterialId => id_to_color(rec.mat_ptr.as_ref().unwrap().id()),
+        }
+    }
+}
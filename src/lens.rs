@@ -0,0 +1,390 @@
+use crate::camera::{self, Aperture, Camera};
+use crate::checkpoint::invalid;
+use crate::ray::Ray;
+use crate::rtweekend;
+use crate::vec3::{Point3, Vec3};
+
+// Lens prescriptions are in millimetres and scenes in metres
+const MM: f64 = 0.001;
+
+// Film positions the exit pupil is bounded for, from the centre outwards
+const PUPIL_BANDS: usize = 64;
+const PUPIL_SAMPLES: usize = 16384;
+
+// One surface of a lens, listed from the front of the lens to the back. A
+// radius of zero is the aperture stop.
+#[derive(Clone, Copy)]
+struct Interface {
+    radius: f64,
+    // Distance to the next surface, or to the film for the last one
+    thickness: f64,
+    // Refractive index behind the surface, 0 or 1 for air
+    ior: f64,
+    aperture_radius: f64,
+}
+
+// A multi-element lens read from a file with a line per surface holding its
+// curvature radius, thickness, index of refraction and aperture diameter in
+// millimetres, as in pbrt's lens files. Lines starting with # are comments.
+pub(crate) struct Lens {
+    interfaces: Vec<Interface>,
+    stop: Aperture,
+}
+
+impl Lens {
+    pub(crate) fn read(path: &str) -> std::io::Result<Lens> {
+        let mut interfaces = Vec::new();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values: Vec<f64> = line.split_whitespace().map(|v| v.parse().map_err(|_| invalid("Invalid number in lens file"))).collect::<std::io::Result<_>>()?;
+            if values.len() != 4 {
+                return Err(invalid("Lens file lines need a radius, thickness, index and aperture"));
+            }
+            interfaces.push(Interface { radius: values[0] * MM, thickness: values[1] * MM, ior: values[2], aperture_radius: values[3] * MM / 2.0 });
+        }
+        if interfaces.is_empty() {
+            return Err(invalid("Empty lens file"));
+        }
+        Ok(Lens { interfaces, stop: Aperture::new_with_values(0, 0.0) })
+    }
+
+    // Shape of the aperture stop, and its diameter in millimetres if it isn't
+    // opened fully
+    pub(crate) fn set_stop(&mut self, shape: Aperture, diameter: Option<f64>) {
+        self.stop = shape;
+        if let Some(diameter) = diameter {
+            for interface in self.interfaces.iter_mut().filter(|i| i.radius == 0.0) {
+                interface.aperture_radius = interface.aperture_radius.min(diameter * MM / 2.0);
+            }
+        }
+    }
+
+    fn rear_z(&self) -> f64 {
+        self.interfaces.last().unwrap().thickness
+    }
+
+    fn front_z(&self) -> f64 {
+        self.interfaces.iter().map(|i| i.thickness).sum()
+    }
+
+    fn rear_radius(&self) -> f64 {
+        self.interfaces.last().unwrap().aperture_radius
+    }
+
+    fn passes(&self, interface: &Interface, p: &Point3) -> bool {
+        let r = interface.aperture_radius;
+        if interface.radius == 0.0 {
+            self.stop.contains(p.x() / r, p.y() / r)
+        } else {
+            p.x() * p.x() + p.y() * p.y() <= r * r
+        }
+    }
+
+    // Follows a ray from the film out through the lens. Rays are in camera
+    // space with the film at z = 0 and the scene towards -z, as in pbrt.
+    // Returns None for rays that hit the lens barrel or the stop.
+    fn trace_from_film(&self, r: &Ray) -> Option<Ray> {
+        let mut z = 0.0;
+        // Lens space has the scene towards -z
+        let mut origin = flip(r.origin());
+        let mut direction = flip(r.direction());
+        for (i, interface) in self.interfaces.iter().enumerate().rev() {
+            z -= interface.thickness;
+            let (t, n) = if interface.radius == 0.0 {
+                if direction.z() >= 0.0 {
+                    return None;
+                }
+                ((z - origin.z()) / direction.z(), Vec3::new())
+            } else {
+                intersect_element(interface.radius, z + interface.radius, &origin, &direction)?
+            };
+            let hit = origin + direction * t;
+            if !self.passes(interface, &hit) {
+                return None;
+            }
+            origin = hit;
+            if interface.radius != 0.0 {
+                let eta_i = interface.ior;
+                let eta_t = if i > 0 && self.interfaces[i - 1].ior != 0.0 { self.interfaces[i - 1].ior } else { 1.0 };
+                direction = refract(&-direction.unit_vector(), &n, air(eta_i) / eta_t)?;
+            }
+        }
+        Some(Ray::new_with_values(flip(origin), flip(direction)))
+    }
+
+    // Follows a ray from the scene in through the lens towards the film
+    fn trace_from_scene(&self, r: &Ray) -> Option<Ray> {
+        let mut z = -self.front_z();
+        let mut origin = flip(r.origin());
+        let mut direction = flip(r.direction());
+        for (i, interface) in self.interfaces.iter().enumerate() {
+            let (t, n) = if interface.radius == 0.0 {
+                ((z - origin.z()) / direction.z(), Vec3::new())
+            } else {
+                intersect_element(interface.radius, z + interface.radius, &origin, &direction)?
+            };
+            let hit = origin + direction * t;
+            if !self.passes(interface, &hit) {
+                return None;
+            }
+            origin = hit;
+            if interface.radius != 0.0 {
+                let eta_i = if i == 0 { 1.0 } else { air(self.interfaces[i - 1].ior) };
+                let eta_t = air(interface.ior);
+                direction = refract(&-direction.unit_vector(), &n, eta_i / eta_t)?;
+            }
+            z += interface.thickness;
+        }
+        Some(Ray::new_with_values(flip(origin), flip(direction)))
+    }
+
+    // Principal plane and focal point along z of the thick lens that acts like
+    // this one, for light entering from the scene and from the film
+    fn thick_lens(&self) -> Option<([f64; 2], [f64; 2])> {
+        let x = 0.001 * self.rear_radius();
+        let from_scene = Ray::new_with_values(Point3::new_with_values(x, 0.0, self.front_z() + 1.0), Vec3::new_with_values(0.0, 0.0, -1.0));
+        let (pz0, fz0) = cardinal_points(&from_scene, &self.trace_from_scene(&from_scene)?);
+        let from_film = Ray::new_with_values(Point3::new_with_values(x, 0.0, self.rear_z() - 1.0), Vec3::new_with_values(0.0, 0.0, 1.0));
+        let (pz1, fz1) = cardinal_points(&from_film, &self.trace_from_film(&from_film)?);
+        Some(([pz0, pz1], [fz0, fz1]))
+    }
+
+    // Moves the film so objects `distance` away are sharp
+    fn focus(&mut self, distance: f64) -> std::io::Result<()> {
+        let (pz, fz) = self.thick_lens().ok_or_else(|| invalid("The lens doesn't pass light along its axis"))?;
+        let f = fz[0] - pz[0];
+        let z = -distance;
+        let c = (pz[1] - z - pz[0]) * (pz[1] - z - 4.0 * f - pz[0]);
+        if c <= 0.0 {
+            return Err(invalid(&format!("Can't focus the lens at {}", distance)));
+        }
+        let delta = 0.5 * (pz[1] - z + pz[0] - c.sqrt());
+        self.interfaces.last_mut().unwrap().thickness += delta;
+        Ok(())
+    }
+
+    pub(crate) fn focal_length(&self) -> f64 {
+        self.thick_lens().map_or(0.0, |(pz, fz)| fz[0] - pz[0])
+    }
+
+    // Bounds on the rear element of the points that film points between x0
+    // and x1 on the x axis see the scene through
+    fn exit_pupil_bounds(&self, x0: f64, x1: f64) -> [f64; 4] {
+        let extent = 1.5 * self.rear_radius();
+        let mut bounds = [f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY];
+        for i in 0..PUPIL_SAMPLES {
+            let film = Point3::new_with_values(x0 + (x1 - x0) * (i as f64 + 0.5) / PUPIL_SAMPLES as f64, 0.0, 0.0);
+            let (sx, sy) = (radical_inverse(2, i), radical_inverse(3, i));
+            let rear = Point3::new_with_values(extent * (2.0 * sx - 1.0), extent * (2.0 * sy - 1.0), self.rear_z());
+            let inside = rear.x() >= bounds[0] && rear.y() >= bounds[1] && rear.x() <= bounds[2] && rear.y() <= bounds[3];
+            if inside || self.trace_from_film(&Ray::new_with_values(film, rear - film)).is_some() {
+                bounds = [bounds[0].min(rear.x()), bounds[1].min(rear.y()), bounds[2].max(rear.x()), bounds[3].max(rear.y())];
+            }
+        }
+        if bounds[0] > bounds[2] {
+            return [-extent, -extent, extent, extent];
+        }
+        // Grown by the sample spacing so no part of the pupil is missed
+        let pad = 2.0 * extent * 2.0 * std::f64::consts::SQRT_2 / (PUPIL_SAMPLES as f64).sqrt();
+        [bounds[0] - pad, bounds[1] - pad, bounds[2] + pad, bounds[3] + pad]
+    }
+}
+
+// Reflects z, switching between camera space and lens space
+fn flip(v: Vec3) -> Vec3 {
+    Vec3::new_with_values(v.x(), v.y(), -v.z())
+}
+
+fn air(ior: f64) -> f64 {
+    if ior == 0.0 { 1.0 } else { ior }
+}
+
+// Hit on the spherical surface centred `z_center` along the axis, with the
+// normal facing back along the ray
+fn intersect_element(radius: f64, z_center: f64, origin: &Point3, direction: &Vec3) -> Option<(f64, Vec3)> {
+    let o = *origin - Vec3::new_with_values(0.0, 0.0, z_center);
+    let a = direction.length_squared();
+    let b = 2.0 * Vec3::dot(direction, &o);
+    let c = o.length_squared() - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let (t0, t1) = ((-b - root) / (2.0 * a), (-b + root) / (2.0 * a));
+    // The element is the part of the sphere facing the way the ray comes from
+    let closer = (direction.z() > 0.0) ^ (radius < 0.0);
+    let t = if closer { t0.min(t1) } else { t0.max(t1) };
+    if t < 0.0 {
+        return None;
+    }
+    let n = (o + *direction * t).unit_vector();
+    Some((t, if Vec3::dot(&n, direction) > 0.0 { -n } else { n }))
+}
+
+// Direction after refraction of the ray arriving from `wi`, pointing away
+// from the surface, or None for total internal reflection
+fn refract(wi: &Vec3, n: &Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = Vec3::dot(n, wi);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-*wi * eta + *n * (eta * cos_i - cos_t))
+}
+
+// Where a ray parallel to the axis crosses it after the lens, the focal
+// point, and where it appears to bend, the principal plane
+fn cardinal_points(r_in: &Ray, r_out: &Ray) -> (f64, f64) {
+    let tf = -r_out.origin().x() / r_out.direction().x();
+    let tp = (r_in.origin().x() - r_out.origin().x()) / r_out.direction().x();
+    (-r_out.at(tp).z(), -r_out.at(tf).z())
+}
+
+fn radical_inverse(base: usize, mut i: usize) -> f64 {
+    let mut result = 0.0;
+    let mut scale = 1.0 / base as f64;
+    while i > 0 {
+        result += (i % base) as f64 * scale;
+        i /= base;
+        scale /= base as f64;
+    }
+    result
+}
+
+// A camera that traces rays from the film through every element of a real
+// lens (pbrt's RealisticCamera, after Kolb et al. 1995), so defocus, bokeh,
+// distortion and vignetting come from the lens design itself. Rays are
+// sampled over the exit pupil seen from each film point and weighted by the
+// cos⁴ falloff, scaled so the middle of the image is as bright as the scene.
+pub(crate) struct RealisticCamera {
+    origin: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    lens: Lens,
+    film_width: f64,
+    film_height: f64,
+    pupil_bounds: Vec<[f64; 4]>,
+    // Area of the exit pupil seen from the centre of the film
+    centre_pupil_area: f64,
+}
+
+impl RealisticCamera {
+    // The film diagonal is in millimetres
+    pub(crate) fn new_with_values(lookfrom: Point3, lookat: Point3, vup: Vec3, mut lens: Lens, film_diagonal: f64, aspect_ratio: f64, focus_dist: f64) -> std::io::Result<RealisticCamera> {
+        let (u, v, w) = camera::basis(lookfrom, lookat, vup);
+        lens.focus(focus_dist)?;
+
+        let diagonal = film_diagonal * MM;
+        let film_height = diagonal / (1.0 + aspect_ratio * aspect_ratio).sqrt();
+        let film_width = film_height * aspect_ratio;
+        let pupil_bounds: Vec<[f64; 4]> = (0..PUPIL_BANDS).map(|i| {
+            let r = diagonal / 2.0;
+            lens.exit_pupil_bounds(r * i as f64 / PUPIL_BANDS as f64, r * (i + 1) as f64 / PUPIL_BANDS as f64)
+        }).collect();
+
+        // How much of the first band's bounds the centre of the film sees through
+        let b = pupil_bounds[0];
+        let film = Point3::new();
+        let grid = 128;
+        let mut passed = 0;
+        for i in 0..grid * grid {
+            let x = b[0] + (b[2] - b[0]) * ((i % grid) as f64 + 0.5) / grid as f64;
+            let y = b[1] + (b[3] - b[1]) * ((i / grid) as f64 + 0.5) / grid as f64;
+            let rear = Point3::new_with_values(x, y, lens.rear_z());
+            if lens.trace_from_film(&Ray::new_with_values(film, rear - film)).is_some() {
+                passed += 1;
+            }
+        }
+        let centre_pupil_area = (b[2] - b[0]) * (b[3] - b[1]) * passed as f64 / (grid * grid) as f64;
+        if centre_pupil_area == 0.0 {
+            return Err(invalid("No light reaches the film through the lens"));
+        }
+
+        Ok(RealisticCamera { origin: lookfrom, u, v, w, lens, film_width, film_height, pupil_bounds, centre_pupil_area })
+    }
+}
+
+impl Camera for RealisticCamera {
+    fn get_ray(&self, u: f64, v: f64) -> Option<Ray> {
+        self.get_weighted_ray(u, v).map(|(r, _)| r)
+    }
+
+    fn get_weighted_ray(&self, u: f64, v: f64) -> Option<(Ray, f64)> {
+        // The lens turns the image upside down and back to front
+        let film = Point3::new_with_values(-(u - 0.5) * self.film_width, -(v - 0.5) * self.film_height, 0.0);
+
+        let r_film = (film.x() * film.x() + film.y() * film.y()).sqrt();
+        let half_diagonal = 0.5 * (self.film_width * self.film_width + self.film_height * self.film_height).sqrt();
+        let band = ((r_film / half_diagonal * PUPIL_BANDS as f64) as usize).min(PUPIL_BANDS - 1);
+        let b = self.pupil_bounds[band];
+        let (s, t) = rtweekend::random_double_2d();
+        let (px, py) = (b[0] + (b[2] - b[0]) * s, b[1] + (b[3] - b[1]) * t);
+        // The bounds were found along +x, so turn them towards the film point
+        let (sin, cos) = if r_film > 0.0 { (film.y() / r_film, film.x() / r_film) } else { (0.0, 1.0) };
+        let rear = Point3::new_with_values(cos * px - sin * py, sin * px + cos * py, self.lens.rear_z());
+
+        let out = self.lens.trace_from_film(&Ray::new_with_values(film, rear - film))?;
+        let cos_theta = (rear - film).unit_vector().z();
+        let weight = cos_theta.powi(4) * (b[2] - b[0]) * (b[3] - b[1]) / self.centre_pupil_area;
+
+        // Camera space has x right, y up and the scene along +z
+        let to_world = |p: Vec3| self.u * p.x() + self.v * p.y() - self.w * p.z();
+        Some((Ray::new_with_values(self.origin + to_world(out.origin()), to_world(out.direction())), weight))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOUBLE_GAUSS: &str = "lenses/dgauss.50mm.dat";
+
+    fn read_text(name: &str, text: &str) -> std::io::Result<Lens> {
+        let path = std::env::temp_dir().join(format!("ray_tracer_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let lens = Lens::read(&path.to_string_lossy());
+        std::fs::remove_file(&path).ok();
+        lens
+    }
+
+    fn camera(lens: Lens, focus_dist: f64) -> std::io::Result<RealisticCamera> {
+        let up = Vec3::new_with_values(0.0, 1.0, 0.0);
+        RealisticCamera::new_with_values(Point3::new(), Point3::new_with_values(0.0, 0.0, -1.0), up, lens, 35.0, 1.5, focus_dist)
+    }
+
+    #[test]
+    fn reads_lens_files() {
+        let lens = Lens::read(DOUBLE_GAUSS).unwrap();
+        assert_eq!(lens.interfaces.len(), 11);
+        assert_eq!(lens.interfaces.iter().filter(|i| i.radius == 0.0).count(), 1);
+        assert!((lens.focal_length() / MM - 50.0).abs() < 1.0, "{}mm", lens.focal_length() / MM);
+
+        let lens = read_text("single.dat", "# A single surface\n\n  50 40 1.5 20\n").unwrap();
+        assert_eq!(lens.interfaces.len(), 1);
+        assert_eq!(lens.interfaces[0].aperture_radius, 10.0 * MM);
+    }
+
+    #[test]
+    fn rejects_bad_lens_files() {
+        for (name, text) in [("empty.dat", "# Nothing here\n"), ("short.dat", "50 40 1.5\n"), ("long.dat", "50 40 1.5 20 1\n"), ("word.dat", "50 forty 1.5 20\n")] {
+            assert!(read_text(name, text).is_err(), "{}", name);
+        }
+        assert!(Lens::read("lenses/missing.dat").is_err());
+    }
+
+    #[test]
+    fn reports_lenses_it_cant_use() {
+        assert!(camera(Lens::read(DOUBLE_GAUSS).unwrap(), 10.0).is_ok());
+        // Closer than the focal length
+        assert!(camera(Lens::read(DOUBLE_GAUSS).unwrap(), 0.01).is_err());
+        let mut closed = Lens::read(DOUBLE_GAUSS).unwrap();
+        closed.set_stop(Aperture::new_with_values(0, 0.0), Some(0.0));
+        assert!(camera(closed, 10.0).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use crate::hittable::hit_record;
+use crate::sphere::Sphere;
+use crate::vec3::{Color, Point3, Vec3};
+
+// How far an animated light has moved from its shape at the current camera time
+pub(crate) type LightOffset = Arc<dyn Fn() -> Vec3 + Send + Sync>;
+
+// A sphere with an emissive material that light can be sampled from.
+pub(crate) struct AreaLight {
+    object_id: usize,
+    shape: Arc<Sphere>,
+    offset: Option<LightOffset>,
+}
+
+impl AreaLight {
+    // object_id is the index of the shape in the world list, as reported in hit_record
+    pub(crate) fn new_with_values(object_id: usize, shape: Arc<Sphere>) -> AreaLight {
+        AreaLight { object_id, shape, offset: None }
+    }
+
+    // The same light following its object when an animation moves it
+    pub(crate) fn moved(&self, offset: LightOffset) -> AreaLight {
+        AreaLight { object_id: self.object_id, shape: self.shape.clone(), offset: Some(offset) }
+    }
+
+    pub(crate) fn object_id(&self) -> usize {
+        self.object_id
+    }
+
+    // Uniformly sampled point and outward normal, the pdf is `pdf_position`
+    pub(crate) fn sample_point(&self) -> (Point3, Vec3) {
+        let (p, n) = self.shape.random_point();
+        match &self.offset {
+            Some(offset) => (p + offset(), n),
+            None => (p, n),
+        }
+    }
+
+    pub(crate) fn pdf_position(&self) -> f64 {
+        1.0 / self.shape.area()
+    }
+
+    // Radiance leaving a point with outward normal `n` in direction `w`
+    pub(crate) fn radiance(&self, n: &Vec3, w: &Vec3) -> Color {
+        let mut rec = hit_record::new();
+        rec.normal = *n;
+        rec.front_face = Vec3::dot(n, w) > 0.0;
+        self.shape.material().emitted(&rec)
+    }
+}
@@ -1,7 +1,33 @@
-use std::rc::Rc;
-use crate::hittable::{hit_record, Hittable};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
 use crate::hittable_list::HittableList;
-use crate::vec3::Point3;
+use crate::vec3::{Color, Point3};
+use crate::environment::{luminance, Environment, EnvironmentMap, GradientSky, PreethamSky};
+use crate::options::Options;
+use crate::integrator::{AmbientOcclusionIntegrator, DebugIntegrator, DebugMode, DirectLightingIntegrator, Integrator, PathIntegrator};
+use crate::scene::Scene;
+use crate::bdpt::BdptIntegrator;
+use crate::light::AreaLight;
+use crate::photon::PhotonIntegrator;
+use crate::mlt::MltIntegrator;
+use crate::sppm::Sppm;
+use std::time::{Duration, Instant};
+use crate::film::{CropWindow, Film, FilmTile, PixelStatistics};
+use crate::checkpoint::Checkpoint;
+use crate::denoise::FeatureBuffers;
+use crate::image_io::{HdrImage, InputSpace};
+use crate::aov::{AovBuffers, AovLayout};
+use crate::display::{DisplayTransform, ToneMap, Transfer};
+use crate::spectrum::{Ior, SpectralEnvironment};
+use crate::post::PostEffects;
+use crate::camera::{Aperture, Camera, EquirectangularCamera, FisheyeCamera, FisheyeMapping, OdsCamera, OrthographicCamera, PerspectiveCamera, StereoLayout};
+use crate::lens::{Lens, RealisticCamera};
+use crate::animation::Animation;
+use crate::tiles::TileOrder;
+use crate::distributed::{Coordinator, Job};
+use crate::filter::{BoxFilter, Filter, GaussianFilter, LanczosFilter, MitchellFilter, TentFilter};
+use crate::sampler::{BlueNoiseSampler, HaltonSampler, IndependentSampler, Sampler, SobolSampler, StratifiedSampler};
 
 mod vec3;
 mod ray;
@@ -12,131 +38,695 @@ mod color;
 mod rtweekend;
 mod camera;
 mod material;
+mod distribution;
+mod environment;
+mod image_io;
+mod onb;
+mod options;
+mod integrator;
+mod scene;
+mod aabb;
+mod bdpt;
+mod light;
+mod photon;
+mod sppm;
+mod sampler;
+mod mlt;
+mod blue_noise;
+mod filter;
+mod film;
+mod checkpoint;
+mod denoise;
+mod aov;
+mod display;
+mod spectrum;
+mod post;
+mod lens;
+mod animation;
+mod tiles;
+mod distributed;
 
-fn ray_color(r: &ray::Ray, world: &dyn Hittable, depth: usize) -> vec3::Color {
-    let mut rec = hit_record::new();
+fn build_environment(options: &Options) -> Box<dyn Environment> {
+    if let Some(path) = &options.env_map {
+        let space = match options.env_map_space.as_str() {
+            "srgb" => InputSpace::Srgb,
+            "linear" => InputSpace::Linear,
+            _ => InputSpace::Auto,
+        };
+        let image = image_io::read_image(path, space).expect("Error loading environment map");
+        eprintln!("Loaded environment map {} ({}x{})", path, image.width, image.height);
+        return Box::new(EnvironmentMap::new_with_values(image, options.env_rotation, options.env_intensity));
+    }
+    if options.sky == "preetham" {
+        return Box::new(PreethamSky::new_with_values(options.sun_direction(), options.turbidity, options.env_intensity, options.sun_intensity));
+    }
+    Box::new(GradientSky::new_with_values(options.env_intensity))
+}
+
+fn build_integrator(options: &Options) -> Box<dyn Integrator> {
+    let max_depth = options.max_depth.unwrap_or(64);
+    match options.integrator.as_str() {
+        "bdpt" => Box::new(BdptIntegrator::new_with_values(options.max_depth.unwrap_or(8))),
+        "mlt" => {
+            let path = PathIntegrator::new_with_values(max_depth, options.rr_depth);
+            Box::new(MltIntegrator::new_with_values(path, options.mlt_bootstrap, options.mlt_chains, options.mlt_sigma, options.mlt_large_step))
+        }
+        "photon" => Box::new(PhotonIntegrator::new_with_values(max_depth, options.rr_depth, options.caustic_photons, options.photon_lookup, options.photon_radius)),
+        "direct" => Box::new(DirectLightingIntegrator::new_with_values(max_depth)),
+        "ao" => Box::new(AmbientOcclusionIntegrator::new_with_values(options.ao_samples, options.ao_distance)),
+        "normals" => Box::new(DebugIntegrator::new_with_values(DebugMode::Normals, options.depth_range)),
+        "depth" => Box::new(DebugIntegrator::new_with_values(DebugMode::Depth, options.depth_range)),
+        "uv" => Box::new(DebugIntegrator::new_with_values(DebugMode::Uv, options.depth_range)),
+        "material-id" => Box::new(DebugIntegrator::new_with_values(DebugMode::MaterialId, options.depth_range)),
+        _ => Box::new(PathIntegrator::new_with_values(max_depth, options.rr_depth)),
+    }
+}
+
+fn build_sampler(options: &Options, samples_per_pixel: usize) -> Box<dyn Sampler> {
+    match options.sampler.as_str() {
+        "independent" => Box::new(IndependentSampler),
+        "stratified" => Box::new(StratifiedSampler::new_with_values(samples_per_pixel)),
+        "halton" => Box::new(HaltonSampler::new()),
+        "blue-noise" => Box::new(BlueNoiseSampler::new()),
+        _ => Box::new(SobolSampler::new()),
+    }
+}
+
+fn build_filter(options: &Options) -> Box<dyn Filter> {
+    match options.filter.as_str() {
+        "tent" => Box::new(TentFilter::new_with_values(options.filter_radius.unwrap_or(1.0))),
+        "gaussian" => Box::new(GaussianFilter::new_with_values(options.filter_radius.unwrap_or(1.5))),
+        "mitchell" => Box::new(MitchellFilter::new_with_values(options.filter_radius.unwrap_or(2.0))),
+        "lanczos" => Box::new(LanczosFilter::new_with_values(options.filter_radius.unwrap_or(3.0))),
+        _ => Box::new(BoxFilter::new_with_values(options.filter_radius.unwrap_or(0.5))),
+    }
+}
+
+// `crop` is the window written out of a width x height frame, if only that is
+fn build_display(options: &Options, width: usize, height: usize, crop: Option<CropWindow>) -> DisplayTransform {
+    let mut effects = PostEffects::new();
+    effects.bloom = options.bloom;
+    effects.bloom_radius = options.bloom_radius.unwrap_or(width as f64 / 50.0);
+    effects.threshold = options.bloom_threshold;
+    effects.streaks = options.streaks;
+    effects.streak_length = options.streak_length.unwrap_or(width as f64 / 10.0);
+    effects.streak_strength = options.streak_strength;
+    effects.chromatic_aberration = options.chromatic_aberration;
+    effects.vignette = options.vignette;
+    effects.grain = options.grain;
+    effects.frame = crop.map(|window| (width, height, window));
 
-    if depth <= 0 {
-        return vec3::Color::new_with_values(0.0, 0.0, 0.0);
+    let tone_map = match options.tonemap.as_str() {
+        "reinhard" => ToneMap::Reinhard,
+        "aces" => ToneMap::Aces,
+        "agx" => ToneMap::Agx,
+        _ => ToneMap::Clamp,
+    };
+    let transfer = match options.transfer.as_str() {
+        "srgb" => Transfer::Srgb,
+        "rec709" => Transfer::Rec709,
+        "linear" => Transfer::Linear,
+        _ => Transfer::Gamma2,
+    };
+    let mut exposure = options.exposure;
+    if options.iso.is_some() || options.shutter.is_some() || options.f_stop.is_some() {
+        exposure += camera::exposure_stops(options.iso.unwrap_or(100.0), options.shutter.unwrap_or(0.01), options.f_stop.unwrap_or(16.0));
     }
-    if world.hit(r, 0.001, f64::INFINITY, &mut rec) {
-        let mut scattered = ray::Ray::new();
-        let mut attenuation = vec3::Color::new_with_values(0.0, 0.0, 0.0);
-        if rec.mat_ptr.as_ref().unwrap().scatter(r, &rec, &mut attenuation, &mut scattered) {
-            return attenuation * ray_color(&scattered, world, depth-1);
+    DisplayTransform::new_with_values(effects, exposure, options.white_balance, tone_map, transfer, options.dither)
+}
+
+fn needs_samples(options: &Options, statistics: &PixelStatistics) -> bool {
+    match options.adaptive_threshold {
+        Some(threshold) => statistics.count() < options.max_samples && statistics.relative_error() > threshold,
+        None => false,
+    }
+}
+
+// Traces one camera sample through pixel (i, j) and adds it, and any light
+// paths it splatted, to the tile through the filter, working out its AOVs when there are any.
+// Returns the sample's colour.
+fn add_camera_sample(scene: &Scene, integrator: &dyn Integrator, filter: &dyn Filter, tile: &mut FilmTile, aovs: Option<&AovLayout>, (i, j): (usize, usize), index: usize) -> Color {
+    sampler::start_pixel_sample(i, j, index);
+    sampler::seed_pixel_sample(i, j, index);
+    let (du, dv) = rtweekend::random_double_2d();
+    let u = (i as f64 + du) / (tile.width() - 1) as f64;
+    let v = (j as f64 + dv) / (tile.height() - 1) as f64;
+    let (r, weight) = match scene.camera.get_weighted_ray(u, v) {
+        Some(weighted) => weighted,
+        None => {
+            // Outside what the camera sees
+            tile.add_sample(filter, u, v, Color::new(), 0.0);
+            if let Some(aovs) = aovs {
+                tile.add_aov_sample(aovs.sample(u, v, None, scene, None));
+            }
+            return Color::new();
         }
-        return vec3::Color::new_with_values(0.0, 0.0, 0.0);
+    };
+    if scene.spectral {
+        spectrum::start_sample(rtweekend::random_double());
+    }
+    scene.start_time();
+    let mut splats = Vec::new();
+    let mut components = match aovs {
+        Some(aovs) if aovs.wants_lighting() => integrator.li_components(&r, scene, &mut splats),
+        _ => None,
+    };
+    let mut color = match &components {
+        Some(components) => components.total(),
+        None => integrator.li(&r, scene, &mut splats),
+    };
+    if scene.spectral {
+        color = spectrum::to_rgb(color);
+        components = components.map(|c| c.map(spectrum::to_rgb));
+        spectrum::end_sample();
+    }
+    if weight != 1.0 {
+        color = color * weight;
+        components = components.map(|c| c.map(|c| c * weight));
+    }
+    let alpha = if scene.transparent_background { scene.coverage(&r) } else { 1.0 };
+    tile.add_sample(filter, u, v, color, alpha);
+    if let Some(aovs) = aovs {
+        tile.add_aov_sample(aovs.sample(u, v, Some(&r), scene, components.as_ref()));
     }
 
-    let unit_direction = r.direction().unit_vector();
-    let t = 0.5 * (unit_direction.y() + 1.0);
-    vec3::Color::new_with_values(1.0, 1.0, 1.0) * (1.0 - t) + vec3::Color::new_with_values(0.5, 0.7, 1.0) * t
+    for splat in splats {
+        tile.add_splat(splat);
+    }
+    color
 }
 
-fn hit_sphere(center: &Point3, radius: f64, r: &ray::Ray) -> f64 {
-    let oc = r.origin() - *center;
-    let a = r.direction().length_squared();
-    let half_b = vec3::Vec3::dot(&oc, &r.direction());
-    let c = oc.length_squared() - radius*radius;
-    let discriminant = half_b*half_b - a*c;
-    if discriminant < 0.0 {
-        return -1.0;
+fn surface_distance(world: &dyn hittable::Hittable, lookfrom: Point3, forward: vec3::Vec3, direction: vec3::Vec3) -> Option<f64> {
+    let mut rec = hittable::hit_record::new();
+    if world.hit(&ray::Ray::new_with_values(lookfrom, direction), 0.001, rtweekend::INFINITY, &mut rec) {
+        Some(vec3::Vec3::dot(&(rec.p - lookfrom), &forward))
     } else {
-        return (-half_b - discriminant.sqrt()) / (a);
+        None
+    }
+}
+
+// Distance along the view direction to the near side of an object, where a
+// ray towards the middle of its bounds meets it. None for unbounded objects.
+fn object_distance(object: &dyn hittable::Hittable, lookfrom: Point3, forward: vec3::Vec3) -> Option<f64> {
+    let mut bounds = aabb::Aabb::new();
+    if !object.bounding_box(&mut bounds) {
+        return None;
     }
+    let center = (bounds.min() + bounds.max()) * 0.5;
+    Some(surface_distance(object, lookfrom, forward, center - lookfrom).unwrap_or(vec3::Vec3::dot(&(center - lookfrom), &forward)))
 }
 
-fn random_scene() -> HittableList {
+// The earlier render `--merge` fills in around the crop window, read before
+// rendering so a wrong file is found straight away
+fn merge_image(path: &str, width: usize, height: usize) -> HdrImage {
+    let image = image_io::read_image(path, InputSpace::Linear).unwrap_or_else(|e| options::exit_with_error(&format!("Error reading {}: {}", path, e)));
+    if image.width != width || image.height != height {
+        options::exit_with_error(&format!("Can't merge the {}x{} image {} into a {}x{} render", image.width, image.height, path, width, height));
+    }
+    image
+}
+
+// The image as written with a crop window: just the window, or the full image
+// with black or the earlier render in `background` around it
+fn crop_image(options: &Options, crop: Option<CropWindow>, background: Option<&HdrImage>, width: usize, height: usize, pixels: &[Color], alpha: Option<&[f64]>) -> (usize, usize, Vec<Color>, Option<Vec<f64>>) {
+    let mut pixels = pixels.to_vec();
+    let mut alpha = alpha.map(|a| a.to_vec());
+    let window = match crop {
+        Some(window) => window,
+        None => return (width, height, pixels, alpha),
+    };
+    if options.crop_output == "crop" {
+        return (window.width(), window.height(), window.cut(&pixels, width), alpha.map(|a| window.cut(&a, width)));
+    }
+
+    for y in 0..height {
+        for x in (0..width).filter(|&x| !window.contains(x, y)) {
+            pixels[y * width + x] = background.map_or(Color::new(), |image| image.get(x, y));
+            if let Some(alpha) = &mut alpha {
+                alpha[y * width + x] = if background.is_some() { 1.0 } else { 0.0 };
+            }
+        }
+    }
+    (width, height, pixels, alpha)
+}
+
+fn write_image(options: &Options, display: &DisplayTransform, width: usize, height: usize, pixels: &[Color], alpha: Option<&[f64]>) {
+    match &options.output {
+        Some(path) => image_io::write_image_file(path, width, height, pixels, alpha, display).expect("Error writing image"),
+        None => image_io::write_ppm(&mut std::io::stdout().lock(), width, height, pixels, display).expect("Error writing image"),
+    }
+}
+
+// With `bounce_time`, the small diffuse spheres are moving up at that many
+// seconds, for motion blur
+fn random_scene(glass: Ior, bounce_time: Option<f64>) -> (HittableList, Vec<AreaLight>) {
     let mut world = HittableList::new();
 
-    let ground_material = Rc::new(material::Lambertian::new_with_values(vec3::Color::new_with_values(0.5, 0.5, 0.5)));
-    world.add(Rc::new(sphere::Sphere::new_with_values(Point3::new_with_values(0.0, -1000.0, 0.0), 1000.0, ground_material)));
+    let ground_material = Arc::new(material::Lambertian::new_with_values(vec3::Color::new_with_values(0.5, 0.5, 0.5)));
+    world.add(Arc::new(sphere::Sphere::new_with_values(Point3::new_with_values(0.0, -1000.0, 0.0), 1000.0, ground_material)));
 
     for a in -11..11 {
         for b in -11..11 {
             let choose_mat = rtweekend::random_double();
             let center = Point3::new_with_values(a as f64 + 0.9*rtweekend::random_double(), 0.2, b as f64 + 0.9*rtweekend::random_double());
             if (center - Point3::new_with_values(4.0, 0.2, 0.0)).length() > 0.9 {
-                let sphere_material: Rc<dyn material::Material>;
+                let sphere_material: Arc<dyn material::Material>;
                 if choose_mat < 0.8 {
                     // diffuse
                     let albedo = vec3::random() * vec3::random();
-                    sphere_material = Rc::new(material::Lambertian::new_with_values(albedo));
-                    world.add(Rc::new(sphere::Sphere::new_with_values(center, 0.2, sphere_material)));
+                    sphere_material = Arc::new(material::Lambertian::new_with_values(albedo));
+                    match bounce_time {
+                        Some(time1) => {
+                            // Up to 5 units a second
+                            let center1 = center + vec3::Vec3::new_with_values(0.0, rtweekend::random_double_minmax(0.0, 5.0) * time1, 0.0);
+                            world.add(Arc::new(sphere::Sphere::new_moving(center, center1, time1, 0.2, sphere_material)));
+                        }
+                        None => world.add(Arc::new(sphere::Sphere::new_with_values(center, 0.2, sphere_material))),
+                    }
                 } else if choose_mat < 0.95 {
                     // metal
                     let albedo = vec3::random_minmax(0.5, 1.0);
                     let fuzz = rtweekend::random_double_minmax(0.0, 0.5);
-                    sphere_material = Rc::new(material::Metal::new_with_values(albedo, fuzz));
-                    world.add(Rc::new(sphere::Sphere::new_with_values(center, 0.2, sphere_material)));
+                    sphere_material = Arc::new(material::Metal::new_with_values(albedo, fuzz));
+                    world.add(Arc::new(sphere::Sphere::new_with_values(center, 0.2, sphere_material)));
                 } else {
                     // glass
-                    sphere_material = Rc::new(material::Dielectric::new_with_values(1.5));
-                    world.add(Rc::new(sphere::Sphere::new_with_values(center, 0.2, sphere_material)));
+                    sphere_material = Arc::new(material::Dielectric::new_with_values(glass));
+                    world.add(Arc::new(sphere::Sphere::new_with_values(center, 0.2, sphere_material)));
                 }
             }
         }
     }
 
-    let material1 = Rc::new(material::Dielectric::new_with_values(1.5));
-    world.add(Rc::new(sphere::Sphere::new_with_values(Point3::new_with_values(0.0, 1.0, 0.0), 1.0, material1)));
+    let material1 = Arc::new(material::Dielectric::new_with_values(glass));
+    world.add(Arc::new(sphere::Sphere::new_with_values(Point3::new_with_values(0.0, 1.0, 0.0), 1.0, material1)));
 
-    let material2 = Rc::new(material::Lambertian::new_with_values(vec3::Color::new_with_values(0.4, 0.2, 0.1)));
-    world.add(Rc::new(sphere::Sphere::new_with_values(Point3::new_with_values(-4.0, 1.0, 0.0), 1.0, material2)));
+    let material2 = Arc::new(material::Lambertian::new_with_values(vec3::Color::new_with_values(0.4, 0.2, 0.1)));
+    world.add(Arc::new(sphere::Sphere::new_with_values(Point3::new_with_values(-4.0, 1.0, 0.0), 1.0, material2)));
 
-    let material3 = Rc::new(material::Metal::new_with_values(vec3::Color::new_with_values(0.7, 0.6, 0.5), 0.0));
-    world.add(Rc::new(sphere::Sphere::new_with_values(Point3::new_with_values(4.0, 1.0, 0.0), 1.0, material3)));
+    let material3 = Arc::new(material::Metal::new_with_values(vec3::Color::new_with_values(0.7, 0.6, 0.5), 0.0));
+    world.add(Arc::new(sphere::Sphere::new_with_values(Point3::new_with_values(4.0, 1.0, 0.0), 1.0, material3)));
 
-    world
+    (world, Vec::new())
+}
 
+// A glass ball lit by a small lamp, best rendered with a dim background such as
+// `--env-intensity 0.05` so the caustic below the ball stands out.
+fn caustic_scene(glass: Ior) -> (HittableList, Vec<AreaLight>) {
+    let mut world = HittableList::new();
+    let mut lights = Vec::new();
+
+    let ground_material = Arc::new(material::Lambertian::new_with_values(vec3::Color::new_with_values(0.5, 0.5, 0.5)));
+    world.add(Arc::new(sphere::Sphere::new_with_values(Point3::new_with_values(0.0, -1000.0, 0.0), 1000.0, ground_material)));
 
+    let glass = Arc::new(material::Dielectric::new_with_values(glass));
+    world.add(Arc::new(sphere::Sphere::new_with_values(Point3::new_with_values(0.0, 1.0, 0.0), 1.0, glass)));
 
+    let diffuse = Arc::new(material::Lambertian::new_with_values(vec3::Color::new_with_values(0.4, 0.2, 0.1)));
+    world.add(Arc::new(sphere::Sphere::new_with_values(Point3::new_with_values(-4.0, 1.0, 0.0), 1.0, diffuse)));
 
+    let lamp_material = Arc::new(material::DiffuseLight::new_with_values(vec3::Color::new_with_values(60.0, 55.0, 45.0)));
+    let lamp = Arc::new(sphere::Sphere::new_with_values(Point3::new_with_values(-1.0, 5.0, 1.0), 0.4, lamp_material));
+    lights.push(AreaLight::new_with_values(world.len(), lamp.clone()));
+    world.add(lamp);
 
+    (world, lights)
 }
 
 
 
 fn main() {
+    let options = Options::from_args();
+    if let Some(address) = &options.worker {
+        return work(address, &options);
+    }
+    let resumed = options.resume.as_ref().map(|path| Checkpoint::read(path).expect("Error reading checkpoint"));
+    let options = match &resumed {
+        Some(checkpoint) => Options::from_args_resuming(&checkpoint.settings),
+        None => options,
+    };
+
+    let coordinator = options.listen.as_ref().map(|address| {
+        let job = Job::new_with_values(&options).expect("Error reading the files the workers need");
+        Coordinator::listen(address, job, Duration::from_secs_f64(options.worker_timeout)).unwrap_or_else(|e| panic!("Can't listen on {}: {}", address, e))
+    });
+
+    let (first, last) = match options.frames {
+        Some(frames) => frames,
+        None => return render(&options, resumed.as_ref(), None, 0, coordinator.as_ref()),
+    };
+    let animation = options.animation.as_ref().map(|path| Animation::read(path).unwrap_or_else(|e| panic!("Error reading animation {}: {}", path, e)));
+    for frame in first..=last {
+        let mut frame_options = options.for_frame(frame);
+        let path = frame_options.output.clone().unwrap();
+        if Path::new(&path).exists() {
+            eprintln!("Skipping frame {}, {} exists", frame, path);
+            continue;
+        }
+        eprintln!("Frame {} of {} to {}", frame, last, path);
+        // Written under a hidden name and moved into place when finished, so
+        // an interrupted frame is rendered again
+        let partial = Path::new(&path).with_file_name(format!(".{}", Path::new(&path).file_name().unwrap().to_string_lossy()));
+        frame_options.output = Some(partial.to_string_lossy().into_owned());
+        render(&frame_options, None, animation.as_ref(), frame, coordinator.as_ref());
+        std::fs::rename(&partial, &path).expect("Error writing image");
+    }
+}
+
+// A frame's scene and the tiles it's split into, set up the same way wherever
+// it's rendered
+struct Frame {
+    scene: Scene,
+    width: usize,
+    height: usize,
+    samples_per_pixel: usize,
+    filter: Box<dyn Filter>,
+    crop: Option<CropWindow>,
+    // The crop window and as far around it as the filter reaches
+    traced: Option<CropWindow>,
+    tiles: Vec<CropWindow>,
+}
+
+impl Frame {
+    // Whether pixel (i, j) is traced, j counting up from the bottom
+    fn in_window(&self, i: usize, j: usize) -> bool {
+        self.traced.is_none_or(|window| window.contains(i, self.height - 1 - j))
+    }
+}
 
+// Sets up frame `frame` of the animation if there is one
+fn setup_frame(options: &Options, animation: Option<&Animation>, frame: usize) -> Frame {
     // Image
-    const ASPECT_RATIO: f64 = 16.0 / 9.0;
     const IMAGE_WIDTH: usize = 1920;
     const SAMPLES_PER_PIXEL: usize = 30;
-    const MAX_DEPTH: usize = 5;
-    const IMAGE_HEIGHT: usize = (IMAGE_WIDTH as f64/ ASPECT_RATIO) as usize;
-    eprintln!("Image size: {}x{} and aspect ratio: {}", IMAGE_WIDTH, IMAGE_HEIGHT, ASPECT_RATIO);
+    // Panoramas are twice as wide as high, and fisheyes fill a square
+    let aspect_ratio = match options.camera.as_str() {
+        "equirect" => 2.0,
+        "fisheye" => 1.0,
+        "ods" if options.stereo == "side-by-side" => 4.0,
+        "ods" => 1.0,
+        _ => 16.0 / 9.0,
+    };
+    let image_height = (IMAGE_WIDTH as f64 / aspect_ratio) as usize;
+    let samples_per_pixel = options.samples.unwrap_or(SAMPLES_PER_PIXEL);
+    eprintln!("Image size: {}x{} and aspect ratio: {}", IMAGE_WIDTH, image_height, aspect_ratio);
 
-    // World
-    let mut world = random_scene();
+    // World, drawn from plain random numbers whichever sampler the last frame
+    // left installed
+    sampler::seed(options.seed);
+    sampler::set_sampler(Box::new(IndependentSampler));
+    material::reset_material_ids();
+    let (mut world, mut lights) = match options.scene.as_str() {
+        "caustic" => caustic_scene(options.glass),
+        "bouncing" => random_scene(options.glass, Some(options.shutter.unwrap_or(1.0))),
+        _ => random_scene(options.glass, None),
+    };
+    if let Some(animation) = animation {
+        animation.apply(&mut world, &mut lights, frame as f64, options.fps, options.shutter.unwrap_or(0.0));
+    }
 
     //Camera
 
-    let lookfrom = vec3::Point3::new_with_values(13.0, 2.0, 3.0);
-    let lookat = vec3::Point3::new_with_values(0.0, 0.0, 0.0);
+    let lookfrom = animation.and_then(|a| a.lookfrom(frame as f64)).unwrap_or(vec3::Point3::new_with_values(13.0, 2.0, 3.0));
+    let lookat = animation.and_then(|a| a.lookat(frame as f64)).unwrap_or(vec3::Point3::new_with_values(0.0, 0.0, 0.0));
     let vup = vec3::Vec3::new_with_values(0.0, 1.0, 0.0);
-    let dist_to_focus = 10 as f64;
-    let aperture = 0.1;
+    let mut aperture = 0.1;
+
+    let mut vfov = options.fov.unwrap_or(if options.camera == "fisheye" { 180.0 } else { 20.0 });
+    // A real camera's lens and sensor, in millimetres
+    let sensor_height = options.sensor_width / aspect_ratio;
+    if let Some(focal_length) = options.focal_length {
+        vfov = (2.0 * (sensor_height / (2.0 * focal_length)).atan()).to_degrees();
+    }
+    vfov = animation.and_then(|a| a.fov(frame as f64)).unwrap_or(vfov);
+    if let Some(f_stop) = options.f_stop {
+        let focal_length = options.focal_length.unwrap_or(sensor_height / (2.0 * (rtweekend::degrees_to_radians(vfov) / 2.0).tan()));
+        // The scene is in metres
+        aperture = focal_length * 0.001 / f_stop;
+    }
+
+    // Focus on where things are as the shutter opens
+    camera::set_time(0.0);
+    let forward = (lookat - lookfrom).unit_vector();
+    let distance_to = |n: isize| {
+        let object = world.index(n).map(|i| world.objects()[i].as_ref())
+            .unwrap_or_else(|| options::exit_with_error(&format!("Can't focus on object {}, the scene has {} objects", n, world.len())));
+        object_distance(object, lookfrom, forward)
+            .unwrap_or_else(|| options::exit_with_error(&format!("Can't focus on object {}, it has no bounds", n)))
+    };
+    let mut dist_to_focus = options.focus_distance.unwrap_or(10.0);
+    if let Some(n) = options.focus_object {
+        dist_to_focus = distance_to(n);
+    } else if options.autofocus {
+        // Through the middle of the pixel, or of the image
+        let (x, y) = options.focus_pixel.map_or((0.5, 0.5), |(x, y)| (x as f64 / (IMAGE_WIDTH - 1) as f64, 1.0 - y as f64 / (image_height - 1) as f64));
+        let direction = PerspectiveCamera::new(lookfrom, lookat, vup, vfov, aspect_ratio, 0.0, 1.0).pinhole_direction(x, y);
+        match surface_distance(&world, lookfrom, forward, direction) {
+            Some(distance) => dist_to_focus = distance,
+            None => eprintln!("Nothing to focus on, keeping the focus at {}", dist_to_focus),
+        }
+    }
+    dist_to_focus = animation.and_then(|a| a.focus(frame as f64, distance_to)).unwrap_or(dist_to_focus);
+    if options.autofocus || options.focus_object.is_some() || animation.is_some() {
+        eprintln!("Focus distance: {:.3}", dist_to_focus);
+    }
 
-    let cam = camera::Camera::new(lookfrom, lookat, vup, 20.0, ASPECT_RATIO, aperture, dist_to_focus);
+    let cam: Box<dyn Camera> = match options.camera.as_str() {
+        "orthographic" => Box::new(OrthographicCamera::new_with_values(lookfrom, lookat, vup, vfov, aspect_ratio, dist_to_focus)),
+        "equirect" => Box::new(EquirectangularCamera::new_with_values(lookfrom, lookat, vup)),
+        "fisheye" => {
+            let mapping = if options.fisheye == "equisolid" { FisheyeMapping::Equisolid } else { FisheyeMapping::Equidistant };
+            Box::new(FisheyeCamera::new_with_values(lookfrom, lookat, vup, vfov, aspect_ratio, mapping))
+        }
+        "ods" => {
+            let layout = if options.stereo == "side-by-side" { StereoLayout::SideBySide } else { StereoLayout::OverUnder };
+            Box::new(OdsCamera::new_with_values(lookfrom, lookat, vup, options.ipd, layout))
+        }
+        "realistic" => {
+            let path = options.lens.as_ref().unwrap();
+            let lens_error = |e: std::io::Error| format!("Error with lens {}: {}", path, e);
+            let mut lens = Lens::read(path).unwrap_or_else(|e| options::exit_with_error(&lens_error(e)));
+            lens.set_stop(Aperture::new_with_values(options.aperture_blades, options.aperture_rotation), options.stop_diameter);
+            eprintln!("Lens focal length: {:.1}mm", lens.focal_length() * 1000.0);
+            Box::new(RealisticCamera::new_with_values(lookfrom, lookat, vup, lens, options.film_diagonal, aspect_ratio, dist_to_focus).unwrap_or_else(|e| options::exit_with_error(&lens_error(e))))
+        }
+        _ => {
+            let mut cam = PerspectiveCamera::new(lookfrom, lookat, vup, vfov, aspect_ratio, aperture, dist_to_focus);
+            cam.set_aperture(Aperture::new_with_values(options.aperture_blades, options.aperture_rotation));
+            Box::new(cam)
+        }
+    };
 
-    //Render
+    let mut environment = build_environment(options);
+    if options.spectral {
+        environment = Box::new(SpectralEnvironment::new_with_values(environment));
+    }
+    let mut scene = Scene::new_with_values(world, lights, environment, cam);
+    scene.transparent_background = options.transparent;
+    scene.spectral = options.spectral;
+    scene.shutter = options.shutter.unwrap_or(0.0);
+    sampler::set_sampler(build_sampler(options, samples_per_pixel));
 
-    println!("P3\n{} {}\n255\n", IMAGE_WIDTH, IMAGE_HEIGHT);
+    let filter = build_filter(options);
+    let crop = options.crop_window(IMAGE_WIDTH, image_height);
+    // Samples from just outside the crop window reach its edge through the filter
+    let traced = crop.map(|window| window.grown(filter.radius().ceil() as usize, IMAGE_WIDTH, image_height));
+    let tile_order = match options.tile_order.as_str() {
+        "scanline" => TileOrder::Scanline,
+        "hilbert" => TileOrder::Hilbert,
+        _ => TileOrder::Spiral,
+    };
+    // Tiles that are wholly outside the crop window are left out
+    let tiles = tiles::tiles(IMAGE_WIDTH, image_height, options.tile_size, tile_order).into_iter()
+        .filter(|tile| traced.is_none_or(|window| tile.x0 < window.x1 && window.x0 < tile.x1 && tile.y0 < window.y1 && window.y0 < tile.y1))
+        .collect();
 
-    for j in (0..IMAGE_HEIGHT).rev() {
-        eprintln!("\rScanlines remaining: {}", j);
-        for i in 0..IMAGE_WIDTH {
-            let mut pixel_color = vec3::Color::new_with_values(0.0, 0.0, 0.0);
-            for _s in 0..SAMPLES_PER_PIXEL {
-                let u = (i as f64 + rtweekend::random_double()) / (IMAGE_WIDTH - 1) as f64;
-                let v = (j as f64 + rtweekend::random_double()) / (IMAGE_HEIGHT - 1) as f64;
-                let r = cam.get_ray(u, v);
-                pixel_color = pixel_color + ray_color(&r, &world, MAX_DEPTH);
+    Frame { scene, width: IMAGE_WIDTH, height: image_height, samples_per_pixel, filter, crop, traced, tiles }
+}
+
+fn thread_count(options: &Options) -> usize {
+    options.threads.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+// Every worker thread draws from its own sampler
+fn start_thread(options: &Options, samples_per_pixel: usize) {
+    sampler::seed(options.seed);
+    sampler::set_sampler(build_sampler(options, samples_per_pixel));
+}
+
+// Renders the pixels of a tile in full. Every pixel gets samples_per_pixel
+// samples, and in adaptive mode more until its estimated error drops below the
+// threshold.
+fn render_tile(frame: &Frame, options: &Options, integrator: &dyn Integrator, aovs: Option<&AovLayout>, window: CropWindow) -> FilmTile {
+    let mut tile = FilmTile::new_with_values(frame.width, frame.height, window, frame.filter.as_ref());
+    for y in window.y0..window.y1 {
+        let j = frame.height - 1 - y;
+        for i in (window.x0..window.x1).filter(|&i| frame.in_window(i, j)) {
+            let mut statistics = PixelStatistics::new();
+            let mut s = 0;
+            while s < frame.samples_per_pixel || needs_samples(options, &statistics) {
+                let color = add_camera_sample(&frame.scene, integrator, frame.filter.as_ref(), &mut tile, aovs, (i, j), s);
+                statistics.add(luminance(&color));
+                s += 1;
             }
-            color::write_color(&mut std::io::stdout(), pixel_color, SAMPLES_PER_PIXEL as i32);
         }
     }
+    tile
+}
+
+// Renders tiles for the coordinator at `address`, keeping the frame they're
+// from set up for the tiles after them
+fn work(address: &str, options: &Options) {
+    let threads = thread_count(options);
+    let mut current: Option<(usize, Frame, Box<dyn Integrator>)> = None;
+    let result = distributed::work(address, threads, |job, frame, windows, send| {
+        if current.as_ref().is_none_or(|(f, _, _)| *f != frame) {
+            let animation = job.animation.as_ref().map(|path| Animation::read(path).unwrap_or_else(|e| panic!("Error reading animation {}: {}", path, e)));
+            let setup = setup_frame(job, animation.as_ref(), frame);
+            let mut integrator = build_integrator(job);
+            integrator.preprocess(&setup.scene);
+            current = Some((frame, setup, integrator));
+        }
+        let (_, setup, integrator) = current.as_ref().unwrap();
+        let layout = if job.aovs.is_empty() { None } else { Some(AovLayout::new_with_values(&job.aovs, setup.width, setup.height)) };
+        tiles::render(windows, threads, || start_thread(job, setup.samples_per_pixel), |window| {
+            render_tile(setup, job, integrator.as_ref(), layout.as_ref(), window)
+        }, send);
+    });
+    if let Err(e) = result {
+        panic!("Error working for {}: {}", address, e);
+    }
+    eprintln!("\nDone.\n");
+}
+
+// Renders one image, frame `frame` of the animation if there is one, on the
+// coordinator's workers when there's a coordinator
+fn render(options: &Options, resumed: Option<&Checkpoint>, animation: Option<&Animation>, frame_number: usize, coordinator: Option<&Coordinator>) {
+    let frame = setup_frame(options, animation, frame_number);
+    let scene = &frame.scene;
+    let samples_per_pixel = frame.samples_per_pixel;
+    let crop = frame.crop;
+    let background = options.merge.as_ref().map(|path| merge_image(path, frame.width, frame.height));
+    let mut film = Film::new_with_values(frame.width, frame.height);
+    let threads = thread_count(options);
+    let written_crop = crop.filter(|_| options.crop_output == "crop");
+    let display = build_display(options, frame.width, frame.height, written_crop);
+    let mut aovs = if options.aovs.is_empty() { None } else { Some(AovBuffers::new_with_values(&options.aovs, frame.width, frame.height)) };
+    let layout = if options.aovs.is_empty() { None } else { Some(AovLayout::new_with_values(&options.aovs, frame.width, frame.height)) };
+
+    if options.integrator == "sppm" {
+        // SPPM renders the whole image at once, one iteration per sample
+        let sppm = Sppm::new_with_values(options.max_depth.unwrap_or(64), options.photons, options.photon_radius);
+        for (index, color) in sppm.render(scene, frame.width, frame.height, samples_per_pixel).into_iter().enumerate() {
+            film.set_pixel(index, color);
+        }
+    } else if options.progressive {
+        let mut integrator = build_integrator(options);
+        integrator.preprocess(scene);
+
+        let mut first_pass = 0;
+        if let Some(checkpoint) = &resumed {
+            film.read_state(&mut checkpoint.film.as_slice()).expect("Error reading checkpoint");
+            first_pass = checkpoint.passes;
+            eprintln!("Resuming after {} passes", first_pass);
+        }
+
+        // Whole image passes of one sample per pixel, so the image can be
+        // written out at any point and the render stopped early.
+        let start = Instant::now();
+        let mut last_checkpoint = start;
+        let passes = if options.adaptive_threshold.is_some() { options.max_samples.max(samples_per_pixel) } else { samples_per_pixel };
+        let mut passes_done = first_pass;
+        for pass in first_pass..passes {
+            // Which pixels this pass samples, rows top to bottom
+            let wanted: Vec<bool> = (0..frame.height).rev().flat_map(|j| (0..frame.width).map(move |i| (i, j)))
+                .map(|(i, j)| frame.in_window(i, j) && (pass < samples_per_pixel || needs_samples(options, film.statistics(i, j))))
+                .collect();
+            if !wanted.contains(&true) {
+                break;
+            }
+            tiles::render(&frame.tiles, threads, || start_thread(options, samples_per_pixel), |window| {
+                let mut tile = FilmTile::new_with_values(frame.width, frame.height, window, frame.filter.as_ref());
+                for y in window.y0..window.y1 {
+                    for i in (window.x0..window.x1).filter(|&i| wanted[y * frame.width + i]) {
+                        add_camera_sample(scene, integrator.as_ref(), frame.filter.as_ref(), &mut tile, layout.as_ref(), (i, frame.height - 1 - y), pass);
+                    }
+                }
+                tile
+            }, |tile| film.add_tile(tile, aovs.as_mut()));
+            passes_done = pass + 1;
+
+            let elapsed = start.elapsed().as_secs_f64();
+            eprintln!("\rPass {} of {} after {:.1}s", pass + 1, passes, elapsed);
+            let every_pass = options.checkpoint_every.is_some_and(|n| (pass + 1) % n == 0);
+            let every_second = options.checkpoint_seconds.is_some_and(|t| last_checkpoint.elapsed().as_secs_f64() >= t);
+            if (every_pass || every_second) && pass + 1 < passes {
+                if let Some(path) = &options.output {
+                    let alpha = if options.transparent { Some(film.alphas()) } else { None };
+                    let (width, height, pixels, alpha) = crop_image(options, crop, background.as_ref(), frame.width, frame.height, &film.pixels(), alpha.as_deref());
+                    image_io::write_image_file(path, width, height, &pixels, alpha.as_deref(), &display).expect("Error writing image");
+                }
+                if let Some(path) = &options.checkpoint {
+                    checkpoint::write(path, &options.settings, passes_done, &film).expect("Error writing checkpoint");
+                }
+                last_checkpoint = Instant::now();
+            }
+            if options.time_limit.is_some_and(|limit| elapsed >= limit) {
+                eprintln!("Time limit reached after {} passes", pass + 1);
+                break;
+            }
+        }
+
+        if let Some(path) = &options.checkpoint {
+            checkpoint::write(path, &options.settings, passes_done, &film).expect("Error writing checkpoint");
+        }
+    } else {
+        let mut remaining = frame.tiles.len();
+        let mut finish_tile = |tile| {
+            film.add_tile(tile, aovs.as_mut());
+            remaining -= 1;
+            eprintln!("\rTiles remaining: {}", remaining);
+        };
+        match coordinator {
+            Some(coordinator) => coordinator.render(frame_number, frame.width, frame.height, &frame.tiles, finish_tile),
+            None => {
+                let mut integrator = build_integrator(options);
+                integrator.preprocess(scene);
+                tiles::render(&frame.tiles, threads, || start_thread(options, samples_per_pixel), |window| {
+                    render_tile(&frame, options, integrator.as_ref(), layout.as_ref(), window)
+                }, &mut finish_tile);
+            }
+        }
+    }
+
+    let mut pixels = film.pixels();
+    let alpha = if options.transparent { Some(film.alphas()) } else { None };
+    if options.denoise {
+        if let Some(path) = &options.noisy_output {
+            image_io::write_image_file(path, frame.width, frame.height, &pixels, alpha.as_deref(), &display).expect("Error writing noisy image");
+        }
+        let features = FeatureBuffers::new_with_values(scene, frame.width, frame.height);
+        // mlt and sppm don't estimate pixels from their own samples, so
+        // their noise can't be measured per pixel
+        let variance = film.mean_variances();
+        let measured = options.integrator != "mlt" && options.integrator != "sppm";
+        pixels = denoise::denoise(&pixels, if measured { Some(&variance) } else { None }, &features, frame.width, frame.height);
+    }
+    let (width, height, cropped, cropped_alpha) = crop_image(options, crop, background.as_ref(), frame.width, frame.height, &pixels, alpha.as_deref());
+    write_image(options, &display, width, height, &cropped, cropped_alpha.as_deref());
+
+    if let (Some(aovs), Some(path)) = (&aovs, &options.aov_output) {
+        if path.ends_with(".exr") {
+            aovs.write_exr(path, &pixels, alpha.as_deref()).expect("Error writing AOVs");
+        } else {
+            aovs.write_pfms(path).expect("Error writing AOVs");
+        }
+    }
+
+    if let Some(path) = &options.sample_map {
+        let heat = film.sample_map(options.max_samples.max(samples_per_pixel));
+        image_io::write_image_file(path, frame.width, frame.height, &heat, None, &DisplayTransform::raw()).expect("Error writing sample map");
+    }
 
     eprintln!("\nDone.\n");
 }
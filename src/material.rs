@@ -1,30 +1,78 @@
-use crate::{hittable, ray, vec3};
+use crate::{hittable, ray, rtweekend, vec3};
 use crate::hittable::hit_record;
+use crate::spectrum::{self, Ior};
 use crate::ray::Ray;
 use crate::vec3::Color;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_MATERIAL_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Materials are numbered in creation order so debug views and AOVs are stable between runs
+fn next_material_id() -> usize {
+    NEXT_MATERIAL_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// Starts numbering again from zero, before a scene is built, so every frame
+// and every worker's copy of the scene gives its materials the same ids
+pub(crate) fn reset_material_ids() {
+    NEXT_MATERIAL_ID.store(0, Ordering::Relaxed);
+}
+
+pub(crate) trait Material: Send + Sync {
+    fn id(&self) -> usize;
 
-pub(crate) trait Material {
     fn scatter(&self, r_in: &ray::Ray, rec: &hittable::hit_record, attenuation: &mut vec3::Color, scattered: &mut ray::Ray) -> bool;
+
+    // Radiance leaving the surface towards where the ray came from
+    fn emitted(&self, _rec: &hittable::hit_record) -> vec3::Color {
+        vec3::Color::new()
+    }
+
+    // BRDF times cosine towards `direction`, used when sampling lights explicitly.
+    // Specular materials can't be evaluated this way and return black.
+    fn eval(&self, _r_in: &ray::Ray, _rec: &hittable::hit_record, _direction: &vec3::Vec3) -> vec3::Color {
+        vec3::Color::new()
+    }
+
+    // Solid angle pdf of `scatter` choosing `direction`, zero for specular materials.
+    fn scattering_pdf(&self, _r_in: &ray::Ray, _rec: &hittable::hit_record, _direction: &vec3::Vec3) -> f64 {
+        0.0
+    }
+
+    // Mirrors and glass, which photon mapping follows rather than storing photons on
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    // Surface colour for the denoiser's albedo buffer
+    fn albedo(&self, _rec: &hittable::hit_record) -> vec3::Color {
+        vec3::Color::new_with_values(1.0, 1.0, 1.0)
+    }
 }
 
 pub struct Lambertian {
+    id: usize,
     albedo: vec3::Color,
 }
 
 impl Lambertian{
-    fn new() -> Lambertian {
-        Lambertian {
-            albedo: vec3::Color::new_with_values(0.0, 0.0, 0.0),
-        }
-    }
     pub(crate) fn new_with_values(a: vec3::Color) -> Lambertian {
         Lambertian {
+            id: next_material_id(),
             albedo: a,
         }
     }
 }
 
 impl Material for Lambertian {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn albedo(&self, _rec: &hittable::hit_record) -> vec3::Color {
+        self.albedo
+    }
+
     fn scatter(&self, _r_in: &ray::Ray, rec: &hittable::hit_record, attenuation: &mut vec3::Color, scattered: &mut ray::Ray) -> bool {
         let mut scatter_direction = rec.normal + vec3::random_unit_vector();
 
@@ -33,25 +81,30 @@ impl Material for Lambertian {
         }
 
         *scattered = ray::Ray::new_with_values(rec.p, scatter_direction);
-        *attenuation = self.albedo;
+        *attenuation = spectrum::from_rgb(self.albedo);
         true
     }
+
+    fn eval(&self, r_in: &ray::Ray, rec: &hittable::hit_record, direction: &vec3::Vec3) -> vec3::Color {
+        spectrum::from_rgb(self.albedo) * self.scattering_pdf(r_in, rec, direction)
+    }
+
+    fn scattering_pdf(&self, _r_in: &ray::Ray, rec: &hittable::hit_record, direction: &vec3::Vec3) -> f64 {
+        let cosine = vec3::Vec3::dot(&rec.normal, &direction.unit_vector());
+        if cosine < 0.0 { 0.0 } else { cosine / rtweekend::PI }
+    }
 }
 
 pub(crate) struct Metal {
+    id: usize,
     albedo: vec3::Color,
     fuzz: f64,
 }
 
 impl Metal {
-    fn new() -> Metal {
-        Metal {
-            albedo: vec3::Color::new_with_values(0.0, 0.0, 0.0),
-            fuzz: 0.0,
-        }
-    }
     pub(crate) fn new_with_values(a: vec3::Color, f: f64) -> Metal {
         Metal {
+            id: next_material_id(),
             albedo: a,
             fuzz: if f < 1.0 { f } else { 1.0 },
         }
@@ -59,36 +112,51 @@ impl Metal {
 }
 
 impl Material for Metal {
+    fn id(&self) -> usize {
+        self.id
+    }
+
     fn scatter(&self, r_in: &ray::Ray, rec: &hittable::hit_record, attenuation: &mut vec3::Color, scattered: &mut ray::Ray) -> bool {
         let reflected = vec3::reflect(&r_in.direction().unit_vector(), &rec.normal);
         *scattered = ray::Ray::new_with_values(rec.p, reflected + vec3::random_in_unit_sphere()*self.fuzz);
-        *attenuation = self.albedo;
+        *attenuation = spectrum::from_rgb(self.albedo);
 
         vec3::Vec3::dot(&scattered.direction(), &rec.normal) > 0.0
     }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn albedo(&self, _rec: &hittable::hit_record) -> vec3::Color {
+        self.albedo
+    }
 }
 
+// Glass, dispersive when its index of refraction depends on the wavelength
 pub(crate) struct Dielectric {
-    ir: f64,
+    id: usize,
+    ir: Ior,
 }
 
 impl Dielectric {
-    fn new() -> Dielectric {
-        Dielectric {
-            ir: 0.0,
-        }
-    }
-    pub(crate) fn new_with_values(index_of_refraction: f64) -> Dielectric {
+    pub(crate) fn new_with_values(index_of_refraction: Ior) -> Dielectric {
         Dielectric {
+            id: next_material_id(),
             ir: index_of_refraction,
         }
     }
 }
 
 impl Material for Dielectric {
+    fn id(&self) -> usize {
+        self.id
+    }
+
     fn scatter(&self, r_in: &Ray, rec: &hit_record, attenuation: &mut Color, scattered: &mut Ray) -> bool {
-        *attenuation = Color::new_with_values(1.0, 1.0, 1.0);
-        let refraction_ratio = if rec.front_face { 1.0 / self.ir } else { self.ir };
+        let (ir, weight) = self.ir.sample();
+        *attenuation = weight;
+        let refraction_ratio = if rec.front_face { 1.0 / ir } else { ir };
 
         let unit_direction = r_in.direction().unit_vector();
 
@@ -97,7 +165,7 @@ impl Material for Dielectric {
 
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
         let direction =
-            if cannot_refract || reflectance(cos_theta, refraction_ratio) > rand::random() {
+            if cannot_refract || reflectance(cos_theta, refraction_ratio) > rtweekend::random_double() {
                 vec3::reflect(&unit_direction, &rec.normal)
             } else {
                 vec3::refract(&unit_direction, &rec.normal, refraction_ratio)
@@ -107,6 +175,9 @@ impl Material for Dielectric {
         true
     }
 
+    fn is_specular(&self) -> bool {
+        true
+    }
 }
 
 fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
@@ -114,4 +185,33 @@ fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
     let mut r0 = (1.0-ref_idx) / (1.0+ref_idx);
     r0 = r0*r0;
     r0 + (1.0-r0)*(1.0-cosine).powf(5.0)
-}
\ No newline at end of file
+}
+
+// Emits light from the outside of the surface and doesn't scatter anything.
+pub(crate) struct DiffuseLight {
+    id: usize,
+    emit: Color,
+}
+
+impl DiffuseLight {
+    pub(crate) fn new_with_values(c: Color) -> DiffuseLight {
+        DiffuseLight {
+            id: next_material_id(),
+            emit: c,
+        }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn scatter(&self, _r_in: &Ray, _rec: &hit_record, _attenuation: &mut Color, _scattered: &mut Ray) -> bool {
+        false
+    }
+
+    fn emitted(&self, rec: &hit_record) -> Color {
+        if rec.front_face { spectrum::from_rgb(self.emit) } else { Color::new() }
+    }
+}
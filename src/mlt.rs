@@ -0,0 +1,234 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::distribution::Distribution1D;
+use crate::environment::luminance;
+use crate::integrator::{Integrator, PathIntegrator, Splat};
+use crate::ray::Ray;
+use crate::rtweekend::{self, PI};
+use crate::sampler::{self, Sampler};
+use crate::scene::Scene;
+use crate::vec3::Color;
+
+#[derive(Clone, Copy)]
+struct PrimarySample {
+    value: f64,
+    // Iteration the value was last changed in, so mutations can be applied lazily
+    last_modification: u64,
+    value_backup: f64,
+    modify_backup: u64,
+}
+
+// A point in primary sample space: the vector of uniform numbers a path is
+// built from, mutated a little at a time or regenerated with a large step.
+struct PrimarySampleSpace {
+    rng: StdRng,
+    sigma: f64,
+    large_step_probability: f64,
+    x: Vec<PrimarySample>,
+    current_iteration: u64,
+    large_step: bool,
+    last_large_step_iteration: u64,
+    sample_index: usize,
+}
+
+impl PrimarySampleSpace {
+    fn new_with_values(seed: u64, sigma: f64, large_step_probability: f64) -> PrimarySampleSpace {
+        PrimarySampleSpace {
+            rng: StdRng::seed_from_u64(seed),
+            sigma,
+            large_step_probability,
+            x: Vec::new(),
+            current_iteration: 0,
+            large_step: true,
+            last_large_step_iteration: 0,
+            sample_index: 0,
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.current_iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.large_step_probability;
+        self.sample_index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step_iteration = self.current_iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for xi in self.x.iter_mut() {
+            if xi.last_modification == self.current_iteration {
+                xi.value = xi.value_backup;
+                xi.last_modification = xi.modify_backup;
+            }
+        }
+        self.current_iteration -= 1;
+    }
+
+    // Brings the sample up to date with every mutation it missed while unused
+    fn ensure_ready(&mut self, index: usize) {
+        // Dimensions seen for the first time start out uniform, as if from a
+        // large step, since small steps away from a fixed value would get stuck
+        // in rejection sampling loops.
+        while self.x.len() <= index {
+            let value = self.rng.gen();
+            let last_modification = self.last_large_step_iteration;
+            self.x.push(PrimarySample { value, last_modification, value_backup: value, modify_backup: last_modification });
+        }
+        let xi = &mut self.x[index];
+
+        if xi.last_modification < self.last_large_step_iteration {
+            xi.value = self.rng.gen();
+            xi.last_modification = self.last_large_step_iteration;
+        }
+
+        xi.value_backup = xi.value;
+        xi.modify_backup = xi.last_modification;
+        if self.large_step {
+            xi.value = self.rng.gen();
+        } else {
+            // The small steps skipped add up to a single wider normal perturbation
+            let n_small = (self.current_iteration - xi.last_modification) as f64;
+            let u1: f64 = self.rng.gen::<f64>().max(f64::MIN_POSITIVE);
+            let u2: f64 = self.rng.gen();
+            let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+            xi.value += normal * self.sigma * n_small.sqrt();
+            xi.value -= xi.value.floor();
+        }
+        xi.last_modification = self.current_iteration;
+    }
+}
+
+struct PssSampler {
+    space: Arc<Mutex<PrimarySampleSpace>>,
+}
+
+impl Sampler for PssSampler {
+    fn next_1d(&mut self) -> f64 {
+        let mut space = self.space.lock().unwrap();
+        let index = space.sample_index;
+        space.sample_index += 1;
+        space.ensure_ready(index);
+        space.x[index].value
+    }
+}
+
+struct Chain {
+    space: Arc<Mutex<PrimarySampleSpace>>,
+    l: Color,
+    u: f64,
+    v: f64,
+}
+
+// Primary sample space Metropolis light transport (Kelemen et al.) on top of the
+// path tracer. Markov chains wander over the random numbers the path tracer
+// consumes, spending more samples on the paths that carry most light. Each
+// camera sample advances one chain and splats its paths wherever on the image
+// they land, so the camera ray itself is ignored. The overall brightness is
+// estimated beforehand from `bootstrap` independent paths, which also seed the
+// chains. Chains are locked one at a time, so several threads can advance
+// different chains at once.
+pub(crate) struct MltIntegrator {
+    path: PathIntegrator,
+    bootstrap: usize,
+    chain_count: usize,
+    sigma: f64,
+    large_step_probability: f64,
+    b: f64,
+    chains: Vec<Mutex<Chain>>,
+    next_chain: AtomicUsize,
+}
+
+impl MltIntegrator {
+    pub(crate) fn new_with_values(path: PathIntegrator, bootstrap: usize, chain_count: usize, sigma: f64, large_step_probability: f64) -> MltIntegrator {
+        MltIntegrator {
+            path,
+            bootstrap,
+            chain_count,
+            sigma,
+            large_step_probability,
+            b: 0.0,
+            chains: Vec::new(),
+            next_chain: AtomicUsize::new(0),
+        }
+    }
+
+    // Traces the path given by the current point in primary sample space,
+    // returning its radiance and image position.
+    fn evaluate(&self, space: &Arc<Mutex<PrimarySampleSpace>>, scene: &Scene) -> (Color, f64, f64) {
+        let previous = sampler::set_sampler(Box::new(PssSampler { space: space.clone() }));
+        let (u, v) = rtweekend::random_double_2d();
+        scene.start_time();
+        let l = match scene.camera.get_ray(u, v) {
+            Some(r) => self.path.li(&r, scene, &mut Vec::new()),
+            None => Color::new(),
+        };
+        sampler::set_sampler(previous);
+        (l, u, v)
+    }
+
+    fn new_space(&self, seed: usize) -> Arc<Mutex<PrimarySampleSpace>> {
+        Arc::new(Mutex::new(PrimarySampleSpace::new_with_values(seed as u64, self.sigma, self.large_step_probability)))
+    }
+}
+
+impl Integrator for MltIntegrator {
+    fn preprocess(&mut self, scene: &Scene) {
+        let weights: Vec<f64> = (0..self.bootstrap).map(|i| {
+            let (l, _, _) = self.evaluate(&self.new_space(i), scene);
+            luminance(&l).max(0.0)
+        }).collect();
+        let distribution = Distribution1D::new_with_values(&weights);
+        self.b = distribution.integral();
+        eprintln!("Bootstrapped {} paths, mean luminance {}", self.bootstrap, self.b);
+
+        // Chains start from bootstrap paths picked in proportion to their
+        // luminance, replayed from the same seed.
+        let mut chains = Vec::with_capacity(self.chain_count);
+        for _ in 0..self.chain_count {
+            let (_, _, index) = distribution.sample_continuous(rtweekend::random_double());
+            let space = self.new_space(index);
+            let (l, u, v) = self.evaluate(&space, scene);
+            chains.push(Mutex::new(Chain { space, l, u, v }));
+        }
+        self.chains = chains;
+    }
+
+    fn li(&self, _r: &Ray, scene: &Scene, splats: &mut Vec<Splat>) -> Color {
+        if self.b <= 0.0 || self.chains.is_empty() {
+            return Color::new();
+        }
+        let index = self.next_chain.fetch_add(1, Ordering::Relaxed) % self.chains.len();
+        let mut chain = self.chains[index].lock().unwrap();
+
+        chain.space.lock().unwrap().start_iteration();
+        let (l, u, v) = self.evaluate(&chain.space, scene);
+
+        // Both the proposal and the current path are recorded, weighted by how
+        // likely each is to be the next state.
+        let proposed = luminance(&l);
+        let current = luminance(&chain.l);
+        let accept = if current > 0.0 { (proposed / current).min(1.0) } else { 1.0 };
+        if proposed > 0.0 {
+            splats.push(Splat { u, v, color: l * (self.b * accept / proposed) });
+        }
+        if current > 0.0 {
+            splats.push(Splat { u: chain.u, v: chain.v, color: chain.l * (self.b * (1.0 - accept) / current) });
+        }
+
+        if rtweekend::random_double() < accept {
+            chain.space.lock().unwrap().accept();
+            chain.l = l;
+            chain.u = u;
+            chain.v = v;
+        } else {
+            chain.space.lock().unwrap().reject();
+        }
+
+        Color::new()
+    }
+}
@@ -0,0 +1,20 @@
+use crate::vec3::Vec3;
+
+// Orthonormal basis built around a given w axis.
+pub struct Onb {
+    axis: [Vec3; 3],
+}
+
+impl Onb {
+    pub(crate) fn build_from_w(n: &Vec3) -> Onb {
+        let w = n.unit_vector();
+        let a = if w.x().abs() > 0.9 { Vec3::new_with_values(0.0, 1.0, 0.0) } else { Vec3::new_with_values(1.0, 0.0, 0.0) };
+        let v = Vec3::cross(w, a).unit_vector();
+        let u = Vec3::cross(w, v);
+        Onb { axis: [u, v, w] }
+    }
+
+    pub(crate) fn local(&self, a: f64, b: f64, c: f64) -> Vec3 {
+        self.axis[0] * a + self.axis[1] * b + self.axis[2] * c
+    }
+}
@@ -0,0 +1,896 @@
+use crate::aov::{AOVS, LIGHTING_AOVS};
+use crate::film::CropWindow;
+use crate::spectrum::Ior;
+use crate::vec3::Vec3;
+use crate::rtweekend::degrees_to_radians;
+
+// Settings that can be changed from the command line without recompiling.
+#[derive(Clone)]
+pub(crate) struct Options {
+    pub env_map: Option<String>,
+    pub env_map_space: String,
+    pub env_rotation: f64,
+    pub env_intensity: f64,
+    pub sky: String,
+    pub sun_elevation: f64,
+    pub sun_azimuth: f64,
+    pub sun_intensity: f64,
+    pub turbidity: f64,
+    pub scene: String,
+    pub glass: Ior,
+    pub camera: String,
+    pub fov: Option<f64>,
+    pub fisheye: String,
+    pub stereo: String,
+    pub ipd: f64,
+    pub lens: Option<String>,
+    pub film_diagonal: f64,
+    pub stop_diameter: Option<f64>,
+    pub aperture_blades: usize,
+    pub aperture_rotation: f64,
+    pub iso: Option<f64>,
+    pub shutter: Option<f64>,
+    pub f_stop: Option<f64>,
+    pub focal_length: Option<f64>,
+    pub sensor_width: f64,
+    pub focus_distance: Option<f64>,
+    pub autofocus: bool,
+    pub focus_pixel: Option<(usize, usize)>,
+    pub focus_object: Option<isize>,
+    pub animation: Option<String>,
+    pub frames: Option<(usize, usize)>,
+    pub fps: f64,
+    pub integrator: String,
+    pub sampler: String,
+    pub filter: String,
+    pub filter_radius: Option<f64>,
+    pub adaptive_threshold: Option<f64>,
+    pub max_samples: usize,
+    pub sample_map: Option<String>,
+    pub output: Option<String>,
+    pub crop: Option<[f64; 4]>,
+    pub crop_output: String,
+    pub merge: Option<String>,
+    pub progressive: bool,
+    pub checkpoint_every: Option<usize>,
+    pub checkpoint_seconds: Option<f64>,
+    pub time_limit: Option<f64>,
+    pub samples: Option<usize>,
+    pub seed: u64,
+    pub threads: Option<usize>,
+    pub tile_size: usize,
+    pub tile_order: String,
+    pub listen: Option<String>,
+    pub worker: Option<String>,
+    // Seconds the coordinator waits for a worker's next tile before handing its tiles to others
+    pub worker_timeout: f64,
+    // The command line as given, for workers to render from
+    pub args: Vec<String>,
+    pub checkpoint: Option<String>,
+    pub resume: Option<String>,
+    // The arguments that decide what the image looks like, saved with checkpoints
+    pub settings: Vec<String>,
+    pub denoise: bool,
+    pub noisy_output: Option<String>,
+    pub aovs: Vec<String>,
+    pub aov_output: Option<String>,
+    pub transparent: bool,
+    pub spectral: bool,
+    pub exposure: f64,
+    pub white_balance: Option<f64>,
+    pub tonemap: String,
+    pub transfer: String,
+    pub dither: bool,
+    pub bloom: f64,
+    pub bloom_radius: Option<f64>,
+    pub bloom_threshold: f64,
+    pub streaks: usize,
+    pub streak_length: Option<f64>,
+    pub streak_strength: f64,
+    pub chromatic_aberration: f64,
+    pub vignette: f64,
+    pub grain: f64,
+    pub max_depth: Option<usize>,
+    pub rr_depth: usize,
+    pub ao_samples: usize,
+    pub ao_distance: f64,
+    pub depth_range: f64,
+    pub photons: usize,
+    pub caustic_photons: usize,
+    pub photon_lookup: usize,
+    pub photon_radius: f64,
+    pub mlt_bootstrap: usize,
+    pub mlt_chains: usize,
+    pub mlt_sigma: f64,
+    pub mlt_large_step: f64,
+}
+
+const USAGE: &str = "Usage: ray_tracer [options] > image.ppm
+
+Scene:
+  --scene <name>            random (the book's final scene), bouncing (the same with the small diffuse
+                            spheres bouncing, for motion blur) or caustic (a glass ball lit by a lamp)
+                            (default random)
+  --glass <ior|name>        Refractive index of the glass, or a dispersive glass: bk7, sf11, diamond
+                            or cauchy:<a>,<b> with n = a + b / λ² in micrometres (default 1.5)
+
+Camera:
+  --camera <name>           perspective, orthographic, equirect (360° panorama), fisheye, ods
+                            (360° omni-directional stereo) or realistic (default perspective)
+  --fov <deg>               Vertical field of view, or across the circle for fisheye (default 20, fisheye 180)
+  --fisheye <mapping>       equidistant or equisolid (default equidistant)
+  --stereo <layout>         ods eye layout: over-under (left eye on top) or side-by-side (default over-under)
+  --ipd <f>                 ods distance between the eyes in scene units (default 0.064)
+  --lens <file>             realistic lens prescription, as in lenses/dgauss.50mm.dat
+  --film-diagonal <mm>      realistic film size (default 35)
+  --stop-diameter <mm>      realistic aperture stop opening (default fully open)
+  --aperture-blades <n>     Polygonal aperture with n blades for shaped bokeh (default round)
+  --aperture-rotation <deg> Rotation of the aperture blades
+  --focus-distance <f>      Distance to the plane in focus (default 10)
+  --autofocus               Focus on whatever is in the middle of the image
+  --focus-pixel <x>,<y>     perspective: focus on whatever is at this pixel, counted from the top left
+  --focus-object <n>        Focus on the nth object the scene adds, or from the end when negative
+
+Exposure (any of these exposes the sky as daylight at ISO 100, 1/100 s and f/16):
+  --iso <n>                 Film speed (default 100)
+  --shutter <s>             Shutter time in seconds, such as 1/60, which also blurs moving objects
+                            (default 1/100, without motion blur)
+  --f-stop <n>              perspective f-number, which also sets the aperture (default 16)
+  --focal-length <mm>       perspective focal length, setting the field of view with the sensor
+  --sensor-width <mm>       perspective sensor width (default 36)
+
+Animation:
+  --frames <a>-<b>          Render frames a to b, or just frame a, to numbered files, skipping files
+                            that exist. Any # in --output and the other file names become the frame
+                            number, or else it goes before the extension
+  --animation <file>        Keyframed camera and object motion (see src/animation.rs)
+  --fps <f>                 Frames per second, for how far objects move while the shutter is open (default 24)
+
+Background:
+  --env-map <file>          Equirectangular .hdr, .exr, .pfm or 8-bit .ppm environment map
+  --env-map-space <name>    auto, srgb or linear encoding of the map's values; auto takes .ppm
+                            to be sRGB and the others linear (default auto)
+  --env-rotation <deg>      Rotation of the environment map around the up axis
+  --env-intensity <f>       Environment map / sky radiance scale (default 1)
+  --sky <gradient|preetham> Background when no map is given (default gradient)
+  --sun-elevation <deg>     Preetham sun elevation above the horizon (default 35)
+  --sun-azimuth <deg>       Preetham sun azimuth around the up axis (default 60)
+  --sun-intensity <f>       Preetham sun irradiance relative to the sky (default 3)
+  --turbidity <f>           Preetham atmospheric turbidity, 2 to 10 (default 3)
+
+Image:
+  --spectral                Trace wavelengths instead of RGB, showing dispersion; path and direct only
+  --transparent             Make the background transparent, including where it is seen through glass,
+                            and write alpha to .png and .exr output
+  --filter <name>           Pixel filter: box, tent, gaussian, mitchell or lanczos (default box)
+  --filter-radius <f>       Filter radius in pixels (default 0.5, 1, 1.5, 2 and 3 respectively)
+  --adaptive <f>            Keep sampling pixels whose relative error is above this, e.g. 0.02
+  --max-samples <n>         Most samples an adaptive pixel may take (default 256)
+  --sample-map <file>       Also write a PPM heat map of the samples taken per pixel
+  --denoise                 Denoise the final image, guided by the surface colours and normals
+  --noisy-output <file>     Also write the image as it was before denoising (implies --denoise)
+  --aov <names|all>         Render passes to output, comma separated: depth, normal, albedo, position,
+                            object-id, material-id, direct-diffuse, indirect-diffuse, direct-specular,
+                            indirect-specular, emission and alpha. The lighting passes need --integrator path
+  --aov-output <file>       A multi-layer .exr with the image and the passes, or else a prefix for
+                            one .pfm per pass named <prefix>.<pass>.pfm (default all passes)
+
+Output:
+  --output <file>           Write the image to a file instead of standard output
+  --crop <x0>,<y0>,<x1>,<y1>  Only render this window, in pixels from the top left (ends excluded)
+                            or, when every value is at most 1, in fractions of the image
+  --crop-output <full|crop> Write the full image with black around the window, or just the window
+                            (default full)
+  --merge <file>            Fill in around the window from an earlier .pfm, .hdr or .exr render
+  --exposure <stops>        Brighten or darken the image by this many stops (default 0)
+  --white-balance <kelvin>  Colour temperature of the light to turn white, e.g. 3200 for tungsten
+  --tonemap <name>          clamp, reinhard, aces or agx (default clamp)
+  --transfer <name>         Display encoding: gamma2, srgb, rec709 or linear (default gamma2)
+  --dither                  Add noise before 8-bit quantisation to hide banding
+
+Post effects (not applied to .exr output or render passes):
+  --bloom <f>               Fraction of the light above the threshold spread into a glow, e.g. 0.3
+  --bloom-radius <px>       Size of the glow (default 1/50 of the image width)
+  --bloom-threshold <f>     Luminance above which light blooms and streaks (default 1)
+  --streaks <n>             Star streaks around highlights, e.g. 4 or 6
+  --streak-length <px>      Length of the streaks (default 1/10 of the image width)
+  --streak-strength <f>     Fraction of the light above the threshold spread into streaks (default 0.05)
+  --chromatic-aberration <px>  Red and blue fringes this many pixels apart in the corners
+  --vignette <f>            Darken the corners to 1 / (1 + f)² of the centre, e.g. 0.5
+  --grain <f>               Film grain, as a fraction of the brightness, e.g. 0.05
+  --progressive             Render the whole image one sample per pixel at a time
+  --checkpoint-every <n>    Rewrite the output file every n passes (implies --progressive)
+  --checkpoint-seconds <t>  Rewrite the output file at most every t seconds (implies --progressive)
+  --time-limit <t>          Stop after the pass that runs past t, e.g. 90, 90s, 10m or 2h (implies --progressive)
+  --samples <n>             Samples per pixel (default SAMPLES_PER_PIXEL in main.rs)
+  --checkpoint <file>       Save the render state to resume from at every checkpoint and at the end (implies --progressive)
+  --resume <file>           Continue a render from its checkpoint, keeping its settings, up to --samples
+  --seed <n>                Seed for the random numbers, random scene included (default 0)
+
+Threads (sppm renders on one):
+  --threads <n>             Worker threads (default one per CPU)
+  --tile-size <px>          Width and height of the tiles the image is split into (default 32)
+  --tile-order <name>       Order the tiles are started in: scanline, spiral (outwards from the middle)
+                            or hilbert (default spiral)
+
+Distributed rendering:
+  --listen <address>        Coordinate the render: wait for workers on this address, e.g. 0.0.0.0:7878,
+                            hand them the tiles and write the image as usual
+  --worker <address>        Render tiles for the coordinator at this address with the settings and
+                            files it sends, until it's done. Only --threads applies
+  --worker-timeout <t>      How long the coordinator waits for a worker's next tile before giving its
+                            tiles to the others, in seconds or with an m or h suffix (default 10m)
+
+Integrator:
+  --integrator <name>       path, bdpt, mlt, photon, sppm, direct, ao, normals, depth, uv or material-id (default path)
+  --max-depth <n>           Hard limit on the number of bounces (default 64, 8 for bdpt)
+  --sampler <name>          independent, stratified, halton, sobol or blue-noise (default sobol)
+  --rr-depth <n>            Bounces before Russian roulette may end a path (default 3)
+  --ao-samples <n>          Occlusion rays per camera sample for ao (default 4)
+  --ao-distance <f>         Maximum occluder distance for ao (default 1)
+  --depth-range <f>         Distance that maps to black in the depth view (default 30)
+
+Photon mapping:
+  --caustic-photons <n>     Photons shot at specular objects for photon (default 200000)
+  --photon-lookup <n>       Nearest photons used per caustic estimate (default 50)
+  --photons <n>             Photons per sppm iteration, one per sample per pixel (default 100000)
+  --photon-radius <f>       Caustic search radius for photon, initial radius for sppm (default 0.25)
+
+Metropolis:
+  --mlt-bootstrap <n>       Paths traced to estimate the image brightness and seed chains (default 100000)
+  --mlt-chains <n>          Markov chains run side by side (default 1000)
+  --mlt-sigma <f>           Standard deviation of small mutations in primary sample space (default 0.01)
+  --mlt-large-step <f>      Probability of replacing the whole path instead (default 0.3)";
+
+// Options that only change how a run goes, not the image it makes. A resumed
+// render takes everything else from its checkpoint.
+const RUN_OPTIONS: [&str; 34] = [
+    "--output", "--progressive", "--checkpoint-every", "--checkpoint-seconds", "--time-limit", "--samples", "--checkpoint", "--resume", "--sample-map",
+    "--denoise", "--noisy-output", "--aov", "--aov-output", "--exposure", "--white-balance", "--tonemap", "--transfer", "--dither",
+    "--bloom", "--bloom-radius", "--bloom-threshold", "--streaks", "--streak-length", "--streak-strength", "--chromatic-aberration", "--vignette", "--grain",
+    "--iso", "--threads", "--tile-size", "--tile-order", "--listen", "--worker", "--worker-timeout",
+];
+
+// Options without a value
+const FLAGS: [&str; 6] = ["--progressive", "--denoise", "--transparent", "--dither", "--spectral", "--autofocus"];
+
+const INTEGRATORS: [&str; 11] = ["path", "bdpt", "mlt", "photon", "sppm", "direct", "ao", "normals", "depth", "uv", "material-id"];
+
+const SAMPLERS: [&str; 5] = ["independent", "stratified", "halton", "sobol", "blue-noise"];
+
+const FILTERS: [&str; 5] = ["box", "tent", "gaussian", "mitchell", "lanczos"];
+
+const CAMERAS: [&str; 6] = ["perspective", "orthographic", "equirect", "fisheye", "ods", "realistic"];
+
+const TILE_ORDERS: [&str; 3] = ["scanline", "spiral", "hilbert"];
+
+const TONEMAPS: [&str; 4] = ["clamp", "reinhard", "aces", "agx"];
+
+const TRANSFERS: [&str; 4] = ["gamma2", "srgb", "rec709", "linear"];
+
+const INPUT_SPACES: [&str; 3] = ["auto", "srgb", "linear"];
+
+impl Options {
+    pub(crate) fn new() -> Options {
+        Options {
+            env_map: None,
+            env_map_space: String::from("auto"),
+            env_rotation: 0.0,
+            env_intensity: 1.0,
+            sky: String::from("gradient"),
+            sun_elevation: 35.0,
+            sun_azimuth: 60.0,
+            sun_intensity: 3.0,
+            turbidity: 3.0,
+            scene: String::from("random"),
+            camera: String::from("perspective"),
+            fov: None,
+            fisheye: String::from("equidistant"),
+            stereo: String::from("over-under"),
+            ipd: 0.064,
+            lens: None,
+            film_diagonal: 35.0,
+            stop_diameter: None,
+            aperture_blades: 0,
+            aperture_rotation: 0.0,
+            iso: None,
+            shutter: None,
+            f_stop: None,
+            focal_length: None,
+            sensor_width: 36.0,
+            focus_distance: None,
+            autofocus: false,
+            focus_pixel: None,
+            focus_object: None,
+            animation: None,
+            frames: None,
+            fps: 24.0,
+            glass: Ior::Constant(1.5),
+            integrator: String::from("path"),
+            sampler: String::from("sobol"),
+            filter: String::from("box"),
+            filter_radius: None,
+            adaptive_threshold: None,
+            max_samples: 256,
+            sample_map: None,
+            output: None,
+            crop: None,
+            crop_output: String::from("full"),
+            merge: None,
+            progressive: false,
+            checkpoint_every: None,
+            checkpoint_seconds: None,
+            time_limit: None,
+            samples: None,
+            seed: 0,
+            threads: None,
+            tile_size: 32,
+            tile_order: String::from("spiral"),
+            listen: None,
+            worker: None,
+            worker_timeout: 600.0,
+            args: Vec::new(),
+            checkpoint: None,
+            resume: None,
+            settings: Vec::new(),
+            denoise: false,
+            noisy_output: None,
+            aovs: Vec::new(),
+            aov_output: None,
+            transparent: false,
+            spectral: false,
+            exposure: 0.0,
+            white_balance: None,
+            tonemap: String::from("clamp"),
+            transfer: String::from("gamma2"),
+            dither: false,
+            bloom: 0.0,
+            bloom_radius: None,
+            bloom_threshold: 1.0,
+            streaks: 0,
+            streak_length: None,
+            streak_strength: 0.05,
+            chromatic_aberration: 0.0,
+            vignette: 0.0,
+            grain: 0.0,
+            max_depth: None,
+            rr_depth: 3,
+            ao_samples: 4,
+            ao_distance: 1.0,
+            depth_range: 30.0,
+            photons: 100000,
+            caustic_photons: 200000,
+            photon_lookup: 50,
+            photon_radius: 0.25,
+            mlt_bootstrap: 100000,
+            mlt_chains: 1000,
+            mlt_sigma: 0.01,
+            mlt_large_step: 0.3,
+        }
+    }
+
+    // Parses the process arguments, printing usage and exiting on errors.
+    pub(crate) fn from_args() -> Options {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        exit_on_error(Options::parse(&args))
+    }
+
+    // The command line applied on top of the settings a checkpoint was saved
+    // with, which can't be changed any more.
+    pub(crate) fn from_args_resuming(settings: &[String]) -> Options {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if let Some(arg) = image_settings(&args).first() {
+            exit_on_error(Err(format!("{} can't be changed when resuming", arg)));
+        }
+        let mut combined = settings.to_vec();
+        combined.extend(args);
+        exit_on_error(Options::parse(&combined))
+    }
+
+    pub(crate) fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::new();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next().cloned().ok_or_else(|| format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--help" | "-h" => return Err(String::from("Ray tracer options")),
+                "--env-map" => options.env_map = Some(value()?),
+                "--env-map-space" => {
+                    options.env_map_space = value()?;
+                    if !INPUT_SPACES.contains(&options.env_map_space.as_str()) {
+                        return Err(format!("Unknown colour encoding: {}", options.env_map_space));
+                    }
+                }
+                "--env-rotation" => options.env_rotation = parse_number(arg, &value()?)?,
+                "--env-intensity" => options.env_intensity = parse_number(arg, &value()?)?,
+                "--sky" => {
+                    options.sky = value()?;
+                    if options.sky != "gradient" && options.sky != "preetham" {
+                        return Err(format!("Unknown sky model: {}", options.sky));
+                    }
+                }
+                "--sun-elevation" => options.sun_elevation = parse_number(arg, &value()?)?,
+                "--sun-azimuth" => options.sun_azimuth = parse_number(arg, &value()?)?,
+                "--sun-intensity" => options.sun_intensity = parse_number(arg, &value()?)?,
+                "--turbidity" => options.turbidity = parse_number(arg, &value()?)?,
+                "--scene" => {
+                    options.scene = value()?;
+                    if options.scene != "random" && options.scene != "bouncing" && options.scene != "caustic" {
+                        return Err(format!("Unknown scene: {}", options.scene));
+                    }
+                }
+                "--glass" => {
+                    let glass = value()?;
+                    options.glass = Ior::parse(&glass).ok_or_else(|| format!("Unknown glass: {}", glass))?;
+                }
+                "--camera" => {
+                    options.camera = value()?;
+                    if !CAMERAS.contains(&options.camera.as_str()) {
+                        return Err(format!("Unknown camera: {}", options.camera));
+                    }
+                }
+                "--fov" => {
+                    let fov = parse_positive(arg, &value()?)?;
+                    if fov >= 360.0 {
+                        return Err(format!("Field of view must be under 360 degrees: {}", fov));
+                    }
+                    options.fov = Some(fov);
+                }
+                "--fisheye" => {
+                    options.fisheye = value()?;
+                    if options.fisheye != "equidistant" && options.fisheye != "equisolid" {
+                        return Err(format!("Unknown fisheye mapping: {}", options.fisheye));
+                    }
+                }
+                "--stereo" => {
+                    options.stereo = value()?;
+                    if options.stereo != "over-under" && options.stereo != "side-by-side" {
+                        return Err(format!("Unknown stereo layout: {}", options.stereo));
+                    }
+                }
+                "--ipd" => options.ipd = parse_non_negative(arg, &value()?)?,
+                "--lens" => options.lens = Some(value()?),
+                "--film-diagonal" => options.film_diagonal = parse_positive(arg, &value()?)?,
+                "--stop-diameter" => options.stop_diameter = Some(parse_positive(arg, &value()?)?),
+                "--aperture-blades" => {
+                    options.aperture_blades = parse_count(arg, &value()?)?;
+                    if options.aperture_blades < 3 {
+                        return Err(format!("An aperture needs at least 3 blades: {}", options.aperture_blades));
+                    }
+                }
+                "--aperture-rotation" => options.aperture_rotation = parse_number(arg, &value()?)?,
+                "--iso" => options.iso = Some(parse_positive(arg, &value()?)?),
+                "--shutter" => options.shutter = Some(parse_shutter(arg, &value()?)?),
+                "--f-stop" => options.f_stop = Some(parse_positive(arg, &value()?)?),
+                "--focal-length" => options.focal_length = Some(parse_positive(arg, &value()?)?),
+                "--sensor-width" => options.sensor_width = parse_positive(arg, &value()?)?,
+                "--focus-distance" => options.focus_distance = Some(parse_positive(arg, &value()?)?),
+                "--autofocus" => options.autofocus = true,
+                "--focus-pixel" => {
+                    let pixel = value()?;
+                    match pixel.split_once(',').map(|(x, y)| (x.trim().parse(), y.trim().parse())) {
+                        Some((Ok(x), Ok(y))) => options.focus_pixel = Some((x, y)),
+                        _ => return Err(format!("Expected a pixel like 960,540 for {}: {}", arg, pixel)),
+                    }
+                    options.autofocus = true;
+                }
+                "--focus-object" => {
+                    let object = value()?;
+                    options.focus_object = Some(object.parse().map_err(|_| format!("Invalid object for {}: {}", arg, object))?);
+                }
+                "--crop" => {
+                    let window = value()?;
+                    let values: Vec<f64> = window.split(',').map(|v| v.trim().parse::<f64>()).collect::<Result<_, _>>().map_err(|_| format!("Invalid crop window for {}: {}", arg, window))?;
+                    match values[..] {
+                        [x0, y0, x1, y1] if x0 >= 0.0 && y0 >= 0.0 && x0 < x1 && y0 < y1 => options.crop = Some([x0, y0, x1, y1]),
+                        _ => return Err(format!("Expected a crop window like 100,50,400,300 for {}: {}", arg, window)),
+                    }
+                }
+                "--crop-output" => {
+                    options.crop_output = value()?;
+                    if options.crop_output != "full" && options.crop_output != "crop" {
+                        return Err(format!("Unknown crop output: {}", options.crop_output));
+                    }
+                }
+                "--merge" => options.merge = Some(value()?),
+                "--animation" => options.animation = Some(value()?),
+                "--frames" => {
+                    let frames = value()?;
+                    let (first, last) = frames.split_once('-').unwrap_or((&frames, &frames));
+                    match (first.parse::<usize>(), last.parse::<usize>()) {
+                        (Ok(first), Ok(last)) if first <= last => options.frames = Some((first, last)),
+                        _ => return Err(format!("Invalid frame range for {}: {}", arg, frames)),
+                    }
+                }
+                "--fps" => options.fps = parse_positive(arg, &value()?)?,
+                "--integrator" => {
+                    options.integrator = value()?;
+                    if !INTEGRATORS.contains(&options.integrator.as_str()) {
+                        return Err(format!("Unknown integrator: {}", options.integrator));
+                    }
+                }
+                "--sampler" => {
+                    options.sampler = value()?;
+                    if !SAMPLERS.contains(&options.sampler.as_str()) {
+                        return Err(format!("Unknown sampler: {}", options.sampler));
+                    }
+                }
+                "--filter" => {
+                    options.filter = value()?;
+                    if !FILTERS.contains(&options.filter.as_str()) {
+                        return Err(format!("Unknown filter: {}", options.filter));
+                    }
+                }
+                "--filter-radius" => {
+                    let radius = parse_number(arg, &value()?)?;
+                    if radius <= 0.0 {
+                        return Err(format!("Filter radius must be positive: {}", radius));
+                    }
+                    options.filter_radius = Some(radius);
+                }
+                "--adaptive" => options.adaptive_threshold = Some(parse_number(arg, &value()?)?),
+                "--max-samples" => options.max_samples = parse_count(arg, &value()?)?,
+                "--sample-map" => options.sample_map = Some(value()?),
+                "--transparent" => options.transparent = true,
+                "--spectral" => options.spectral = true,
+                "--denoise" => options.denoise = true,
+                "--noisy-output" => {
+                    options.noisy_output = Some(value()?);
+                    options.denoise = true;
+                }
+                "--aov" => {
+                    let names = value()?;
+                    options.aovs = if names == "all" {
+                        AOVS.iter().map(|name| name.to_string()).collect()
+                    } else {
+                        names.split(',').map(|name| name.trim().to_string()).collect()
+                    };
+                    if let Some(name) = options.aovs.iter().find(|name| !AOVS.contains(&name.as_str())) {
+                        return Err(format!("Unknown AOV: {}", name));
+                    }
+                }
+                "--aov-output" => options.aov_output = Some(value()?),
+                "--output" => options.output = Some(value()?),
+                "--exposure" => options.exposure = parse_number(arg, &value()?)?,
+                "--white-balance" => {
+                    let kelvin = parse_number(arg, &value()?)?;
+                    if !(1667.0..=25000.0).contains(&kelvin) {
+                        return Err(format!("White balance must be between 1667 and 25000 K: {}", kelvin));
+                    }
+                    options.white_balance = Some(kelvin);
+                }
+                "--tonemap" => {
+                    options.tonemap = value()?;
+                    if !TONEMAPS.contains(&options.tonemap.as_str()) {
+                        return Err(format!("Unknown tone mapping operator: {}", options.tonemap));
+                    }
+                }
+                "--transfer" => {
+                    options.transfer = value()?;
+                    if !TRANSFERS.contains(&options.transfer.as_str()) {
+                        return Err(format!("Unknown transfer function: {}", options.transfer));
+                    }
+                }
+                "--dither" => options.dither = true,
+                "--bloom" => options.bloom = parse_fraction(arg, &value()?)?,
+                "--bloom-radius" => options.bloom_radius = Some(parse_positive(arg, &value()?)?),
+                "--bloom-threshold" => options.bloom_threshold = parse_non_negative(arg, &value()?)?,
+                "--streaks" => options.streaks = parse_count(arg, &value()?)?,
+                "--streak-length" => options.streak_length = Some(parse_positive(arg, &value()?)?),
+                "--streak-strength" => options.streak_strength = parse_fraction(arg, &value()?)?,
+                "--chromatic-aberration" => options.chromatic_aberration = parse_number(arg, &value()?)?,
+                "--vignette" => options.vignette = parse_non_negative(arg, &value()?)?,
+                "--grain" => options.grain = parse_non_negative(arg, &value()?)?,
+                "--progressive" => options.progressive = true,
+                "--checkpoint-every" => {
+                    options.checkpoint_every = Some(parse_count(arg, &value()?)?);
+                    options.progressive = true;
+                }
+                "--checkpoint-seconds" => {
+                    options.checkpoint_seconds = Some(parse_duration(arg, &value()?)?);
+                    options.progressive = true;
+                }
+                "--time-limit" => {
+                    options.time_limit = Some(parse_duration(arg, &value()?)?);
+                    options.progressive = true;
+                }
+                "--samples" => options.samples = Some(parse_count(arg, &value()?)?),
+                "--seed" => {
+                    let seed = value()?;
+                    options.seed = seed.parse().map_err(|_| format!("Invalid seed for {}: {}", arg, seed))?;
+                }
+                "--threads" => options.threads = Some(parse_count(arg, &value()?)?),
+                "--tile-size" => options.tile_size = parse_count(arg, &value()?)?,
+                "--listen" => options.listen = Some(value()?),
+                "--worker" => options.worker = Some(value()?),
+                "--worker-timeout" => options.worker_timeout = parse_duration(arg, &value()?)?,
+                "--tile-order" => {
+                    options.tile_order = value()?;
+                    if !TILE_ORDERS.contains(&options.tile_order.as_str()) {
+                        return Err(format!("Unknown tile order: {}", options.tile_order));
+                    }
+                }
+                "--checkpoint" => {
+                    options.checkpoint = Some(value()?);
+                    options.progressive = true;
+                }
+                "--resume" => {
+                    options.resume = Some(value()?);
+                    options.progressive = true;
+                }
+                "--max-depth" => options.max_depth = Some(parse_count(arg, &value()?)?),
+                "--rr-depth" => options.rr_depth = parse_count(arg, &value()?)?,
+                "--ao-samples" => options.ao_samples = parse_count(arg, &value()?)?,
+                "--ao-distance" => options.ao_distance = parse_number(arg, &value()?)?,
+                "--depth-range" => options.depth_range = parse_number(arg, &value()?)?,
+                "--photons" => options.photons = parse_count(arg, &value()?)?,
+                "--caustic-photons" => options.caustic_photons = parse_count(arg, &value()?)?,
+                "--photon-lookup" => options.photon_lookup = parse_count(arg, &value()?)?,
+                "--photon-radius" => options.photon_radius = parse_positive(arg, &value()?)?,
+                "--mlt-bootstrap" => options.mlt_bootstrap = parse_count(arg, &value()?)?,
+                "--mlt-chains" => options.mlt_chains = parse_count(arg, &value()?)?,
+                "--mlt-sigma" => options.mlt_sigma = parse_number(arg, &value()?)?,
+                "--mlt-large-step" => options.mlt_large_step = parse_number(arg, &value()?)?,
+                _ => return Err(format!("Unknown option: {}", arg)),
+            }
+        }
+
+        // A resumed render keeps saving to the checkpoint it came from
+        if options.checkpoint.is_none() {
+            options.checkpoint = options.resume.clone();
+        }
+        if options.output.is_none() && options.checkpoint.is_none() && (options.checkpoint_every.is_some() || options.checkpoint_seconds.is_some()) {
+            return Err(String::from("Checkpoints need an --output or --checkpoint file to write to"));
+        }
+        // mlt's Markov chains aren't saved, and restarting them wouldn't give the same image
+        if (options.integrator == "mlt" || options.integrator == "sppm") && options.checkpoint.is_some() {
+            return Err(format!("{} renders can't be checkpointed", options.integrator));
+        }
+        if options.aov_output.is_some() && options.aovs.is_empty() {
+            options.aovs = AOVS.iter().map(|name| name.to_string()).collect();
+        }
+        if !options.aovs.is_empty() {
+            if options.aov_output.is_none() {
+                return Err(String::from("AOVs need an --aov-output file"));
+            }
+            if options.integrator == "sppm" {
+                return Err(String::from("sppm renders can't output AOVs"));
+            }
+            if options.integrator != "path" && options.aovs.iter().any(|name| LIGHTING_AOVS.contains(&name.as_str())) {
+                return Err(String::from("The lighting AOVs need --integrator path"));
+            }
+        }
+        if options.transparent && (options.integrator == "bdpt" || options.integrator == "sppm") {
+            return Err(format!("{} can't render a transparent background", options.integrator));
+        }
+        if options.camera != "perspective" && (options.integrator == "bdpt" || options.integrator == "mlt") {
+            return Err(format!("{} needs the perspective camera", options.integrator));
+        }
+        if options.camera == "realistic" && options.integrator == "sppm" {
+            return Err(String::from("sppm can't render through a realistic lens"));
+        }
+        if options.camera == "realistic" && options.lens.is_none() {
+            return Err(String::from("The realistic camera needs --lens"));
+        }
+        if (options.f_stop.is_some() || options.focal_length.is_some()) && options.camera != "perspective" {
+            return Err(String::from("--f-stop and --focal-length need the perspective camera"));
+        }
+        if options.focal_length.is_some() && options.fov.is_some() {
+            return Err(String::from("Give either --fov or --focal-length"));
+        }
+        let focus_options = options.focus_distance.is_some() as usize + options.autofocus as usize + options.focus_object.is_some() as usize;
+        if focus_options > 1 {
+            return Err(String::from("Give only one of --focus-distance, --autofocus, --focus-pixel and --focus-object"));
+        }
+        if focus_options > 0 && !["perspective", "orthographic", "realistic"].contains(&options.camera.as_str()) {
+            return Err(format!("The {} camera has no focus", options.camera));
+        }
+        if options.focus_pixel.is_some() && options.camera != "perspective" {
+            return Err(String::from("--focus-pixel needs the perspective camera"));
+        }
+        if options.crop.is_some() && (options.integrator == "mlt" || options.integrator == "sppm") {
+            return Err(format!("{} renders the whole image at once and can't crop it", options.integrator));
+        }
+        if options.merge.is_some() && (options.crop.is_none() || options.crop_output != "full") {
+            return Err(String::from("--merge needs --crop and a full size --crop-output"));
+        }
+        // 8-bit images have been through the display transform, which can't be undone
+        if options.merge.as_ref().is_some_and(|path| !["pfm", "hdr", "exr"].iter().any(|ext| path.to_ascii_lowercase().ends_with(&format!(".{}", ext)))) {
+            return Err(String::from("--merge needs a linear .pfm, .hdr or .exr render"));
+        }
+        // More than all of the light above the threshold can't be spread around
+        if options.streaks > 0 && options.bloom + options.streak_strength > 1.0 {
+            return Err(String::from("--bloom and --streak-strength can't add up to more than 1"));
+        }
+        if options.frames.is_some() && options.output.is_none() {
+            return Err(String::from("--frames needs --output"));
+        }
+        if options.frames.is_some() && (options.checkpoint.is_some() || options.resume.is_some() || options.checkpoint_every.is_some() || options.checkpoint_seconds.is_some()) {
+            return Err(String::from("Frames are resumed by skipping finished files, not with checkpoints"));
+        }
+        if options.animation.is_some() && options.frames.is_none() {
+            return Err(String::from("--animation needs --frames"));
+        }
+        if options.spectral && options.integrator != "path" && options.integrator != "direct" {
+            return Err(String::from("Spectral rendering needs --integrator path or direct"));
+        }
+        if options.listen.is_some() {
+            if options.progressive || options.resume.is_some() {
+                return Err(String::from("Distributed renders can't be progressive or resumed"));
+            }
+            if options.integrator == "mlt" || options.integrator == "sppm" {
+                return Err(format!("{} renders the whole image at once and can't be distributed", options.integrator));
+            }
+        }
+        options.settings = image_settings(args);
+        options.args = args.to_vec();
+
+        Ok(options)
+    }
+
+    // The pixels --crop covers in a width x height image
+    pub(crate) fn crop_window(&self, width: usize, height: usize) -> Option<CropWindow> {
+        let [x0, y0, x1, y1] = self.crop?;
+        let fractions = [x0, y0, x1, y1].iter().all(|&v| v <= 1.0);
+        let (sx, sy) = if fractions { (width as f64, height as f64) } else { (1.0, 1.0) };
+        let window = CropWindow {
+            x0: ((x0 * sx).floor() as usize).min(width),
+            y0: ((y0 * sy).floor() as usize).min(height),
+            x1: ((x1 * sx).ceil() as usize).min(width),
+            y1: ((y1 * sy).ceil() as usize).min(height),
+        };
+        if window.x0 >= window.x1 || window.y0 >= window.y1 {
+            exit_with_error(&format!("The crop window is outside the {}x{} image", width, height));
+        }
+        Some(window)
+    }
+
+    // The options for one frame of a sequence, with its number in the file names
+    pub(crate) fn for_frame(&self, frame: usize) -> Options {
+        let mut options = self.clone();
+        for path in [&mut options.output, &mut options.noisy_output, &mut options.aov_output, &mut options.sample_map].into_iter().flatten() {
+            *path = frame_path(path, frame);
+        }
+        options
+    }
+
+    pub(crate) fn sun_direction(&self) -> Vec3 {
+        let elevation = degrees_to_radians(self.sun_elevation);
+        let azimuth = degrees_to_radians(self.sun_azimuth);
+        Vec3::new_with_values(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin())
+    }
+}
+
+fn exit_on_error(options: Result<Options, String>) -> Options {
+    options.unwrap_or_else(|message| exit_with_error(&message))
+}
+
+// Reports a mistake in the options, for ones that can only be checked once
+// the image is set up
+pub(crate) fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    std::process::exit(1);
+}
+
+// The arguments left once the run options are taken out. Every option but
+// the flags takes a single value.
+fn image_settings(args: &[String]) -> Vec<String> {
+    let mut settings = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = if FLAGS.contains(&arg.as_str()) { None } else { iter.next() };
+        if !RUN_OPTIONS.contains(&arg.as_str()) {
+            settings.push(arg.clone());
+            settings.extend(value.cloned());
+        }
+    }
+    settings
+}
+
+fn parse_number(arg: &str, value: &str) -> Result<f64, String> {
+    value.parse().map_err(|_| format!("Invalid number for {}: {}", arg, value))
+}
+
+fn parse_positive(arg: &str, value: &str) -> Result<f64, String> {
+    match parse_number(arg, value)? {
+        n if n > 0.0 => Ok(n),
+        _ => Err(format!("Expected a positive number for {}: {}", arg, value)),
+    }
+}
+
+fn parse_non_negative(arg: &str, value: &str) -> Result<f64, String> {
+    match parse_number(arg, value)? {
+        n if n >= 0.0 => Ok(n),
+        _ => Err(format!("Expected a number of at least 0 for {}: {}", arg, value)),
+    }
+}
+
+fn parse_fraction(arg: &str, value: &str) -> Result<f64, String> {
+    match parse_number(arg, value)? {
+        n if (0.0..=1.0).contains(&n) => Ok(n),
+        _ => Err(format!("Expected a number from 0 to 1 for {}: {}", arg, value)),
+    }
+}
+
+fn parse_count(arg: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("Expected a positive integer for {}: {}", arg, value)),
+    }
+}
+
+// Replaces the first run of # with the frame number, padded to its length,
+// or else adds the number before the extension
+fn frame_path(path: &str, frame: usize) -> String {
+    match path.find('#') {
+        Some(start) => {
+            let digits = path[start..].chars().take_while(|&c| c == '#').count();
+            format!("{}{:0width$}{}", &path[..start], frame, &path[start + digits..], width = digits)
+        }
+        None => match path.rfind('.').filter(|&dot| !path[dot..].contains('/')) {
+            Some(dot) => format!("{}_{:04}{}", &path[..dot], frame, &path[dot..]),
+            None => format!("{}_{:04}", path, frame),
+        },
+    }
+}
+
+// Seconds, or a fraction of a second like 1/125
+fn parse_shutter(arg: &str, value: &str) -> Result<f64, String> {
+    let seconds = match value.split_once('/') {
+        Some((a, b)) => a.parse::<f64>().ok().zip(b.parse::<f64>().ok()).map(|(a, b)| a / b),
+        None => value.parse().ok(),
+    };
+    match seconds {
+        Some(t) if t > 0.0 && t.is_finite() => Ok(t),
+        _ => Err(format!("Invalid shutter time for {}: {}", arg, value)),
+    }
+}
+
+// Seconds, or minutes and hours with an m or h suffix
+fn parse_duration(arg: &str, value: &str) -> Result<f64, String> {
+    let (number, scale) = match value.chars().last() {
+        Some('s') => (&value[..value.len() - 1], 1.0),
+        Some('m') => (&value[..value.len() - 1], 60.0),
+        Some('h') => (&value[..value.len() - 1], 3600.0),
+        _ => (value, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(n * scale),
+        _ => Err(format!("Invalid duration for {}: {}", arg, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutter_times_can_be_fractions() {
+        assert_eq!(parse_shutter("--shutter", "0.5"), Ok(0.5));
+        assert_eq!(parse_shutter("--shutter", "1/250"), Ok(1.0 / 250.0));
+        assert_eq!(parse_shutter("--shutter", "2"), Ok(2.0));
+    }
+
+    #[test]
+    fn shutter_times_must_be_positive() {
+        for value in ["", "0", "-1/60", "1/0", "1/", "/60", "1/60/2", "fast", "inf"] {
+            assert!(parse_shutter("--shutter", value).is_err(), "accepted {:?}", value);
+        }
+    }
+
+    #[test]
+    fn durations_take_a_unit_suffix() {
+        assert_eq!(parse_duration("--time-limit", "90"), Ok(90.0));
+        assert_eq!(parse_duration("--time-limit", "1.5s"), Ok(1.5));
+        assert_eq!(parse_duration("--time-limit", "2m"), Ok(120.0));
+        assert_eq!(parse_duration("--time-limit", "0.5h"), Ok(1800.0));
+    }
+
+    #[test]
+    fn durations_must_be_positive_numbers() {
+        for value in ["", "s", "0", "-3m", "10x", "m5", "infh", "NaN"] {
+            assert!(parse_duration("--time-limit", value).is_err(), "accepted {:?}", value);
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use crate::aabb::Aabb;
+use crate::hittable::{hit_record, Hittable};
+use crate::integrator::{Integrator, Splat};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::rtweekend::{self, PI};
+use crate::scene::Scene;
+use crate::vec3::{self, Color, Point3, Vec3};
+
+#[derive(Clone, Copy)]
+pub(crate) struct Photon {
+    pub p: Point3,
+    // Unit direction the photon was travelling in
+    pub dir: Vec3,
+    pub power: Color,
+}
+
+// Photons in a balanced kd-tree. The tree is implicit: the photon in the middle
+// of every range splits it, along the axis stored next to it.
+pub(crate) struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<usize>,
+}
+
+struct Neighbour {
+    dist2: f64,
+    index: usize,
+}
+
+impl PartialEq for Neighbour {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist2 == other.dist2
+    }
+}
+
+impl Eq for Neighbour {}
+
+impl PartialOrd for Neighbour {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbour {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.dist2.total_cmp(&other.dist2)
+    }
+}
+
+impl PhotonMap {
+    pub(crate) fn new_with_values(mut photons: Vec<Photon>) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap { photons, axes }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    // Up to `k` photons closest to `p` within sqrt(max_dist2), with the squared
+    // radius that encloses them: the distance to the farthest one when all k
+    // were found, otherwise the search radius itself.
+    pub(crate) fn nearest(&self, p: &Point3, k: usize, max_dist2: f64) -> (Vec<&Photon>, f64) {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        let mut radius2 = max_dist2;
+        self.search(0, self.photons.len(), p, k, &mut radius2, &mut heap);
+
+        let radius2 = if heap.len() == k { radius2 } else { max_dist2 };
+        (heap.into_iter().map(|n| &self.photons[n.index]).collect(), radius2)
+    }
+
+    fn search(&self, lo: usize, hi: usize, p: &Point3, k: usize, radius2: &mut f64, heap: &mut BinaryHeap<Neighbour>) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        let axis = self.axes[mid];
+        let delta = p[axis] - photon.p[axis];
+        let (near, far) = if delta < 0.0 { ((lo, mid), (mid + 1, hi)) } else { ((mid + 1, hi), (lo, mid)) };
+
+        self.search(near.0, near.1, p, k, radius2, heap);
+
+        let dist2 = (photon.p - *p).length_squared();
+        if dist2 < *radius2 {
+            heap.push(Neighbour { dist2, index: mid });
+            if heap.len() > k {
+                heap.pop();
+            }
+            if heap.len() == k {
+                *radius2 = heap.peek().unwrap().dist2;
+            }
+        }
+
+        if delta * delta < *radius2 {
+            self.search(far.0, far.1, p, k, radius2, heap);
+        }
+    }
+}
+
+// Splits around the median along the widest axis of the photons' bounds
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let mut min = photons[0].p;
+    let mut max = photons[0].p;
+    for photon in photons.iter() {
+        for a in 0..3 {
+            min[a] = min[a].min(photon.p[a]);
+            max[a] = max[a].max(photon.p[a]);
+        }
+    }
+    let extent = max - min;
+    let axis = if extent.x() > extent.y() && extent.x() > extent.z() { 0 } else if extent.y() > extent.z() { 1 } else { 2 };
+
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| a.p[axis].total_cmp(&b.p[axis]));
+    axes[mid] = axis;
+
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+// A sphere photons are aimed at, so they aren't wasted on parts of the scene
+// where they can't contribute.
+#[derive(Clone, Copy)]
+pub(crate) struct Target {
+    pub center: Point3,
+    pub radius: f64,
+}
+
+impl Target {
+    pub(crate) fn from_box(bounds: &Aabb) -> Target {
+        let center = (bounds.min() + bounds.max()) * 0.5;
+        Target { center, radius: (bounds.max() - center).length().max(1e-3) }
+    }
+
+    // Whether the line through `p` along unit `d` crosses the sphere
+    fn crossed_by(&self, p: &Point3, d: &Vec3) -> bool {
+        let oc = self.center - *p;
+        let along = Vec3::dot(&oc, d);
+        oc.length_squared() - along * along <= self.radius * self.radius
+    }
+
+    // Uniformly samples the cone of directions from `p` towards the sphere
+    fn sample_direction(&self, p: &Point3) -> Vec3 {
+        let to_center = self.center - *p;
+        let dist2 = to_center.length_squared();
+        if dist2 <= self.radius * self.radius {
+            return vec3::random_unit_vector();
+        }
+        let cos_max = (1.0 - self.radius * self.radius / dist2).sqrt();
+        let (r1, r2) = rtweekend::random_double_2d();
+        let z = 1.0 + r1 * (cos_max - 1.0);
+        let phi = 2.0 * PI * r2;
+        let sin = (1.0 - z * z).max(0.0).sqrt();
+        Onb::build_from_w(&to_center).local(phi.cos() * sin, phi.sin() * sin, z)
+    }
+
+    fn direction_pdf(&self, p: &Point3, d: &Vec3) -> f64 {
+        let to_center = self.center - *p;
+        let dist2 = to_center.length_squared();
+        if dist2 <= self.radius * self.radius {
+            return 1.0 / (4.0 * PI);
+        }
+        let cos_max = (1.0 - self.radius * self.radius / dist2).sqrt();
+        if Vec3::dot(d, &to_center.unit_vector()) < cos_max {
+            return 0.0;
+        }
+        1.0 / (2.0 * PI * (1.0 - cos_max))
+    }
+}
+
+// Emits a photon from a uniformly picked light, returning its ray and power.
+// Directions are drawn towards one of `targets`, or over the whole scene when
+// `include_scene` is set, and the pdf combines all of them so every path
+// through a target is still accounted for exactly once.
+pub(crate) fn emit_photon(scene: &Scene, targets: &[Target], include_scene: bool) -> Option<(Ray, Color)> {
+    let scene_target = Target { center: scene.center, radius: scene.radius };
+    let count = targets.len() + include_scene as usize;
+    if count == 0 {
+        return None;
+    }
+    scene.start_time();
+    let choice = ((rtweekend::random_double() * count as f64) as usize).min(count - 1);
+    let pick_pdf = scene.light_pick_pdf();
+    let pick = scene.pick_light();
+
+    if pick < scene.lights.len() {
+        let light = &scene.lights[pick];
+        let (p, n) = light.sample_point();
+        let direction = if choice < targets.len() {
+            targets[choice].sample_direction(&p)
+        } else {
+            let d = vec3::random_cosine_direction();
+            Onb::build_from_w(&n).local(d.x(), d.y(), d.z())
+        };
+
+        let cosine = Vec3::dot(&n, &direction);
+        let le = light.radiance(&n, &direction);
+        if cosine <= 0.0 || le.near_zero() {
+            return None;
+        }
+        let mut pdf_dir: f64 = targets.iter().map(|t| t.direction_pdf(&p, &direction)).sum();
+        if include_scene {
+            pdf_dir += cosine / PI;
+        }
+        pdf_dir /= count as f64;
+        return Some((Ray::new_with_values(p, direction), le * (cosine / (pick_pdf * light.pdf_position() * pdf_dir))));
+    }
+
+    // Environment photons cross a disk facing them that covers the chosen target
+    let (to_env, pdf_dir) = scene.environment.sample()?;
+    let to_env = to_env.unit_vector();
+    let le = scene.environment.value(&to_env);
+    if pdf_dir <= 0.0 || le.near_zero() {
+        return None;
+    }
+    let d = -to_env;
+    let target = if choice < targets.len() { targets[choice] } else { scene_target };
+    let disk = vec3::random_in_unit_disk();
+    let p = target.center + Onb::build_from_w(&d).local(disk.x(), disk.y(), 0.0) * target.radius;
+
+    let area_pdf = |t: &Target| if t.crossed_by(&p, &d) { 1.0 / (PI * t.radius * t.radius) } else { 0.0 };
+    let mut pdf_pos: f64 = targets.iter().map(area_pdf).sum();
+    if include_scene {
+        pdf_pos += area_pdf(&scene_target);
+    }
+    pdf_pos /= count as f64;
+
+    // Start outside the scene so everything along the way is hit
+    let oc = p - scene.center;
+    let b = Vec3::dot(&oc, &d);
+    let back = b + (b * b - oc.length_squared() + scene.radius * scene.radius).max(0.0).sqrt();
+    Some((Ray::new_with_values(p - d * back, d), le / (pick_pdf * pdf_dir * pdf_pos)))
+}
+
+// Spheres around every specular object, the only things caustic photons can go through first
+fn specular_targets(scene: &Scene) -> Vec<Target> {
+    let mut targets = Vec::new();
+    for object in scene.world.objects() {
+        let mut bounds = Aabb::new();
+        if object.is_specular() && object.bounding_box(&mut bounds) {
+            targets.push(Target::from_box(&bounds));
+        }
+    }
+    targets
+}
+
+// Photon mapping for caustics. Photons are shot at the mirrors and glass in the
+// scene and stored where they land on a diffuse surface after one or more
+// specular bounces. Rendering traces paths as the path tracer does, but at every
+// diffuse hit direct light is sampled explicitly and caustics are estimated from
+// the nearest photons; paths that reach a light by any other route are skipped
+// so nothing is counted twice.
+pub(crate) struct PhotonIntegrator {
+    max_depth: usize,
+    rr_depth: usize,
+    caustic_photons: usize,
+    lookup: usize,
+    max_radius: f64,
+    caustics: PhotonMap,
+}
+
+impl PhotonIntegrator {
+    pub(crate) fn new_with_values(max_depth: usize, rr_depth: usize, caustic_photons: usize, lookup: usize, max_radius: f64) -> PhotonIntegrator {
+        PhotonIntegrator {
+            max_depth,
+            rr_depth,
+            caustic_photons,
+            lookup,
+            max_radius,
+            caustics: PhotonMap::new_with_values(Vec::new()),
+        }
+    }
+
+    fn trace_caustic_photon(&self, scene: &Scene, targets: &[Target], photons: &mut Vec<Photon>) {
+        let (mut ray, mut power) = match emit_photon(scene, targets, false) {
+            Some(photon) => photon,
+            None => return,
+        };
+
+        for depth in 0..self.max_depth {
+            let mut rec = hit_record::new();
+            if !scene.world.hit(&ray, 0.001, rtweekend::INFINITY, &mut rec) {
+                return;
+            }
+            let mat = rec.mat_ptr.as_ref().unwrap();
+            if !mat.is_specular() {
+                if depth > 0 {
+                    photons.push(Photon { p: rec.p, dir: ray.direction().unit_vector(), power });
+                }
+                return;
+            }
+
+            let mut scattered = Ray::new();
+            let mut attenuation = Color::new();
+            if !mat.scatter(&ray, &rec, &mut attenuation, &mut scattered) {
+                return;
+            }
+            power = power * attenuation;
+            ray = scattered;
+        }
+    }
+
+    fn caustic_radiance(&self, r: &Ray, rec: &hit_record) -> Color {
+        if self.caustics.len() == 0 {
+            return Color::new();
+        }
+        let mat = rec.mat_ptr.as_ref().unwrap();
+        let (photons, radius2) = self.caustics.nearest(&rec.p, self.lookup, self.max_radius * self.max_radius);
+
+        let mut flux = Color::new();
+        for photon in photons {
+            let wi = -photon.dir;
+            let cosine = Vec3::dot(&rec.normal, &wi);
+            if cosine > 0.0 {
+                flux = flux + mat.eval(r, rec, &wi) * photon.power / cosine;
+            }
+        }
+        flux / (PI * radius2)
+    }
+}
+
+// One sample of the light arriving directly at a hit point from any light
+pub(crate) fn direct_lighting(r: &Ray, rec: &hit_record, scene: &Scene) -> Color {
+    let mat = rec.mat_ptr.as_ref().unwrap();
+    if let Some(sample) = scene.sample_light(&rec.p) {
+        let f = mat.eval(r, rec, &sample.wi);
+        if !f.near_zero() && !sample.le.near_zero() && scene.unoccluded(&rec.p, &sample) {
+            return f * sample.le / sample.pdf;
+        }
+    }
+    Color::new()
+}
+
+impl Integrator for PhotonIntegrator {
+    fn preprocess(&mut self, scene: &Scene) {
+        let targets = specular_targets(scene);
+        let mut photons = Vec::new();
+        if !targets.is_empty() {
+            for _ in 0..self.caustic_photons {
+                self.trace_caustic_photon(scene, &targets, &mut photons);
+            }
+        }
+
+        // Every emitted photon counts, including those that never landed
+        let scale = 1.0 / self.caustic_photons as f64;
+        for photon in photons.iter_mut() {
+            photon.power = photon.power * scale;
+        }
+        eprintln!("Stored {} caustic photons", photons.len());
+        self.caustics = PhotonMap::new_with_values(photons);
+    }
+
+    fn li(&self, r: &Ray, scene: &Scene, _splats: &mut Vec<Splat>) -> Color {
+        let mut color = Color::new();
+        let mut throughput = Color::new_with_values(1.0, 1.0, 1.0);
+        let mut ray = Ray::new_with_values(r.origin(), r.direction());
+        // Light is only picked up by the path itself while every bounce so far was specular
+        let mut specular_path = true;
+        let mut seen_by_camera = true;
+
+        for depth in 0..self.max_depth {
+            let mut rec = hit_record::new();
+            if !scene.world.hit(&ray, 0.001, rtweekend::INFINITY, &mut rec) {
+                if specular_path {
+                    color = color + throughput * scene.background(&ray.direction(), seen_by_camera);
+                }
+                break;
+            }
+
+            let mat = rec.mat_ptr.as_ref().unwrap();
+            if specular_path {
+                color = color + throughput * mat.emitted(&rec);
+            }
+            if !mat.is_specular() {
+                color = color + throughput * (direct_lighting(&ray, &rec, scene) + self.caustic_radiance(&ray, &rec));
+            }
+
+            let mut scattered = Ray::new();
+            let mut attenuation = Color::new();
+            if !mat.scatter(&ray, &rec, &mut attenuation, &mut scattered) {
+                break;
+            }
+            specular_path = specular_path && mat.is_specular();
+            seen_by_camera = seen_by_camera && Scene::transmitted(&rec, &scattered);
+            throughput = throughput * attenuation;
+            ray = scattered;
+
+            if depth + 1 >= self.rr_depth {
+                let survive = throughput.max_component().min(0.95);
+                if survive <= 0.0 || rtweekend::random_double() >= survive {
+                    break;
+                }
+                throughput = throughput / survive;
+            }
+        }
+
+        color
+    }
+}
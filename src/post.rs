@@ -0,0 +1,243 @@
+use crate::display;
+use crate::environment::luminance;
+use crate::film::CropWindow;
+use crate::rtweekend::PI;
+use crate::vec3::Color;
+
+// Photographic effects applied to the linear image on its way to the display,
+// in the order light meets them: glare in the lens, then chromatic aberration
+// and vignetting, then grain in the film. Rows are top to bottom. Glare only
+// moves light around, so the image keeps its total brightness.
+pub(crate) struct PostEffects {
+    // Fraction of the light above the threshold spread into a soft glow
+    pub bloom: f64,
+    // Size of the glow in pixels
+    pub bloom_radius: f64,
+    // Luminance above which light blooms and streaks
+    pub threshold: f64,
+    // Number of star streaks around highlights, 0 for none
+    pub streaks: usize,
+    pub streak_length: f64,
+    // Fraction of the light above the threshold spread into the streaks
+    pub streak_strength: f64,
+    // How far red and blue drift apart in the corners, in pixels
+    pub chromatic_aberration: f64,
+    // Darkening towards the corners, 0 for none
+    pub vignette: f64,
+    // Standard deviation of the grain relative to the brightness
+    pub grain: f64,
+    // The full frame's size and the window of it that is written, when that's
+    // only a crop window. The effects are worked out on the full frame, black
+    // around the window, so vignetting and the like stay centred on the frame.
+    pub frame: Option<(usize, usize, CropWindow)>,
+}
+
+impl PostEffects {
+    pub(crate) fn new() -> PostEffects {
+        PostEffects {
+            bloom: 0.0,
+            bloom_radius: 0.0,
+            threshold: 1.0,
+            streaks: 0,
+            streak_length: 0.0,
+            streak_strength: 0.0,
+            chromatic_aberration: 0.0,
+            vignette: 0.0,
+            grain: 0.0,
+            frame: None,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bloom == 0.0 && self.streaks == 0 && self.chromatic_aberration == 0.0 && self.vignette == 0.0 && self.grain == 0.0
+    }
+
+    pub(crate) fn apply(&self, pixels: &[Color], width: usize, height: usize) -> Vec<Color> {
+        if let Some((frame_width, frame_height, window)) = self.frame {
+            if (window.width(), window.height()) == (width, height) {
+                let mut frame = vec![Color::new(); frame_width * frame_height];
+                for y in 0..height {
+                    frame[(window.y0 + y) * frame_width + window.x0..][..width].copy_from_slice(&pixels[y * width..][..width]);
+                }
+                return window.cut(&self.apply_to_frame(&frame, frame_width, frame_height), frame_width);
+            }
+        }
+        self.apply_to_frame(pixels, width, height)
+    }
+
+    fn apply_to_frame(&self, pixels: &[Color], width: usize, height: usize) -> Vec<Color> {
+        let mut image = pixels.to_vec();
+        if self.bloom > 0.0 || (self.streaks > 0 && self.streak_strength > 0.0) {
+            image = self.glare(&image, width, height);
+        }
+        if self.chromatic_aberration != 0.0 {
+            image = chromatic_aberration(&image, width, height, self.chromatic_aberration);
+        }
+        for y in 0..height {
+            for x in 0..width {
+                let p = &mut image[y * width + x];
+                if self.vignette > 0.0 {
+                    // Falls off like the cos⁴ law, reaching 1 / (1 + vignette)² in the corners
+                    let r2 = radius_squared(x as f64 + 0.5, y as f64 + 0.5, width, height);
+                    *p = *p / ((1.0 + self.vignette * r2) * (1.0 + self.vignette * r2));
+                }
+                if self.grain > 0.0 {
+                    let h = display::hash((y * 65536 + x) as u64 ^ 0x6772_6169_6e00);
+                    let u = |shift: u32| ((h >> shift) & 0xF_FFFF) as f64 / (1 << 20) as f64;
+                    // Roughly Gaussian with unit variance, the same in every channel
+                    let n = (u(0) + u(20) + u(40) - 1.5) * 2.0;
+                    *p = *p * (1.0 + self.grain * n).max(0.0);
+                }
+            }
+        }
+        image
+    }
+
+    // Takes the given fractions of the light above the threshold and spreads
+    // them into a glow and into streaks
+    fn glare(&self, image: &[Color], width: usize, height: usize) -> Vec<Color> {
+        let bright: Vec<Color> = image.iter().map(|c| {
+            let l = luminance(c);
+            if l > self.threshold { *c * ((l - self.threshold) / l) } else { Color::new() }
+        }).collect();
+
+        let streak_strength = if self.streaks > 0 { self.streak_strength } else { 0.0 };
+        let mut result: Vec<Color> = image.iter().zip(&bright).map(|(&c, &b)| c - b * (self.bloom + streak_strength)).collect();
+
+        if self.bloom > 0.0 {
+            // Three Gaussians give a bright core with a long soft tail
+            for (scale, weight) in [(0.25, 0.5), (0.5, 0.3), (1.0, 0.2)] {
+                let glow = gaussian_blur(&bright, width, height, self.bloom_radius * scale / 2.0);
+                for (r, g) in result.iter_mut().zip(&glow) {
+                    *r = *r + *g * (self.bloom * weight);
+                }
+            }
+        }
+
+        if self.streaks > 0 && self.streak_strength > 0.0 {
+            // Each streak fades out exponentially, and is spread by the few
+            // pixels that are bright enough rather than gathered everywhere
+            let steps = self.streak_length.max(1.0) as usize;
+            let falloff: Vec<f64> = (1..=steps).map(|k| (-4.0 * k as f64 / steps as f64).exp()).collect();
+            let total = falloff.iter().sum::<f64>() * self.streaks as f64;
+            let directions: Vec<(f64, f64)> = (0..self.streaks).map(|s| {
+                let angle = 2.0 * PI * s as f64 / self.streaks as f64;
+                (angle.cos(), angle.sin())
+            }).collect();
+            for y in 0..height {
+                for x in 0..width {
+                    let b = bright[y * width + x];
+                    if b.near_zero() {
+                        continue;
+                    }
+                    let b = b * (self.streak_strength / total);
+                    for &(dx, dy) in &directions {
+                        for (k, w) in falloff.iter().enumerate() {
+                            let qx = (x as f64 + 0.5 + dx * (k + 1) as f64).floor();
+                            let qy = (y as f64 + 0.5 - dy * (k + 1) as f64).floor();
+                            if qx < 0.0 || qy < 0.0 || qx >= width as f64 || qy >= height as f64 {
+                                break;
+                            }
+                            let q = qy as usize * width + qx as usize;
+                            result[q] = result[q] + b * *w;
+                        }
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+// Squared distance from the centre, 1 in the corners
+fn radius_squared(x: f64, y: f64, width: usize, height: usize) -> f64 {
+    let dx = x / width as f64 - 0.5;
+    let dy = (y / height as f64 - 0.5) * height as f64 / width as f64;
+    let corner = 0.25 + 0.25 * (height * height) as f64 / (width * width) as f64;
+    (dx * dx + dy * dy) / corner
+}
+
+// Separable Gaussian blur, treating the image as black outside
+fn gaussian_blur(image: &[Color], width: usize, height: usize, sigma: f64) -> Vec<Color> {
+    let reach = (3.0 * sigma).ceil().max(1.0) as isize;
+    let kernel: Vec<f64> = (-reach..=reach).map(|k| (-0.5 * (k * k) as f64 / (sigma * sigma)).exp()).collect();
+    let norm: f64 = kernel.iter().sum();
+
+    let pass = |source: &[Color], horizontal: bool| -> Vec<Color> {
+        let mut out = vec![Color::new(); source.len()];
+        for y in 0..height as isize {
+            for x in 0..width as isize {
+                let mut sum = Color::new();
+                for (k, w) in kernel.iter().enumerate() {
+                    let offset = k as isize - reach;
+                    let (qx, qy) = if horizontal { (x + offset, y) } else { (x, y + offset) };
+                    if qx >= 0 && qy >= 0 && qx < width as isize && qy < height as isize {
+                        sum = sum + source[qy as usize * width + qx as usize] * *w;
+                    }
+                }
+                out[y as usize * width + x as usize] = sum / norm;
+            }
+        }
+        out
+    };
+    pass(&pass(image, true), false)
+}
+
+// Lateral chromatic aberration: red is magnified and blue shrunk about the
+// centre, so colour fringes appear towards the edges
+fn chromatic_aberration(image: &[Color], width: usize, height: usize, pixels: f64) -> Vec<Color> {
+    let half_diagonal = 0.5 * ((width * width + height * height) as f64).sqrt();
+    let shift = pixels / half_diagonal;
+    let (cx, cy) = (width as f64 / 2.0, height as f64 / 2.0);
+    let sample = |x: f64, y: f64, channel: usize| {
+        // Bilinear, clamped at the edges
+        let x = (x - 0.5).clamp(0.0, (width - 1) as f64);
+        let y = (y - 0.5).clamp(0.0, (height - 1) as f64);
+        let (x0, y0) = (x as usize, y as usize);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (tx, ty) = (x - x0 as f64, y - y0 as f64);
+        let at = |x: usize, y: usize| image[y * width + x][channel];
+        (at(x0, y0) * (1.0 - tx) + at(x1, y0) * tx) * (1.0 - ty) + (at(x0, y1) * (1.0 - tx) + at(x1, y1) * tx) * ty
+    };
+
+    let mut out = Vec::with_capacity(image.len());
+    for y in 0..height {
+        for x in 0..width {
+            let (px, py) = (x as f64 + 0.5 - cx, y as f64 + 0.5 - cy);
+            // A channel magnified by 1 + shift shows what lies closer in
+            let red = sample(cx + px / (1.0 + shift), cy + py / (1.0 + shift), 0);
+            let blue = sample(cx + px / (1.0 - shift), cy + py / (1.0 - shift), 2);
+            out.push(Color::new_with_values(red, image[y * width + x].y(), blue));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crop_windows_match_the_full_frame() {
+        let (width, height) = (12, 9);
+        let frame: Vec<Color> = (0..width * height).map(|i| Color::new_with_values((i % 7) as f64, (i % 5) as f64 * 0.3, 0.5)).collect();
+        let window = CropWindow { x0: 2, y0: 5, x1: 7, y1: 9 };
+        let mut effects = PostEffects::new();
+        effects.vignette = 0.5;
+        effects.chromatic_aberration = 2.0;
+        effects.grain = 0.1;
+
+        // Black around the window, as a crop render leaves it
+        let mut cropped_frame = vec![Color::new(); width * height];
+        for y in window.y0..window.y1 {
+            for x in window.x0..window.x1 {
+                cropped_frame[y * width + x] = frame[y * width + x];
+            }
+        }
+        let expected = window.cut(&effects.apply(&cropped_frame, width, height), width);
+        effects.frame = Some((width, height, window));
+        let result = effects.apply(&window.cut(&frame, width), window.width(), window.height());
+        let values = |image: &[Color]| image.iter().map(|c| [c.x(), c.y(), c.z()]).collect::<Vec<_>>();
+        assert_eq!(values(&result), values(&expected));
+    }
+}
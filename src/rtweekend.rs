@@ -1,4 +1,4 @@
-use rand::Rng;
+use crate::sampler;
 
 // Constants
 
@@ -13,12 +13,27 @@ pub fn degrees_to_radians(degrees: f64) -> f64 {
 
 // Random f64 in [0, 1)
 pub fn random_double() -> f64 {
-    rand::thread_rng().gen_range(0.0..1.0)
+    sampler::next_1d()
+}
+
+// Two random f64 in [0, 1), stratified together by samplers that support it
+pub fn random_double_2d() -> (f64, f64) {
+    sampler::next_2d()
 }
 
 // Random f64 in [min, max)
 pub fn random_double_minmax(min: f64, max: f64) -> f64 {
-    rand::thread_rng().gen_range(min..max)
+    min + (max - min) * random_double()
+}
+
+// Multiple importance sampling weight for strategy f against strategy g (one sample each)
+pub fn power_heuristic(f_pdf: f64, g_pdf: f64) -> f64 {
+    let f = f_pdf * f_pdf;
+    let g = g_pdf * g_pdf;
+    if f + g == 0.0 {
+        return 0.0;
+    }
+    f / (f + g)
 }
 
 pub fn clamp(x: f64, min: f64, max: f64) -> f64 {
@@ -0,0 +1,391 @@
+use std::cell::{Cell, RefCell};
+use std::sync::OnceLock;
+use crate::blue_noise;
+
+// Source of the uniform random numbers behind `rtweekend::random_double`.
+// Everything that needs randomness draws from the sampler installed for the
+// current thread, so an integrator can swap in its own to control or replay
+// the numbers a path is built from.
+//
+// Samplers that spread their samples out work per pixel: each pixel sample
+// starts with `start_pixel_sample`, after which every value drawn uses up the
+// next dimension. The pixel position takes the first two, the lens the next
+// two, and the rest go to light and material sampling along the path.
+pub(crate) trait Sampler {
+    fn start_pixel_sample(&mut self, _x: usize, _y: usize, _index: usize) {}
+
+    // Next value in [0, 1)
+    fn next_1d(&mut self) -> f64;
+
+    // Next two values, stratified together where the sampler supports it
+    fn next_2d(&mut self) -> (f64, f64) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+// Dimensions past this are deep into a path where spreading samples out no
+// longer helps, so all samplers fall back to independent numbers.
+const MAX_DIMENSIONS: usize = 64;
+
+// Independent uniform numbers from the thread's random generator
+pub(crate) struct IndependentSampler;
+
+impl Sampler for IndependentSampler {
+    fn next_1d(&mut self) -> f64 {
+        random_uniform()
+    }
+}
+
+// SplitMix64 on a per-thread state. It is seeded rather than taken from the OS
+// so a render, random scene included, can be repeated.
+fn random_uniform() -> f64 {
+    RNG_STATE.with(|state| {
+        let s = state.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state.set(s);
+        (mix(s) >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+// Where a per-pixel sampler is in its sequence
+struct PixelSample {
+    x: usize,
+    y: usize,
+    index: usize,
+    dimension: usize,
+}
+
+impl PixelSample {
+    fn new() -> PixelSample {
+        PixelSample { x: 0, y: 0, index: 0, dimension: 0 }
+    }
+
+    fn start(&mut self, x: usize, y: usize, index: usize) {
+        *self = PixelSample { x, y, index, dimension: 0 };
+    }
+
+    // Hash of the pixel and the current dimension, used to decorrelate them
+    fn hash(&self, salt: u64) -> u64 {
+        mix(mix(mix(self.x as u64 ^ salt) ^ self.y as u64) ^ self.dimension as u64)
+    }
+
+    // Claims the next `count` dimensions, false once they run past MAX_DIMENSIONS
+    fn advance(&mut self, count: usize) -> bool {
+        self.dimension += count;
+        self.dimension <= MAX_DIMENSIONS
+    }
+}
+
+// Jittered stratification: every dimension is split into one stratum per
+// sample and each pixel visits the strata in its own random order. Pairs of
+// dimensions are stratified on a grid when the sample count is square.
+pub(crate) struct StratifiedSampler {
+    samples_per_pixel: usize,
+    state: PixelSample,
+}
+
+impl StratifiedSampler {
+    pub(crate) fn new_with_values(samples_per_pixel: usize) -> StratifiedSampler {
+        StratifiedSampler { samples_per_pixel, state: PixelSample::new() }
+    }
+
+    fn stratum(&self, salt: u64) -> usize {
+        let n = self.samples_per_pixel;
+        permute((self.state.index % n) as u32, n as u32, self.state.hash(salt) as u32) as usize
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let stratum = self.stratum(0);
+        if !self.state.advance(1) {
+            return random_uniform();
+        }
+        (stratum as f64 + random_uniform()) / self.samples_per_pixel as f64
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let n = self.samples_per_pixel;
+        let side = (n as f64).sqrt().round() as usize;
+        let (sx, sy) = if side * side == n {
+            let cell = self.stratum(0);
+            ((cell % side) as f64 / side as f64, (cell / side) as f64 / side as f64)
+        } else {
+            (self.stratum(0) as f64 / n as f64, self.stratum(1) as f64 / n as f64)
+        };
+        if !self.state.advance(2) {
+            return (random_uniform(), random_uniform());
+        }
+        let size = if side * side == n { side } else { n };
+        (sx + random_uniform() / size as f64, sy + random_uniform() / size as f64)
+    }
+}
+
+const PRIMES: [u32; MAX_DIMENSIONS] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+// The Halton sequence, one prime base per dimension, Owen scrambled with a hash
+// per pixel and dimension. Scrambling matters here: the first few points in a
+// large base all fall in a small part of [0, 1) otherwise.
+pub(crate) struct HaltonSampler {
+    state: PixelSample,
+}
+
+impl HaltonSampler {
+    pub(crate) fn new() -> HaltonSampler {
+        HaltonSampler { state: PixelSample::new() }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let dimension = self.state.dimension;
+        let seed = self.state.hash(1);
+        if !self.state.advance(1) {
+            return random_uniform();
+        }
+        owen_scrambled_radical_inverse(PRIMES[dimension], self.state.index as u64, seed)
+    }
+}
+
+// Digits of `index` in `base` mirrored around the radix point, each one
+// permuted depending on the digits before it. Runs on past the last digit of
+// the index so the trailing zeros are scrambled too.
+fn owen_scrambled_radical_inverse(base: u32, mut index: u64, seed: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut prefix: u64 = 0;
+    let mut value = 0.0;
+    while inv_base_m > 1e-10 {
+        let next = index / base as u64;
+        let digit = (index - next * base as u64) as u32;
+        let permuted = permute(digit, base, mix(seed ^ prefix) as u32);
+        inv_base_m *= inv_base;
+        value += permuted as f64 * inv_base_m;
+        prefix = prefix.wrapping_mul(base as u64).wrapping_add(digit as u64 + 1);
+        index = next;
+    }
+    value.min(ONE_MINUS_EPSILON)
+}
+
+// The first two Sobol dimensions, Owen scrambled with a hash per pixel and
+// pair of dimensions and with the sample order shuffled the same way, so
+// every pair of dimensions is a well stratified (0, 2) sequence of its own.
+pub(crate) struct SobolSampler {
+    state: PixelSample,
+}
+
+impl SobolSampler {
+    pub(crate) fn new() -> SobolSampler {
+        SobolSampler { state: PixelSample::new() }
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let seed = self.state.hash(2);
+        if !self.state.advance(1) {
+            return random_uniform();
+        }
+        let index = nested_uniform_scramble(self.state.index as u32, (seed >> 32) as u32);
+        to_unit(nested_uniform_scramble(sobol(index, 0), seed as u32))
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let seed = self.state.hash(2);
+        if !self.state.advance(2) {
+            return (random_uniform(), random_uniform());
+        }
+        let index = nested_uniform_scramble(self.state.index as u32, (seed >> 32) as u32);
+        let y_seed = mix(seed) as u32;
+        (to_unit(nested_uniform_scramble(sobol(index, 0), seed as u32)), to_unit(nested_uniform_scramble(sobol(index, 1), y_seed)))
+    }
+}
+
+// Sobol points with the same scrambling in every pixel, each pixel shifted by
+// a blue noise mask. Neighbouring pixels then get well separated samples and
+// the remaining error looks like fine grain instead of clumped noise.
+pub(crate) struct BlueNoiseSampler {
+    mask: &'static [f64],
+    state: PixelSample,
+}
+
+// The mask is slow to build and the same for every sampler, so it is made once
+fn shared_mask() -> &'static [f64] {
+    static MASK: OnceLock<Vec<f64>> = OnceLock::new();
+    MASK.get_or_init(|| blue_noise::mask(blue_noise::MASK_SIZE))
+}
+
+impl BlueNoiseSampler {
+    pub(crate) fn new() -> BlueNoiseSampler {
+        BlueNoiseSampler { mask: shared_mask(), state: PixelSample::new() }
+    }
+
+    // Mask value at the pixel, toroidally offset by a hash of the dimension
+    fn offset(&self, salt: u64) -> f64 {
+        let size = blue_noise::MASK_SIZE;
+        let shift = mix(self.state.dimension as u64 ^ salt) as usize;
+        let x = (self.state.x + shift) % size;
+        let y = (self.state.y + (shift >> 16)) % size;
+        self.mask[y * size + x]
+    }
+
+    fn sample(&self, dimension: u32, salt: u64) -> f64 {
+        let seed = mix(self.state.dimension as u64 ^ salt);
+        let index = nested_uniform_scramble(self.state.index as u32, (seed >> 32) as u32);
+        let value = to_unit(nested_uniform_scramble(sobol(index, dimension), seed as u32)) + self.offset(salt);
+        value - value.floor()
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let value = self.sample(0, 3);
+        if !self.state.advance(1) {
+            return random_uniform();
+        }
+        value
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let value = (self.sample(0, 3), self.sample(1, 4));
+        if !self.state.advance(2) {
+            return (random_uniform(), random_uniform());
+        }
+        value
+    }
+}
+
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+fn to_unit(bits: u32) -> f64 {
+    (bits as f64 / 4294967296.0).min(ONE_MINUS_EPSILON)
+}
+
+// Splitmix64 finaliser
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+// First two dimensions of the Sobol sequence: the van der Corput sequence and
+// the one generated by the polynomial x + 1.
+fn sobol(index: u32, dimension: u32) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+    let mut result = 0;
+    let mut v = 1u32 << 31;
+    let mut i = index;
+    while i != 0 {
+        if i & 1 != 0 {
+            result ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Owen scrambling through the hash of Laine and Karras, as in Burley's
+// "Practical Hash-based Owen Scrambling".
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut v = x.reverse_bits();
+    v = v.wrapping_add(seed);
+    v ^= v.wrapping_mul(0x6c50_b47c);
+    v ^= v.wrapping_mul(0xb82f_1e52);
+    v ^= v.wrapping_mul(0xc7af_e638);
+    v ^= v.wrapping_mul(0x8d22_f6e6);
+    v.reverse_bits()
+}
+
+// Random permutation of [0, l) evaluated one element at a time, from Kensler's
+// "Correlated Multi-Jittered Sampling".
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+thread_local! {
+    static SAMPLER: RefCell<Box<dyn Sampler>> = RefCell::new(Box::new(IndependentSampler));
+    static RNG_STATE: Cell<u64> = const { Cell::new(0) };
+    static SEED: Cell<u64> = const { Cell::new(0) };
+}
+
+pub(crate) fn start_pixel_sample(x: usize, y: usize, index: usize) {
+    SAMPLER.with(|sampler| sampler.borrow_mut().start_pixel_sample(x, y, index))
+}
+
+pub(crate) fn next_1d() -> f64 {
+    SAMPLER.with(|sampler| sampler.borrow_mut().next_1d())
+}
+
+pub(crate) fn next_2d() -> (f64, f64) {
+    SAMPLER.with(|sampler| sampler.borrow_mut().next_2d())
+}
+
+// Installs `sampler` for the current thread and returns the one it replaces
+pub(crate) fn set_sampler(sampler: Box<dyn Sampler>) -> Box<dyn Sampler> {
+    SAMPLER.with(|current| current.replace(sampler))
+}
+
+pub(crate) fn seed(seed: u64) {
+    SEED.with(|s| s.set(seed));
+    RNG_STATE.with(|state| state.set(mix(seed)))
+}
+
+// Restarts the random generator for a pixel sample, so the numbers it draws
+// don't depend on which thread traced it or on what that thread traced before.
+pub(crate) fn seed_pixel_sample(x: usize, y: usize, index: usize) {
+    let seed = SEED.with(|s| s.get());
+    RNG_STATE.with(|state| state.set(mix(mix(mix(seed ^ x as u64) ^ y as u64) ^ index as u64)))
+}
@@ -0,0 +1,230 @@
+use crate::aabb::Aabb;
+use crate::camera::{self, Camera};
+use crate::environment::Environment;
+use crate::hittable::{hit_record, Hittable};
+use crate::hittable_list::HittableList;
+use crate::light::AreaLight;
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::rtweekend::{self, PI};
+use crate::vec3::{self, Color, Point3, Vec3};
+
+// A ray of light leaving one of the lights, as used to start light paths
+pub(crate) struct LightRay {
+    pub ray: Ray,
+    pub le: Color,
+    // Area light normal, or the direction towards the environment
+    pub n: Vec3,
+    // Index of the area light, None for the environment
+    pub light: Option<usize>,
+    pub pick_pdf: f64,
+    pub pdf_pos: f64,
+    pub pdf_dir: f64,
+}
+
+impl LightRay {
+    // Flux carried by the ray, Le * cos / pdf
+    pub(crate) fn power(&self) -> Color {
+        let cosine = if self.light.is_some() { Vec3::dot(&self.n, &self.ray.direction()).abs() } else { 1.0 };
+        self.le * (cosine / (self.pick_pdf * self.pdf_pos * self.pdf_dir))
+    }
+}
+
+// A point on one of the lights sampled as seen from a point in the scene
+pub(crate) struct LightSample {
+    // Point on the light, or one unit towards the environment
+    pub p: Point3,
+    pub n: Vec3,
+    // Unit direction towards the light
+    pub wi: Vec3,
+    pub le: Color,
+    // Solid angle pdf, including picking the light
+    pub pdf: f64,
+    pub light: Option<usize>,
+}
+
+// Everything an integrator needs to know about the world being rendered.
+pub(crate) struct Scene {
+    pub world: HittableList,
+    pub lights: Vec<AreaLight>,
+    pub environment: Box<dyn Environment>,
+    pub camera: Box<dyn Camera>,
+    // Bounding sphere of the world, used to emit light from the environment
+    pub center: Point3,
+    pub radius: f64,
+    // Leave out the background wherever the camera sees it, directly or through glass
+    pub transparent_background: bool,
+    // Camera samples trace wavelengths, see spectrum.rs
+    pub spectral: bool,
+    // How long the shutter is open in seconds, 0 for an instant
+    pub shutter: f64,
+}
+
+impl Scene {
+    pub(crate) fn new_with_values(world: HittableList, lights: Vec<AreaLight>, environment: Box<dyn Environment>, camera: Box<dyn Camera>) -> Scene {
+        let mut bounds = Aabb::new();
+        let (center, radius) = if world.bounding_box(&mut bounds) {
+            let center = (bounds.min() + bounds.max()) * 0.5;
+            (center, (bounds.max() - center).length().max(1e-3))
+        } else {
+            (Point3::new(), 1.0)
+        };
+
+        Scene { world, lights, environment, camera, center, radius, transparent_background: false, spectral: false, shutter: 0.0 }
+    }
+
+    // Picks the moment the next camera or light path is traced at, while the
+    // shutter is open
+    pub(crate) fn start_time(&self) {
+        if self.shutter > 0.0 {
+            camera::set_time(rtweekend::random_double() * self.shutter);
+        }
+    }
+
+    // Background radiance along `direction`, or none when the background is
+    // transparent and the ray is still one the camera sees straight through
+    pub(crate) fn background(&self, direction: &Vec3, seen_by_camera: bool) -> Color {
+        if self.transparent_background && seen_by_camera {
+            Color::new()
+        } else {
+            self.environment.value(direction)
+        }
+    }
+
+    // Whether `scattered` went through the surface rather than off it
+    pub(crate) fn transmitted(rec: &hit_record, scattered: &Ray) -> bool {
+        Vec3::dot(&scattered.direction(), &rec.normal) < 0.0
+    }
+
+    // How much of the background the camera ray hides: 0 if it reaches the
+    // background, through glass or not, and 1 if it ends on a surface. Glass
+    // both reflects and transmits, so it is sampled and averages out in between.
+    pub(crate) fn coverage(&self, r: &Ray) -> f64 {
+        let mut ray = Ray::new_with_values(r.origin(), r.direction());
+        for _ in 0..64 {
+            let mut rec = hit_record::new();
+            if !self.world.hit(&ray, 0.001, rtweekend::INFINITY, &mut rec) {
+                return 0.0;
+            }
+            let mut scattered = Ray::new();
+            let mut attenuation = Color::new();
+            if !rec.mat_ptr.as_ref().unwrap().scatter(&ray, &rec, &mut attenuation, &mut scattered) || !Scene::transmitted(&rec, &scattered) {
+                return 1.0;
+            }
+            ray = scattered;
+        }
+        1.0
+    }
+
+    // Lights are picked uniformly, with the environment counted as one of them
+    pub(crate) fn light_pick_pdf(&self) -> f64 {
+        1.0 / (self.lights.len() + 1) as f64
+    }
+
+    pub(crate) fn area_light_index(&self, object_id: usize) -> Option<usize> {
+        self.lights.iter().position(|l| l.object_id() == object_id)
+    }
+
+    // Index of a uniformly picked light, lights.len() standing for the environment
+    pub(crate) fn pick_light(&self) -> usize {
+        ((rtweekend::random_double() * (self.lights.len() + 1) as f64) as usize).min(self.lights.len())
+    }
+
+    pub(crate) fn sample_light_ray(&self) -> Option<LightRay> {
+        let pick_pdf = self.light_pick_pdf();
+        let pick = self.pick_light();
+
+        if pick < self.lights.len() {
+            let light = &self.lights[pick];
+            let (p, n) = light.sample_point();
+            let d = vec3::random_cosine_direction();
+            let direction = Onb::build_from_w(&n).local(d.x(), d.y(), d.z());
+            let le = light.radiance(&n, &direction);
+            if d.z() <= 0.0 || le.near_zero() {
+                return None;
+            }
+            return Some(LightRay {
+                ray: Ray::new_with_values(p, direction),
+                le,
+                n,
+                light: Some(pick),
+                pick_pdf,
+                pdf_pos: light.pdf_position(),
+                pdf_dir: d.z() / PI,
+            });
+        }
+
+        // Light from the environment enters through a disk covering the scene
+        let (to_env, pdf_dir) = self.environment.sample()?;
+        let to_env = to_env.unit_vector();
+        let le = self.environment.value(&to_env);
+        if pdf_dir <= 0.0 || le.near_zero() {
+            return None;
+        }
+        let d = -to_env;
+        let disk = vec3::random_in_unit_disk();
+        let origin = self.center + Onb::build_from_w(&d).local(disk.x(), disk.y(), 0.0) * self.radius - d * self.radius;
+        Some(LightRay {
+            ray: Ray::new_with_values(origin, d),
+            le,
+            n: to_env,
+            light: None,
+            pick_pdf,
+            pdf_pos: 1.0 / (PI * self.radius * self.radius),
+            pdf_dir,
+        })
+    }
+
+    pub(crate) fn sample_light(&self, p: &Point3) -> Option<LightSample> {
+        let pick_pdf = self.light_pick_pdf();
+        let pick = self.pick_light();
+
+        if pick == self.lights.len() {
+            let (to_env, pdf) = self.environment.sample()?;
+            if pdf <= 0.0 {
+                return None;
+            }
+            let to_env = to_env.unit_vector();
+            return Some(LightSample {
+                p: *p + to_env,
+                n: -to_env,
+                wi: to_env,
+                le: self.environment.value(&to_env),
+                pdf: pdf * pick_pdf,
+                light: None,
+            });
+        }
+
+        let light = &self.lights[pick];
+        let (light_p, n) = light.sample_point();
+        let to_light = light_p - *p;
+        let wi = to_light.unit_vector();
+        let cos_light = Vec3::dot(&n, &-wi);
+        if cos_light <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            p: light_p,
+            n,
+            wi,
+            le: light.radiance(&n, &-wi),
+            pdf: light.pdf_position() * to_light.length_squared() / cos_light * pick_pdf,
+            light: Some(pick),
+        })
+    }
+
+    // Whether nothing blocks the way from `p` to a sampled light
+    pub(crate) fn unoccluded(&self, p: &Point3, sample: &LightSample) -> bool {
+        if sample.light.is_none() {
+            return !self.world.hit(&Ray::new_with_values(*p, sample.wi), 0.001, rtweekend::INFINITY, &mut hit_record::new());
+        }
+        self.visible(p, &sample.p)
+    }
+
+    pub(crate) fn visible(&self, a: &Point3, b: &Point3) -> bool {
+        let d = *b - *a;
+        let dist = d.length();
+        let shadow_ray = Ray::new_with_values(*a, d / dist);
+        !self.world.hit(&shadow_ray, 0.001, dist - 0.001, &mut hit_record::new())
+    }
+}
@@ -0,0 +1,214 @@
+use std::cell::Cell;
+use std::sync::OnceLock;
+use crate::color;
+use crate::environment::Environment;
+use crate::vec3::{Color, Vec3};
+
+// Wavelengths traced in spectral mode, in nanometres
+pub(crate) const LAMBDA_MIN: f64 = 380.0;
+pub(crate) const LAMBDA_MAX: f64 = 780.0;
+
+// Wavelength used for refractive indices when rendering in RGB, the sodium D line
+const LAMBDA_D: f64 = 589.3;
+
+// The wavelengths a camera sample carries. In spectral mode a Color holds
+// radiance at these three wavelengths rather than red, green and blue. The
+// first is the hero wavelength, the one kept when dispersion splits them up.
+#[derive(Clone, Copy)]
+struct Wavelengths {
+    lambda: [f64; 3],
+    terminated: bool,
+}
+
+thread_local! {
+    static WAVELENGTHS: Cell<Option<Wavelengths>> = const { Cell::new(None) };
+}
+
+// Starts a spectral camera sample: the hero wavelength at `u` across the
+// visible range and two more spaced evenly after it, wrapping around
+// (Wilkie et al. 2014)
+pub(crate) fn start_sample(u: f64) {
+    let lambda = [0.0, 1.0, 2.0].map(|k| LAMBDA_MIN + (u + k / 3.0).fract() * (LAMBDA_MAX - LAMBDA_MIN));
+    WAVELENGTHS.with(|w| w.set(Some(Wavelengths { lambda, terminated: false })));
+}
+
+// Back to RGB for whatever is traced outside camera samples
+pub(crate) fn end_sample() {
+    WAVELENGTHS.with(|w| w.set(None));
+}
+
+// Linear RGB of radiance found by the current spectral sample
+pub(crate) fn to_rgb(radiance: Color) -> Color {
+    let wavelengths = WAVELENGTHS.with(|w| w.get()).expect("No spectral sample started");
+    let mut xyz = Vec3::new();
+    for (k, &lambda) in wavelengths.lambda.iter().enumerate() {
+        xyz = xyz + cie_xyz(lambda) * radiance[k];
+    }
+    // Each wavelength is picked with pdf 1 / (LAMBDA_MAX - LAMBDA_MIN)
+    let rgb = color::xyz_to_rgb(xyz * ((LAMBDA_MAX - LAMBDA_MIN) / 3.0));
+    rgb / white_rgb()
+}
+
+// The hero wavelength, if a spectral sample is being traced
+pub(crate) fn hero_wavelength() -> Option<f64> {
+    WAVELENGTHS.with(|w| w.get()).map(|w| w.lambda[0])
+}
+
+// Weight for a path that can only follow the hero wavelength from here on, as
+// through dispersive glass. The other wavelengths are dropped, and the hero
+// counts for all three, once.
+pub(crate) fn terminate_secondary() -> Color {
+    WAVELENGTHS.with(|w| match w.get() {
+        Some(mut wavelengths) => {
+            let weight = if wavelengths.terminated { 1.0 } else { 3.0 };
+            wavelengths.terminated = true;
+            w.set(Some(wavelengths));
+            Color::new_with_values(weight, 0.0, 0.0)
+        }
+        None => Color::new_with_values(1.0, 1.0, 1.0),
+    })
+}
+
+// An RGB reflectance or emission as seen by the current sample: unchanged in
+// RGB mode, or else a smooth spectrum matching it evaluated at each wavelength
+pub(crate) fn from_rgb(c: Color) -> Color {
+    match WAVELENGTHS.with(|w| w.get()) {
+        Some(wavelengths) => {
+            // Smits' spectra are for reflectances, so brighter colours are scaled down first
+            let scale = c.x().max(c.y()).max(c.z()).max(1.0);
+            let c = c / scale;
+            let [a, b, d] = wavelengths.lambda.map(|lambda| smits(&c, lambda) * scale);
+            Color::new_with_values(a, b, d)
+        }
+        None => c,
+    }
+}
+
+// Spectrum of an RGB colour after Smits (1999): white plus the cyan, magenta
+// or yellow and red, green or blue spectra needed to reach the colour.
+fn smits(c: &Color, lambda: f64) -> f64 {
+    let (r, g, b) = (c.x().max(0.0), c.y().max(0.0), c.z().max(0.0));
+    let at = |table: &[f64; 10]| lookup(table, lambda);
+    let value = if r <= g && r <= b {
+        r * at(&WHITE) + if g <= b { (g - r) * at(&CYAN) + (b - g) * at(&BLUE) } else { (b - r) * at(&CYAN) + (g - b) * at(&GREEN) }
+    } else if g <= r && g <= b {
+        g * at(&WHITE) + if r <= b { (r - g) * at(&MAGENTA) + (b - r) * at(&BLUE) } else { (b - g) * at(&MAGENTA) + (r - b) * at(&RED) }
+    } else {
+        b * at(&WHITE) + if r <= g { (r - b) * at(&YELLOW) + (g - r) * at(&GREEN) } else { (g - b) * at(&YELLOW) + (r - g) * at(&RED) }
+    };
+    value.max(0.0)
+}
+
+// Smits' tables cover 380 nm to 720 nm in ten bins
+const WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// Linear interpolation between the bin centres, constant past the ends
+fn lookup(table: &[f64; 10], lambda: f64) -> f64 {
+    let x = ((lambda - 380.0) / 34.0 - 0.5).clamp(0.0, 9.0);
+    let i = (x as usize).min(8);
+    let t = x - i as f64;
+    table[i] * (1.0 - t) + table[i + 1] * t
+}
+
+// CIE 1931 colour matching functions, from Wyman, Sloan and Shirley's
+// multi-lobe Gaussian fit (2013)
+fn cie_xyz(lambda: f64) -> Vec3 {
+    let g = |mu: f64, sigma_low: f64, sigma_high: f64| {
+        let t = (lambda - mu) / if lambda < mu { sigma_low } else { sigma_high };
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new_with_values(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+// RGB of a constant spectrum of 1, divided out so white surfaces stay white
+fn white_rgb() -> Color {
+    static WHITE_RGB: OnceLock<Color> = OnceLock::new();
+    *WHITE_RGB.get_or_init(|| {
+        let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+        let xyz = (0..steps).fold(Vec3::new(), |sum, i| sum + cie_xyz(LAMBDA_MIN + i as f64 + 0.5));
+        color::xyz_to_rgb(xyz)
+    })
+}
+
+// How a glass's refractive index changes with wavelength
+#[derive(Clone, Copy)]
+pub(crate) enum Ior {
+    Constant(f64),
+    // n = a + b / λ², λ in micrometres
+    Cauchy(f64, f64),
+    // n² = 1 + Σ b λ² / (λ² - c), λ in micrometres
+    Sellmeier([f64; 3], [f64; 3]),
+}
+
+impl Ior {
+    pub(crate) fn at(&self, lambda: f64) -> f64 {
+        let l2 = (lambda / 1000.0) * (lambda / 1000.0);
+        match self {
+            Ior::Constant(n) => *n,
+            Ior::Cauchy(a, b) => a + b / l2,
+            Ior::Sellmeier(b, c) => (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>()).sqrt(),
+        }
+    }
+
+    // The index for the current sample: at the hero wavelength in spectral
+    // mode, with the weight that leaves the path, or else at the D line
+    pub(crate) fn sample(&self) -> (f64, Color) {
+        match (self, hero_wavelength()) {
+            (Ior::Constant(n), _) => (*n, Color::new_with_values(1.0, 1.0, 1.0)),
+            (_, Some(lambda)) => (self.at(lambda), terminate_secondary()),
+            (_, None) => (self.at(LAMBDA_D), Color::new_with_values(1.0, 1.0, 1.0)),
+        }
+    }
+
+    pub(crate) fn parse(name: &str) -> Option<Ior> {
+        match name {
+            // Schott N-BK7 crown glass, barely dispersive
+            "bk7" => Some(Ior::Sellmeier([1.03961212, 0.231792344, 1.01046945], [0.00600069867, 0.0200179144, 103.560653])),
+            // Schott SF11 dense flint glass
+            "sf11" => Some(Ior::Sellmeier([1.73759695, 0.313747346, 1.89878101], [0.013188707, 0.0623068142, 155.23629])),
+            "diamond" => Some(Ior::Sellmeier([4.3356, 0.3306, 0.0], [0.0112360, 0.0306250, 0.0])),
+            _ => match name.strip_prefix("cauchy:") {
+                Some(coefficients) => {
+                    let (a, b) = coefficients.split_once(',')?;
+                    Some(Ior::Cauchy(a.trim().parse().ok()?, b.trim().parse().ok()?))
+                }
+                None => name.parse().ok().map(Ior::Constant),
+            },
+        }
+    }
+}
+
+// Makes an RGB environment spectral along with the materials
+pub(crate) struct SpectralEnvironment {
+    environment: Box<dyn Environment>,
+}
+
+impl SpectralEnvironment {
+    pub(crate) fn new_with_values(environment: Box<dyn Environment>) -> SpectralEnvironment {
+        SpectralEnvironment { environment }
+    }
+}
+
+impl Environment for SpectralEnvironment {
+    fn value(&self, direction: &Vec3) -> Color {
+        from_rgb(self.environment.value(direction))
+    }
+
+    fn sample(&self) -> Option<(Vec3, f64)> {
+        self.environment.sample()
+    }
+
+    fn pdf(&self, direction: &Vec3) -> f64 {
+        self.environment.pdf(direction)
+    }
+}
@@ -1,39 +1,76 @@
-use std::rc::Rc;
-use crate::{hittable, material, vec3};
+use std::sync::Arc;
+use crate::aabb::Aabb;
+use crate::{camera, hittable, material, rtweekend, vec3};
 use crate::ray;
 
 
 pub struct Sphere {
     center: vec3::Point3,
+    // Where the centre is `time1` seconds after the shutter opens, for motion blur
+    center1: vec3::Point3,
+    time1: f64,
     radius: f64,
-    mat_ptr: Rc<dyn material::Material>,
+    mat_ptr: Arc<dyn material::Material>,
 }
 
 impl Sphere {
-    fn new() -> Sphere {
+    pub(crate) fn new_with_values(center: vec3::Point3, radius: f64, material: Arc<dyn material::Material>) -> Sphere {
         Sphere {
-            center: vec3::Point3::new(),
-            radius: 0.0,
-            mat_ptr: Rc::new(material::Lambertian::new_with_values(vec3::Color::new_with_values(0.0, 0.0, 0.0))),
+            center,
+            center1: center,
+            time1: 1.0,
+            radius,
+            mat_ptr: material,
         }
     }
 
-    pub(crate) fn new_with_values(center: vec3::Point3, radius: f64, material: Rc<dyn material::Material>) -> Sphere {
+    // A sphere at `center0` when the shutter opens and at `center1` at `time1`
+    // seconds, moving in a straight line
+    pub(crate) fn new_moving(center0: vec3::Point3, center1: vec3::Point3, time1: f64, radius: f64, material: Arc<dyn material::Material>) -> Sphere {
         Sphere {
-            center: center,
-            radius: radius,
+            center: center0,
+            center1,
+            time1,
+            radius,
             mat_ptr: material,
         }
     }
 
+    fn center(&self) -> vec3::Point3 {
+        if (self.center1 - self.center).near_zero() { self.center } else { self.center + (self.center1 - self.center) * (camera::time() / self.time1) }
+    }
+
+    pub(crate) fn area(&self) -> f64 {
+        4.0 * rtweekend::PI * self.radius * self.radius
+    }
+
+    pub(crate) fn material(&self) -> &Arc<dyn material::Material> {
+        &self.mat_ptr
+    }
+
+    // Uniformly distributed point on the surface together with its outward normal
+    pub(crate) fn random_point(&self) -> (vec3::Point3, vec3::Vec3) {
+        let normal = vec3::random_unit_vector();
+        (self.center + normal * self.radius, normal)
+    }
+
 }
 
 
 
+// u is the angle around the y axis from x=-1, v the angle from y=-1 to y=+1, both mapped to [0, 1]
+fn get_sphere_uv(p: &vec3::Point3) -> (f64, f64) {
+    let theta = (-p.y()).acos();
+    let phi = (-p.z()).atan2(p.x()) + rtweekend::PI;
+
+    (phi / (2.0 * rtweekend::PI), theta / rtweekend::PI)
+}
+
 impl hittable::Hittable for Sphere {
 
     fn hit(&self, r: &ray::Ray, t_min: f64, t_max: f64, rec: &mut hittable::hit_record) -> bool {
-        let oc = r.origin() - self.center;
+        let center = self.center();
+        let oc = r.origin() - center;
         let a = r.direction().length_squared();
         let half_b = oc.dot_self(r.direction());
         let c = oc.length_squared() - self.radius*self.radius;
@@ -55,10 +92,24 @@ impl hittable::Hittable for Sphere {
 
         rec.t = root;
         rec.p = r.at(rec.t);
-        let outward_normal = (rec.p - self.center) / self.radius;
+        let outward_normal = (rec.p - center) / self.radius;
         rec.set_face_normal(r, outward_normal);
-        rec.mat_ptr = Option::from(Rc::clone(&self.mat_ptr));
+        (rec.u, rec.v) = get_sphere_uv(&outward_normal);
+        rec.mat_ptr = Option::from(Arc::clone(&self.mat_ptr));
 
         true
     }
+
+    fn bounding_box(&self, output_box: &mut Aabb) -> bool {
+        let r = vec3::Vec3::new_with_values(self.radius, self.radius, self.radius);
+        *output_box = Aabb::new_with_values(self.center - r, self.center + r);
+        if !(self.center1 - self.center).near_zero() {
+            *output_box = Aabb::surrounding_box(output_box, &Aabb::new_with_values(self.center1 - r, self.center1 + r));
+        }
+        true
+    }
+
+    fn is_specular(&self) -> bool {
+        self.mat_ptr.is_specular()
+    }
 }
\ No newline at end of file
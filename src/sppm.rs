@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use crate::aabb::Aabb;
+use crate::hittable::{hit_record, Hittable};
+use crate::photon::{self, Target};
+use crate::ray::Ray;
+use crate::rtweekend;
+use crate::sampler;
+use crate::scene::Scene;
+use crate::vec3::{Color, Point3, Vec3};
+
+// Fraction of the new photons kept each iteration, which decides how fast the radius shrinks
+const GAMMA: f64 = 2.0 / 3.0;
+
+// Where a pixel's camera path first reached a diffuse surface this iteration
+struct VisiblePoint {
+    rec: hit_record,
+    r_in: Ray,
+    beta: Color,
+}
+
+struct Pixel {
+    // Light found by the camera paths themselves, summed over iterations
+    ld: Color,
+    vp: Option<VisiblePoint>,
+    radius: f64,
+    n: f64,
+    tau: Color,
+    phi: Color,
+    m: usize,
+}
+
+// Stochastic progressive photon mapping (Hachisuka and Jensen). Each iteration
+// traces a camera path per pixel to its first diffuse hit, then shoots a fresh
+// batch of photons and adds those landing near the visible points to the pixels'
+// flux while their search radii shrink. Photons are thrown away after every
+// iteration, so the image converges however many are used in total.
+pub(crate) struct Sppm {
+    max_depth: usize,
+    photons_per_iteration: usize,
+    initial_radius: f64,
+}
+
+impl Sppm {
+    pub(crate) fn new_with_values(max_depth: usize, photons_per_iteration: usize, initial_radius: f64) -> Sppm {
+        Sppm { max_depth, photons_per_iteration, initial_radius }
+    }
+
+    // Returns each pixel's radiance, rows top to bottom
+    pub(crate) fn render(&self, scene: &Scene, width: usize, height: usize, iterations: usize) -> Vec<Color> {
+        let mut pixels: Vec<Pixel> = (0..width * height).map(|_| Pixel {
+            ld: Color::new(),
+            vp: None,
+            radius: self.initial_radius,
+            n: 0.0,
+            tau: Color::new(),
+            phi: Color::new(),
+            m: 0,
+        }).collect();
+
+        for iteration in 0..iterations {
+            eprintln!("\rIteration {} of {}", iteration + 1, iterations);
+
+            for j in 0..height {
+                for i in 0..width {
+                    sampler::start_pixel_sample(i, j, iteration);
+                    let (du, dv) = rtweekend::random_double_2d();
+                    let u = (i as f64 + du) / (width - 1) as f64;
+                    let v = (j as f64 + dv) / (height - 1) as f64;
+                    if let Some(r) = scene.camera.get_ray(u, v) {
+                        scene.start_time();
+                        self.trace_camera_path(&r, scene, &mut pixels[(height - 1 - j) * width + i]);
+                    }
+                }
+            }
+
+            let grid = Grid::new_with_values(&pixels);
+            if let Some(bounds) = grid.bounds {
+                let targets = [Target::from_box(&bounds)];
+                for _ in 0..self.photons_per_iteration {
+                    self.trace_photon(scene, &targets, &grid, &mut pixels);
+                }
+            }
+
+            for pixel in pixels.iter_mut() {
+                if pixel.m > 0 {
+                    let beta = pixel.vp.as_ref().unwrap().beta;
+                    let n = pixel.n + GAMMA * pixel.m as f64;
+                    let radius = pixel.radius * (n / (pixel.n + pixel.m as f64)).sqrt();
+                    pixel.tau = (pixel.tau + beta * pixel.phi) * (radius * radius / (pixel.radius * pixel.radius));
+                    pixel.n = n;
+                    pixel.radius = radius;
+                }
+                pixel.phi = Color::new();
+                pixel.m = 0;
+                pixel.vp = None;
+            }
+        }
+
+        let photons = (iterations * self.photons_per_iteration) as f64;
+        pixels.iter().map(|pixel| {
+            let indirect = pixel.tau / (photons * rtweekend::PI * pixel.radius * pixel.radius);
+            pixel.ld / iterations as f64 + indirect
+        }).collect()
+    }
+
+    fn trace_camera_path(&self, r: &Ray, scene: &Scene, pixel: &mut Pixel) {
+        let mut beta = Color::new_with_values(1.0, 1.0, 1.0);
+        let mut ray = Ray::new_with_values(r.origin(), r.direction());
+
+        for _ in 0..self.max_depth {
+            let mut rec = hit_record::new();
+            if !scene.world.hit(&ray, 0.001, rtweekend::INFINITY, &mut rec) {
+                pixel.ld = pixel.ld + beta * scene.environment.value(&ray.direction());
+                return;
+            }
+
+            let mat = rec.mat_ptr.clone().unwrap();
+            pixel.ld = pixel.ld + beta * mat.emitted(&rec);
+            if !mat.is_specular() {
+                pixel.ld = pixel.ld + beta * photon::direct_lighting(&ray, &rec, scene);
+                pixel.vp = Some(VisiblePoint { rec, r_in: ray, beta });
+                return;
+            }
+
+            let mut scattered = Ray::new();
+            let mut attenuation = Color::new();
+            if !mat.scatter(&ray, &rec, &mut attenuation, &mut scattered) {
+                return;
+            }
+            beta = beta * attenuation;
+            ray = scattered;
+        }
+    }
+
+    // Direct light is sampled at the visible points, so only photons that have
+    // bounced at least once are gathered.
+    fn trace_photon(&self, scene: &Scene, targets: &[Target], grid: &Grid, pixels: &mut [Pixel]) {
+        let (mut ray, mut power) = match photon::emit_photon(scene, targets, true) {
+            Some(photon) => photon,
+            None => return,
+        };
+
+        for depth in 0..self.max_depth {
+            let mut rec = hit_record::new();
+            if !scene.world.hit(&ray, 0.001, rtweekend::INFINITY, &mut rec) {
+                return;
+            }
+            let mat = rec.mat_ptr.clone().unwrap();
+
+            if depth > 0 && !mat.is_specular() {
+                let wi = -ray.direction().unit_vector();
+                for &index in grid.cell(&rec.p) {
+                    let pixel = &mut pixels[index];
+                    let vp = pixel.vp.as_ref().unwrap();
+                    let cosine = Vec3::dot(&vp.rec.normal, &wi);
+                    if cosine > 0.0 && (vp.rec.p - rec.p).length_squared() < pixel.radius * pixel.radius {
+                        let f = vp.rec.mat_ptr.as_ref().unwrap().eval(&vp.r_in, &vp.rec, &wi) / cosine;
+                        pixel.phi = pixel.phi + f * power;
+                        pixel.m += 1;
+                    }
+                }
+            }
+
+            let mut scattered = Ray::new();
+            let mut attenuation = Color::new();
+            if !mat.scatter(&ray, &rec, &mut attenuation, &mut scattered) {
+                return;
+            }
+            // Photons keep roughly constant power by surviving in proportion to the albedo
+            let survive = attenuation.max_component().min(1.0);
+            if survive <= 0.0 || rtweekend::random_double() >= survive {
+                return;
+            }
+            power = power * attenuation / survive;
+            ray = scattered;
+        }
+    }
+}
+
+// Uniform hash grid of the visible points, each one entered in every cell its
+// search sphere overlaps.
+struct Grid {
+    cell_size: f64,
+    cells: HashMap<(i64, i64, i64), Vec<usize>>,
+    bounds: Option<Aabb>,
+}
+
+impl Grid {
+    fn new_with_values(pixels: &[Pixel]) -> Grid {
+        let cell_size = pixels.iter().filter(|p| p.vp.is_some()).map(|p| p.radius).fold(0.0, f64::max) * 2.0;
+        let mut grid = Grid { cell_size, cells: HashMap::new(), bounds: None };
+
+        for (index, pixel) in pixels.iter().enumerate() {
+            let vp = match &pixel.vp {
+                Some(vp) => vp,
+                None => continue,
+            };
+            let r = Vec3::new_with_values(pixel.radius, pixel.radius, pixel.radius);
+            let vp_box = Aabb::new_with_values(vp.rec.p - r, vp.rec.p + r);
+            grid.bounds = Some(match grid.bounds {
+                Some(bounds) => Aabb::surrounding_box(&bounds, &vp_box),
+                None => vp_box,
+            });
+
+            let lo = grid.key(&vp_box.min());
+            let hi = grid.key(&vp_box.max());
+            for x in lo.0..=hi.0 {
+                for y in lo.1..=hi.1 {
+                    for z in lo.2..=hi.2 {
+                        grid.cells.entry((x, y, z)).or_default().push(index);
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn key(&self, p: &Point3) -> (i64, i64, i64) {
+        ((p.x() / self.cell_size).floor() as i64, (p.y() / self.cell_size).floor() as i64, (p.z() / self.cell_size).floor() as i64)
+    }
+
+    fn cell(&self, p: &Point3) -> &[usize] {
+        self.cells.get(&self.key(p)).map_or(&[], |cell| cell.as_slice())
+    }
+}
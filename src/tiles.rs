@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use crate::film::CropWindow;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum TileOrder {
+    // Rows of tiles from the top
+    Scanline,
+    // Outwards from the middle of the image, so it fills in first
+    Spiral,
+    // Along a Hilbert curve, each tile next to the one before
+    Hilbert,
+}
+
+// The image split into tiles of `size` pixels, smaller at the right and
+// bottom edges, in the order they're rendered
+pub(crate) fn tiles(width: usize, height: usize, size: usize, order: TileOrder) -> Vec<CropWindow> {
+    let columns = width.div_ceil(size);
+    let rows = height.div_ceil(size);
+    let positions: Vec<(usize, usize)> = match order {
+        TileOrder::Scanline => (0..rows).flat_map(|y| (0..columns).map(move |x| (x, y))).collect(),
+        TileOrder::Spiral => spiral(columns, rows),
+        TileOrder::Hilbert => {
+            let n = columns.max(rows).next_power_of_two();
+            let mut positions: Vec<(usize, usize)> = (0..rows).flat_map(|y| (0..columns).map(move |x| (x, y))).collect();
+            positions.sort_by_key(|&(x, y)| hilbert_index(n, x, y));
+            positions
+        }
+    };
+    positions.into_iter().map(|(x, y)| CropWindow {
+        x0: x * size,
+        y0: y * size,
+        x1: ((x + 1) * size).min(width),
+        y1: ((y + 1) * size).min(height),
+    }).collect()
+}
+
+// Walks a square spiral out from the middle tile, keeping the tiles it passes
+// until it has them all
+fn spiral(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let total = columns * rows;
+    let mut positions = Vec::with_capacity(total);
+    let (mut x, mut y) = (((columns - 1) / 2) as isize, ((rows - 1) / 2) as isize);
+    let (mut dx, mut dy) = (1, 0);
+    let mut length = 1;
+    positions.push((x as usize, y as usize));
+    while positions.len() < total {
+        // Two legs of each length, turning clockwise after each
+        for _ in 0..2 {
+            for _ in 0..length {
+                x += dx;
+                y += dy;
+                if x >= 0 && y >= 0 && (x as usize) < columns && (y as usize) < rows {
+                    positions.push((x as usize, y as usize));
+                }
+            }
+            (dx, dy) = (-dy, dx);
+        }
+        length += 1;
+    }
+    positions
+}
+
+// Distance along the Hilbert curve filling an n x n grid, n a power of two
+fn hilbert_index(n: usize, mut x: usize, mut y: usize) -> usize {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as usize;
+        let ry = (y & s > 0) as usize;
+        d += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant so the curve inside it starts and ends in the right corners
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x &= s - 1;
+        y &= s - 1;
+        s /= 2;
+    }
+    d
+}
+
+// Renders `tiles` with `render_tile` on `threads` worker threads, each set up
+// with `start_thread`, and hands the results to `finish_tile` on this thread
+// in the order of `tiles`, whichever thread finishes first. Tiles are dealt
+// out to the threads in turn, and a thread that runs out takes the last tile
+// queued for whichever thread has the most left.
+pub(crate) fn render<T: Send>(
+    tiles: &[CropWindow],
+    threads: usize,
+    start_thread: impl Fn() + Sync,
+    render_tile: impl Fn(CropWindow) -> T + Sync,
+    mut finish_tile: impl FnMut(T),
+) {
+    let threads = threads.clamp(1, tiles.len().max(1));
+    let queues: Vec<Mutex<VecDeque<usize>>> = (0..threads).map(|t| Mutex::new((t..tiles.len()).step_by(threads).collect())).collect();
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for worker in 0..threads {
+            let sender = sender.clone();
+            let (queues, start_thread, render_tile) = (&queues, &start_thread, &render_tile);
+            scope.spawn(move || {
+                start_thread();
+                while let Some(index) = next_tile(queues, worker) {
+                    if sender.send((index, render_tile(tiles[index]))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        // Tiles that finished before some earlier one, waiting for it
+        let mut waiting = BTreeMap::new();
+        let mut next = 0;
+        for (index, result) in receiver {
+            waiting.insert(index, result);
+            while let Some(result) = waiting.remove(&next) {
+                finish_tile(result);
+                next += 1;
+            }
+        }
+    });
+}
+
+fn next_tile(queues: &[Mutex<VecDeque<usize>>], worker: usize) -> Option<usize> {
+    if let Some(index) = queues[worker].lock().unwrap().pop_front() {
+        return Some(index);
+    }
+    // Lengths are read once, as other threads keep taking tiles while sorting
+    let mut others: Vec<(usize, usize)> = (0..queues.len()).filter(|&q| q != worker).map(|q| (queues[q].lock().unwrap().len(), q)).collect();
+    others.sort_by_key(|&(length, _)| std::cmp::Reverse(length));
+    others.into_iter().find_map(|(_, q)| queues[q].lock().unwrap().pop_back())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const ORDERS: [TileOrder; 3] = [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert];
+
+    // Every pixel of a width x height image is in exactly one tile
+    fn assert_covers(tiles: &[CropWindow], width: usize, height: usize) {
+        let mut covered = vec![0; width * height];
+        for tile in tiles {
+            assert!(tile.x0 < tile.x1 && tile.x1 <= width && tile.y0 < tile.y1 && tile.y1 <= height);
+            for y in tile.y0..tile.y1 {
+                for x in tile.x0..tile.x1 {
+                    covered[y * width + x] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1), "{}x{}", width, height);
+    }
+
+    #[test]
+    fn every_order_covers_the_image_once() {
+        for &order in &ORDERS {
+            for (width, height, size) in [(1, 1, 16), (64, 64, 16), (100, 37, 16), (37, 100, 16), (250, 3, 7), (5, 90, 4), (96, 54, 32)] {
+                let tiles = tiles(width, height, size, order);
+                assert_eq!(tiles.len(), width.div_ceil(size) * height.div_ceil(size));
+                assert_covers(&tiles, width, height);
+            }
+        }
+    }
+
+    #[test]
+    fn hilbert_tiles_follow_on() {
+        // Neighbouring along the curve within a power of two grid
+        let tiles = tiles(64, 64, 8, TileOrder::Hilbert);
+        for pair in tiles.windows(2) {
+            let (dx, dy) = (pair[0].x0.abs_diff(pair[1].x0), pair[0].y0.abs_diff(pair[1].y0));
+            assert_eq!(dx + dy, 8);
+        }
+    }
+
+    #[test]
+    fn spiral_starts_in_the_middle() {
+        let tiles = tiles(90, 50, 10, TileOrder::Spiral);
+        assert_eq!((tiles[0].x0, tiles[0].y0), (40, 20));
+    }
+
+    #[test]
+    fn results_come_out_in_tile_order() {
+        let tiles = tiles(75, 41, 8, TileOrder::Spiral);
+        for threads in [1, 3, 8, 100] {
+            let mut finished = Vec::new();
+            render(&tiles, threads, || {}, |tile| {
+                // Later tiles finish first, if anything
+                thread::sleep(Duration::from_micros(((tile.x0 * 7 + tile.y0 * 13) % 50) as u64 * 20));
+                (tile.x0, tile.y0)
+            }, |position| finished.push(position));
+            let expected: Vec<(usize, usize)> = tiles.iter().map(|tile| (tile.x0, tile.y0)).collect();
+            assert_eq!(finished, expected, "{} threads", threads);
+        }
+    }
+}
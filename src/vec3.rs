@@ -57,6 +57,10 @@ impl Vec3 {
         *self / self.length()
     }
 
+    pub(crate) fn max_component(&self) -> f64 {
+        self.e[0].max(self.e[1]).max(self.e[2])
+    }
+
     pub fn near_zero(&self) -> bool {
         let s = 1e-8;
         (self.e[0].abs() < s) && (self.e[1].abs() < s) && (self.e[2].abs() < s)
@@ -71,27 +75,31 @@ pub fn random_minmax(min: f64,max: f64) -> Vec3 {
     Vec3::new_with_values(rtweekend::random_double_minmax(min, max), rtweekend::random_double_minmax(min, max), rtweekend::random_double_minmax(min, max))
 }
 
+// The sampling functions below map a fixed number of random values to their
+// shapes directly, without rejection, so the samplers' dimensions line up
+// between samples.
 pub fn random_in_unit_sphere() -> Vec3 {
-    loop {
-        let p = random_minmax(-1.0, 1.0);
-        if p.length_squared() >= 1.0 {
-            continue;
-        }
-        return p;
-    }
+    random_unit_vector() * rtweekend::random_double().cbrt()
 }
 
 pub fn random_unit_vector() -> Vec3 {
-    random_in_unit_sphere().unit_vector()
+    let (r1, r2) = rtweekend::random_double_2d();
+    let z = 1.0 - 2.0 * r1;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * rtweekend::PI * r2;
+    Vec3::new_with_values(r * phi.cos(), r * phi.sin(), z)
 }
 
-pub fn random_in_hemisphere(normal: &Vec3) -> Vec3 {
-    let in_unit_sphere = random_in_unit_sphere();
-    if Vec3::dot(&in_unit_sphere, normal) > 0.0 {
-        in_unit_sphere
-    } else {
-        -in_unit_sphere
-    }
+// Cosine weighted direction around +z
+pub fn random_cosine_direction() -> Vec3 {
+    let (r1, r2) = rtweekend::random_double_2d();
+
+    let phi = 2.0 * rtweekend::PI * r1;
+    let x = phi.cos() * r2.sqrt();
+    let y = phi.sin() * r2.sqrt();
+    let z = (1.0 - r2).sqrt();
+
+    Vec3::new_with_values(x, y, z)
 }
 
 pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
@@ -105,14 +113,20 @@ pub fn refract(uv: &Vec3, n: &Vec3, etai_over_etat: f64) -> Vec3 {
     r_out_perp + r_out_parallel
 }
 
+// Shirley and Chiu's concentric mapping, which keeps strata compact
 pub fn random_in_unit_disk() -> Vec3 {
-    loop {
-        let p = Vec3::new_with_values(rtweekend::random_double_minmax(-1.0, 1.0), rtweekend::random_double_minmax(-1.0, 1.0), 0.0);
-        if p.length_squared() >= 1.0 {
-            continue;
-        }
-        return p;
-    }
+    let (r1, r2) = rtweekend::random_double_2d();
+    let a = 2.0 * r1 - 1.0;
+    let b = 2.0 * r2 - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vec3::new();
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, rtweekend::PI / 4.0 * (b / a))
+    } else {
+        (b, rtweekend::PI / 2.0 - rtweekend::PI / 4.0 * (a / b))
+    };
+    Vec3::new_with_values(r * theta.cos(), r * theta.sin(), 0.0)
 }
 
 impl Neg for Vec3 {